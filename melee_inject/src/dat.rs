@@ -0,0 +1,577 @@
+//! HSD archives: the `.dat` files holding characters, stages and menus.
+//!
+//! ```text
+//! 0x00  header (0x20 bytes)
+//! 0x20  data block
+//!       relocation table: offsets (into the data block) of every pointer
+//!       root nodes: (data offset, string offset) for each exported symbol
+//!       reference nodes: (data offset, string offset) for each import
+//!       string table: null terminated symbol names
+//! ```
+//!
+//! Everything is big endian. Pointers in the data block are offsets from the
+//! start of the data block, and are only pointers if listed in the
+//! relocation table.
+//!
+//! [`DatFile::to_bytes`] writes an archive back out. An unedited archive is
+//! written byte for byte as it was read.
+//!
+//! Character archives are recognised by their root symbols: `PlCa.dat`
+//! exports `ftDataCaptain`, and the green costume `PlCaGr.dat` exports
+//! symbols starting with `PlyCaptain5KGr_` (`PlyCaptain5K_` for neutral).
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+
+/// Size of the archive header.
+pub const HEADER_SIZE: usize = 0x20;
+
+/// The archive header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// Size of the whole archive.
+    pub file_size: u32,
+    pub data_size: u32,
+    pub relocation_count: u32,
+    pub root_count: u32,
+    pub reference_count: u32,
+    /// The rest of the header, usually zeros or a version string.
+    pub unknown: [u8; 0x0c],
+}
+
+/// A root or reference node: a symbol and where it points in the data block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// Offset into the data block.
+    pub offset: u32,
+    /// Symbol name, e.g. `ftDataCaptain` or `PlyCaptain5K_Share_joint`.
+    pub symbol: String,
+    /// Offset of the symbol in the string table.
+    pub name_offset: u32,
+}
+
+/// A parsed HSD archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatFile {
+    /// The header as parsed; [`DatFile::to_bytes`] recomputes it.
+    pub header: Header,
+    /// The data block, with pointers stored as data block offsets.
+    pub data: Vec<u8>,
+    /// Offsets into the data block holding pointers, in table order.
+    relocations: Vec<u32>,
+    /// The same offsets, for [`DatFile::is_pointer`].
+    pointers: HashSet<u32>,
+    pub roots: Vec<Node>,
+    pub references: Vec<Node>,
+    /// The raw string table, including any trailing padding.
+    pub strings: Vec<u8>,
+}
+
+fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> io::Result<u32> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|word| u32::from_be_bytes(word.try_into().expect("4 bytes")))
+        .ok_or_else(|| invalid(format!("read past the end of the archive at {offset:#x}")))
+}
+
+/// Read a null terminated string from the string table.
+fn read_symbol(strings: &[u8], offset: u32) -> io::Result<String> {
+    let start = strings
+        .get(offset as usize..)
+        .ok_or_else(|| invalid(format!("symbol offset {offset:#x} out of bounds")))?;
+    let end = start
+        .iter()
+        .position(|byte| *byte == 0)
+        .ok_or_else(|| invalid(format!("symbol at {offset:#x} is not terminated")))?;
+    Ok(String::from_utf8_lossy(&start[..end]).into_owned())
+}
+
+impl Header {
+    pub fn parse(bytes: &[u8]) -> io::Result<Header> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(invalid("archive is smaller than its header"));
+        }
+        Ok(Header {
+            file_size: read_u32(bytes, 0x00)?,
+            data_size: read_u32(bytes, 0x04)?,
+            relocation_count: read_u32(bytes, 0x08)?,
+            root_count: read_u32(bytes, 0x0c)?,
+            reference_count: read_u32(bytes, 0x10)?,
+            unknown: bytes[0x14..HEADER_SIZE].try_into().expect("12 bytes"),
+        })
+    }
+
+    /// Where the relocation table starts, relative to the start of the archive.
+    pub fn relocation_offset(&self) -> usize {
+        HEADER_SIZE + self.data_size as usize
+    }
+
+    /// Where the root node table starts.
+    pub fn root_offset(&self) -> usize {
+        self.relocation_offset() + self.relocation_count as usize * 4
+    }
+
+    /// Where the reference node table starts.
+    pub fn reference_offset(&self) -> usize {
+        self.root_offset() + self.root_count as usize * 8
+    }
+
+    /// Where the string table starts.
+    pub fn string_offset(&self) -> usize {
+        self.reference_offset() + self.reference_count as usize * 8
+    }
+}
+
+impl DatFile {
+    /// Parse an archive, checking every table fits within `file_size`.
+    pub fn parse(bytes: &[u8]) -> io::Result<DatFile> {
+        let header = Header::parse(bytes)?;
+        let file_size = header.file_size as usize;
+        if file_size > bytes.len() {
+            return Err(invalid(format!(
+                "header says the archive is {file_size:#x} bytes, but it is {:#x}",
+                bytes.len()
+            )));
+        }
+        let bytes = &bytes[..file_size];
+        if header.string_offset() > file_size {
+            return Err(invalid("archive tables extend past the end of the file"));
+        }
+
+        let relocations = (0..header.relocation_count as usize)
+            .map(|index| read_u32(bytes, header.relocation_offset() + index * 4))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let strings = bytes[header.string_offset()..].to_vec();
+        let nodes = |start: usize, count: u32| {
+            (0..count as usize)
+                .map(|index| {
+                    let offset = read_u32(bytes, start + index * 8)?;
+                    let name_offset = read_u32(bytes, start + index * 8 + 4)?;
+                    Ok(Node {
+                        offset,
+                        symbol: read_symbol(&strings, name_offset)?,
+                        name_offset,
+                    })
+                })
+                .collect::<io::Result<Vec<_>>>()
+        };
+        let roots = nodes(header.root_offset(), header.root_count)?;
+        let references = nodes(header.reference_offset(), header.reference_count)?;
+
+        Ok(DatFile {
+            data: bytes[HEADER_SIZE..header.relocation_offset()].to_vec(),
+            header,
+            pointers: relocations.iter().copied().collect(),
+            relocations,
+            roots,
+            references,
+            strings,
+        })
+    }
+
+    /// Find a root node by symbol.
+    pub fn root(&self, symbol: &str) -> Option<&Node> {
+        self.roots.iter().find(|node| node.symbol == symbol)
+    }
+
+    /// Read a big endian word from the data block.
+    pub fn read_u32(&self, offset: u32) -> io::Result<u32> {
+        read_u32(&self.data, offset as usize)
+    }
+
+    /// Offsets into the data block holding pointers, in table order.
+    ///
+    /// Change them with [`DatFile::set_pointer`] and [`DatFile::clear_pointer`].
+    pub fn relocations(&self) -> &[u32] {
+        &self.relocations
+    }
+
+    /// Is the word at `offset` in the data block a pointer?
+    pub fn is_pointer(&self, offset: u32) -> bool {
+        self.pointers.contains(&offset)
+    }
+
+    /// Follow the pointer at `offset` in the data block.
+    ///
+    /// Returns `None` for words that aren't relocated, such as null pointers.
+    pub fn read_pointer(&self, offset: u32) -> io::Result<Option<u32>> {
+        if !self.is_pointer(offset) {
+            return Ok(None);
+        }
+        let target = self.read_u32(offset)?;
+        if target as usize >= self.data.len() {
+            return Err(invalid(format!(
+                "pointer at {offset:#x} points outside the data block ({target:#x})"
+            )));
+        }
+        Ok(Some(target))
+    }
+
+    /// Write a big endian word to the data block.
+    pub fn write_u32(&mut self, offset: u32, value: u32) -> io::Result<()> {
+        self.data
+            .get_mut(offset as usize..offset as usize + 4)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "write past the end of the data block at {offset:#x}"
+                ))
+            })?
+            .copy_from_slice(&value.to_be_bytes());
+        Ok(())
+    }
+
+    /// Store a pointer to `target` at `offset`, adding it to the relocation table.
+    pub fn set_pointer(&mut self, offset: u32, target: u32) -> io::Result<()> {
+        if target as usize >= self.data.len() {
+            return Err(invalid(format!(
+                "pointer target {target:#x} is outside the data block"
+            )));
+        }
+        self.write_u32(offset, target)?;
+        if self.pointers.insert(offset) {
+            self.relocations.push(offset);
+        }
+        Ok(())
+    }
+
+    /// Replace the pointer at `offset` with a null pointer.
+    pub fn clear_pointer(&mut self, offset: u32) -> io::Result<()> {
+        self.write_u32(offset, 0)?;
+        if self.pointers.remove(&offset) {
+            self.relocations.retain(|relocation| *relocation != offset);
+        }
+        Ok(())
+    }
+
+    /// Append `bytes` to the end of the data block, aligned to `align` bytes.
+    ///
+    /// Returns the offset of the new data.
+    pub fn append(&mut self, bytes: &[u8], align: usize) -> u32 {
+        let offset = self.data.len().next_multiple_of(align.max(1));
+        self.data.resize(offset, 0);
+        self.data.extend_from_slice(bytes);
+        offset as u32
+    }
+
+    /// The costume this archive was made for, from its root symbols.
+    pub fn costume_slot(&self) -> Option<Slot> {
+        self.roots
+            .iter()
+            .find_map(|node| Slot::from_symbol(&node.symbol))
+    }
+
+    /// Rename every symbol of the `from` costume to the `to` costume's,
+    /// e.g. `PlyCaptain5KGr_TopN` to `PlyCaptain5K_TopN`.
+    ///
+    /// Returns how many root and reference symbols were renamed.
+    pub fn rename_costume(&mut self, from: &Slot, to: &Slot) -> usize {
+        let (from, to) = (from.symbol_prefix(), to.symbol_prefix());
+        let mut renamed = 0;
+        for node in self.roots.iter_mut().chain(&mut self.references) {
+            if has_prefix(&node.symbol, &from) {
+                node.symbol = format!("{to}{}", &node.symbol[from.len()..]);
+                renamed += 1;
+            }
+        }
+        renamed
+    }
+
+    /// Do the nodes' names still match the raw string table?
+    fn strings_match(&self) -> bool {
+        self.roots.iter().chain(&self.references).all(|node| {
+            read_symbol(&self.strings, node.name_offset).ok().as_deref() == Some(&node.symbol)
+        })
+    }
+
+    /// Write the archive, recomputing the header sizes and counts.
+    ///
+    /// If a node's symbol was changed, the string table is rebuilt with every
+    /// symbol in node order, and the nodes' `name_offset`s are ignored.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let nodes = self.roots.iter().chain(&self.references);
+        let (strings, name_offsets) = if self.strings_match() {
+            (
+                self.strings.clone(),
+                nodes.map(|node| node.name_offset).collect::<Vec<_>>(),
+            )
+        } else {
+            let mut strings = Vec::new();
+            let offsets = nodes
+                .map(|node| {
+                    let offset = strings.len() as u32;
+                    strings.extend(node.symbol.as_bytes());
+                    strings.push(0);
+                    offset
+                })
+                .collect();
+            (strings, offsets)
+        };
+
+        let mut out = vec![0; HEADER_SIZE];
+        out.extend(&self.data);
+        for relocation in &self.relocations {
+            out.extend(relocation.to_be_bytes());
+        }
+        for (node, name_offset) in self.roots.iter().chain(&self.references).zip(name_offsets) {
+            out.extend(node.offset.to_be_bytes());
+            out.extend(name_offset.to_be_bytes());
+        }
+        out.extend(strings);
+
+        let header = [
+            out.len() as u32,
+            self.data.len() as u32,
+            self.relocations.len() as u32,
+            self.roots.len() as u32,
+            self.references.len() as u32,
+        ];
+        for (index, value) in header.iter().enumerate() {
+            out[index * 4..index * 4 + 4].copy_from_slice(&value.to_be_bytes());
+        }
+        out[0x14..HEADER_SIZE].copy_from_slice(&self.header.unknown);
+        out
+    }
+}
+/// Internal character names used in symbols, by the code in their file names.
+pub const CHARACTER_NAMES: &[(&str, &str)] = &[
+    ("Bo", "Boy"),
+    ("Ca", "Captain"),
+    ("Ch", "Crazyhand"),
+    ("Cl", "Clink"),
+    ("Dk", "Donkey"),
+    ("Dr", "Drmario"),
+    ("Fc", "Falco"),
+    ("Fe", "Emblem"),
+    ("Fx", "Fox"),
+    ("Gk", "Gkoopa"),
+    ("Gl", "Girl"),
+    ("Gn", "Ganon"),
+    ("Gw", "Gamewatch"),
+    ("Kb", "Kirby"),
+    ("Kp", "Koopa"),
+    ("Lg", "Luigi"),
+    ("Lk", "Link"),
+    ("Mh", "Masterhand"),
+    ("Mr", "Mario"),
+    ("Ms", "Mars"),
+    ("Mt", "Mewtwo"),
+    ("Nn", "Nana"),
+    ("Ns", "Ness"),
+    ("Pc", "Pichu"),
+    ("Pe", "Peach"),
+    ("Pk", "Pikachu"),
+    ("Pp", "Popo"),
+    ("Pr", "Purin"),
+    ("Sb", "Sandbag"),
+    ("Sk", "Seak"),
+    ("Ss", "Samus"),
+    ("Ys", "Yoshi"),
+    ("Zd", "Zelda"),
+];
+
+/// Look up the internal name for a character code, e.g. `Captain` for `Ca`.
+pub fn character_name(code: &str) -> Option<&'static str> {
+    CHARACTER_NAMES
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map(|(_, name)| *name)
+}
+
+/// A character costume, from a file name like `PlCaGr.dat`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Slot {
+    /// Character code, e.g. `Ca`.
+    pub code: String,
+    /// Costume color, e.g. `Gr` or `Nr` for neutral.
+    pub color: String,
+}
+
+impl Slot {
+    /// Parse a costume file name (or FST path). Shared files like `PlCa.dat`
+    /// and Kirby's copy power files aren't costumes.
+    pub fn from_file_name(name: &str) -> Option<Slot> {
+        let stem = Path::new(name).file_stem()?.to_str()?;
+        let rest = stem.strip_prefix("Pl")?;
+        if rest.len() != 4 || !rest.is_char_boundary(2) {
+            return None;
+        }
+        let (code, color) = rest.split_at(2);
+        character_name(code)?;
+        Some(Slot {
+            code: code.to_string(),
+            color: color.to_string(),
+        })
+    }
+
+    /// The costume a symbol belongs to, e.g. `PlCaGr.dat` for `PlyCaptain5KGr_TopN`.
+    pub fn from_symbol(symbol: &str) -> Option<Slot> {
+        let rest = symbol.strip_prefix("Ply")?;
+        CHARACTER_NAMES.iter().find_map(|(code, name)| {
+            let rest = rest.strip_prefix(name)?.strip_prefix("5K")?;
+            let color = match rest.strip_prefix('_') {
+                Some(_) => "Nr",
+                None => rest
+                    .get(..2)
+                    .filter(|_| rest[2..].starts_with('_'))
+                    .filter(|color| color.chars().all(|c| c.is_ascii_alphabetic()))?,
+            };
+            Some(Slot {
+                code: code.to_string(),
+                color: color.to_string(),
+            })
+        })
+    }
+
+    /// Internal character name, e.g. `Captain`.
+    pub fn character(&self) -> &'static str {
+        character_name(&self.code).expect("slots have known character codes")
+    }
+
+    /// Start of every costume symbol, e.g. `PlyCaptain5KGr`, or
+    /// `PlyCaptain5K` for the neutral costume.
+    pub fn symbol_prefix(&self) -> String {
+        match self.color.as_str() {
+            "Nr" => format!("Ply{}5K", self.character()),
+            color => format!("Ply{}5K{color}", self.character()),
+        }
+    }
+
+    /// The costume file name, e.g. `PlCaGr.dat`.
+    pub fn file_name(&self) -> String {
+        format!("Pl{}{}.dat", self.code, self.color)
+    }
+}
+
+/// The root symbol of a character's shared file, e.g. `ftDataCaptain` for `PlCa.dat`.
+pub fn shared_symbol(name: &str) -> Option<String> {
+    let stem = Path::new(name).file_stem()?.to_str()?;
+    let code = stem.strip_prefix("Pl").filter(|code| code.len() == 2)?;
+    Some(format!("ftData{}", character_name(code)?))
+}
+
+/// Convert a costume made for one slot into another slot of the same
+/// character, e.g. from `PlFxOr.dat` to `PlFxNr.dat`.
+///
+/// Slots are costume file names, with or without `.dat`. Every
+/// `PlyFox5KOr_*` root and reference symbol is renamed to `PlyFox5K_*`, and
+/// the string table rebuilt; the data block is left as it is.
+pub fn convert_costume(dat: &[u8], from_slot: &str, to_slot: &str) -> io::Result<Vec<u8>> {
+    let slot = |name: &str| {
+        Slot::from_file_name(name)
+            .ok_or_else(|| invalid(format!("{name} is not a character costume")))
+    };
+    let (from, to) = (slot(from_slot)?, slot(to_slot)?);
+    if from.code != to.code {
+        return Err(invalid(format!(
+            "can't convert a {} costume into a {} slot",
+            from.character(),
+            to.character()
+        )));
+    }
+
+    let mut archive = DatFile::parse(dat)?;
+    if archive.rename_costume(&from, &to) == 0 {
+        return Err(invalid(format!(
+            "archive has no {}_ symbols, is it really {}?",
+            from.symbol_prefix(),
+            from.file_name()
+        )));
+    }
+    Ok(archive.to_bytes())
+}
+
+/// Is `symbol` one of the costume's symbols (`<prefix>_...`)?
+fn has_prefix(symbol: &str, prefix: &str) -> bool {
+    symbol
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.starts_with('_'))
+}
+
+/// Check an archive is well formed, and made for the file it replaces.
+///
+/// Returns a description of each problem: sizes that don't match the
+/// header, pointers and nodes outside the data block, and root symbols for
+/// a different character or costume than `target` (a file name or FST path).
+pub fn validate(bytes: &[u8], target: &str) -> Vec<String> {
+    let dat = match DatFile::parse(bytes) {
+        Ok(dat) => dat,
+        Err(error) => return vec![format!("not a valid HSD archive: {error}")],
+    };
+
+    let mut problems = Vec::new();
+    if dat.header.file_size as usize != bytes.len() {
+        problems.push(format!(
+            "header says the archive is {:#x} bytes, but the file is {:#x}",
+            dat.header.file_size,
+            bytes.len()
+        ));
+    }
+
+    let data_size = dat.data.len() as u32;
+    for relocation in &dat.relocations {
+        match dat.read_u32(*relocation) {
+            Err(_) => problems.push(format!(
+                "relocation {relocation:#x} is outside the data block"
+            )),
+            Ok(target) if target >= data_size => problems.push(format!(
+                "pointer at {relocation:#x} points outside the data block ({target:#x})"
+            )),
+            Ok(_) => {}
+        }
+    }
+    for node in dat.roots.iter().chain(&dat.references) {
+        if node.offset >= data_size {
+            problems.push(format!(
+                "{} is outside the data block ({:#x})",
+                node.symbol, node.offset
+            ));
+        }
+    }
+
+    let symbols = || {
+        dat.roots
+            .iter()
+            .map(|node| node.symbol.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if let Some(slot) = Slot::from_file_name(target) {
+        let prefix = slot.symbol_prefix();
+        let made_for = dat
+            .costume_slot()
+            .filter(|made_for| made_for.code == slot.code);
+        if !dat
+            .roots
+            .iter()
+            .any(|node| has_prefix(&node.symbol, &prefix))
+        {
+            problems.push(match made_for {
+                Some(made_for) => format!(
+                    "made for {} ({}_ symbols), not {}: rename its symbols with `rename_costumes`",
+                    made_for.file_name(),
+                    made_for.symbol_prefix(),
+                    slot.file_name()
+                ),
+                None => format!(
+                    "expected root symbols starting with {prefix}_, found {}",
+                    symbols()
+                ),
+            });
+        }
+    } else if let Some(expected) = shared_symbol(target) {
+        if dat.root(&expected).is_none() {
+            problems.push(format!(
+                "expected root symbol {expected}, found {}",
+                symbols()
+            ));
+        }
+    }
+
+    problems
+}
@@ -0,0 +1,162 @@
+//! Render a rebuilt FST as a Graphviz diagram.
+//!
+//! Each replaced, added or removed file is drawn with its neighbours, before
+//! and after the rebuild, with red edges for every offset or size that changed.
+//! See `transformation-example.txt` for the hand-written diagram this mirrors.
+use super::replace::{FileAction, RebuiltFST, UpdateFST};
+use std::fmt::Write;
+
+/// Options for [`dot`] and [`plantuml`].
+#[derive(Debug, Clone)]
+pub struct DiagramOptions<'a> {
+    /// Label for the original image.
+    pub original_label: &'a str,
+    /// Label for the rebuilt image.
+    pub rebuilt_label: &'a str,
+    /// How many neighbouring files to draw on each side of a changed file.
+    pub context: usize,
+}
+
+impl Default for DiagramOptions<'_> {
+    fn default() -> Self {
+        DiagramOptions {
+            original_label: "ssbm.iso",
+            rebuilt_label: "rebuilt.iso",
+            context: 1,
+        }
+    }
+}
+
+/// Graphviz node identifier for a file.
+fn node_id(update: &UpdateFST, index: usize) -> String {
+    let name = update
+        .name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    format!("{name}_{index}")
+}
+
+/// Escape text for a quoted dot label.
+///
+/// Quotes and backslashes would end the string early, and braces, bars and
+/// angle brackets are record syntax. Graphviz drops the backslash before
+/// other characters, so escaping is harmless in plain labels too.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '"' | '{' | '}' | '|' | '<' | '>') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Record label for a file, e.g. `PlCaGr.dat\n[REPLACED]|{<offset>pos|0x4f638000}|...`.
+fn node_label(update: &UpdateFST, offset: u32, size: u32, tag: Option<&str>) -> String {
+    let tag = tag.map(|tag| format!("\\n[{tag}]")).unwrap_or_default();
+    format!(
+        "{}{tag}|{{<offset>pos|{offset:#010x}}}|{{<size>len|{size:#010x}}}",
+        escape(&update.name)
+    )
+}
+
+/// Edge between the old and new version of a field, red with the delta if it changed.
+fn edge(from: &str, to: &str, port: &str, old: u32, new: u32) -> String {
+    let delta = new as i64 - old as i64;
+    if delta == 0 {
+        return format!("  {from}:{port} -> {to}:{port};\n");
+    }
+
+    let sign = if delta < 0 { '-' } else { '+' };
+    format!(
+        "  {from}:{port} -> {to}:{port} [ color = red, fontcolor = red, label = \"{sign} {:#08x}\", fontname = \"Monospace\" ];\n",
+        delta.unsigned_abs()
+    )
+}
+
+/// Render the affected entries of a rebuilt FST as a Graphviz dot graph.
+pub fn dot(rebuilt: &RebuiltFST, options: &DiagramOptions) -> String {
+    let mut updates = rebuilt
+        .replacements
+        .values()
+        .chain(rebuilt.additions.iter())
+        .collect::<Vec<_>>();
+    updates.sort_by_key(|update| (update.original_offset, update.updated_offset));
+
+    // changed files, and `context` neighbours either side
+    let mut shown = vec![false; updates.len()];
+    for (index, update) in updates.iter().enumerate() {
+        if update.action != FileAction::Keep {
+            let start = index.saturating_sub(options.context);
+            let end = (index + options.context + 1).min(updates.len());
+            shown[start..end].iter_mut().for_each(|shown| *shown = true);
+        }
+    }
+
+    let mut original = String::new();
+    let mut updated = String::new();
+    let mut edges = String::new();
+    for (index, update) in updates.iter().enumerate().filter(|(i, _)| shown[*i]) {
+        let id = node_id(update, index);
+        let new_id = format!("{id}_new");
+        let (style, tag) = match update.action {
+            FileAction::Keep => ("", None),
+            FileAction::Replace => ("style = filled, ", Some("REPLACED")),
+            FileAction::Add => ("style = filled, ", Some("ADDED")),
+            FileAction::Remove => ("style = dashed, ", Some("REMOVED")),
+        };
+
+        if update.action != FileAction::Add {
+            let label = node_label(update, update.original_offset, update.original_size, None);
+            let _ = writeln!(original, "    {id} [ label = \"{label}\" ];");
+        }
+        let label = node_label(update, update.updated_offset, update.updated_size, tag);
+        let _ = writeln!(updated, "    {new_id} [ {style}label = \"{label}\" ];");
+        if update.action != FileAction::Add {
+            edges += &edge(
+                &id,
+                &new_id,
+                "offset",
+                update.original_offset,
+                update.updated_offset,
+            );
+            edges += &edge(
+                &id,
+                &new_id,
+                "size",
+                update.original_size,
+                update.updated_size,
+            );
+        }
+    }
+
+    format!(
+        r#"digraph rebuild {{
+  rankdir = LR;
+  fontname = "Monospace";
+
+  node [ shape = record, fontname = "Monospace" ];
+
+  subgraph cluster_original {{
+    label = "{}";
+
+{original}  }}
+
+  subgraph cluster_rebuilt {{
+    label = "{}";
+
+{updated}  }}
+
+{edges}}}
+"#,
+        escape(options.original_label),
+        escape(options.rebuilt_label)
+    )
+}
+
+/// Render the affected entries of a rebuilt FST as a PlantUML diagram (embedded dot).
+pub fn plantuml(rebuilt: &RebuiltFST, options: &DiagramOptions) -> String {
+    format!("@startdot\n{}@enddot\n", dot(rebuilt, options))
+}
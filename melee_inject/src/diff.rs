@@ -0,0 +1,249 @@
+//! Compare two disc images (or two FSTs) entry by entry.
+//!
+//! Files are matched by full FST path. Files only on one side are matched as
+//! renames when their contents (or, without contents, their offset and size)
+//! are identical.
+use super::disc::Disc;
+use super::fst::{Entry, Fst};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+
+/// Where a file lives on one side of a diff.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileInfo {
+    pub offset: u32,
+    pub size: u32,
+    /// Hex encoded SHA-1 of the contents, when comparing disc images.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<String>,
+}
+
+/// A single way a file differs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Renamed,
+    Moved,
+    Resized,
+    /// Same size, different contents.
+    ContentChanged,
+}
+
+/// A file that differs between two images.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileDiff {
+    /// Full FST path (the new path, for renamed files).
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renamed_from: Option<String>,
+    pub changes: Vec<ChangeKind>,
+    pub old: Option<FileInfo>,
+    pub new: Option<FileInfo>,
+}
+
+/// Every file in a table, keyed by full path.
+fn files(fst: &Fst) -> Vec<(String, FileInfo)> {
+    fst.entries
+        .iter()
+        .zip(fst.paths())
+        .filter_map(|(entry, path)| match entry {
+            Entry::File { offset, size, .. } => Some((
+                path,
+                FileInfo {
+                    offset: *offset,
+                    size: *size,
+                    sha1: None,
+                },
+            )),
+            Entry::Directory { .. } => None,
+        })
+        .collect()
+}
+
+/// Diff two sets of files, in the order of the new side (then removed files).
+fn diff_files(old: Vec<(String, FileInfo)>, new: Vec<(String, FileInfo)>) -> Vec<FileDiff> {
+    let old_by_path: HashMap<&str, &FileInfo> = old
+        .iter()
+        .map(|(path, info)| (path.as_str(), info))
+        .collect();
+    let new_by_path: HashMap<&str, &FileInfo> = new
+        .iter()
+        .map(|(path, info)| (path.as_str(), info))
+        .collect();
+
+    let same_contents = |a: &FileInfo, b: &FileInfo| match (&a.sha1, &b.sha1) {
+        (Some(a_sha1), Some(b_sha1)) => a.size == b.size && a_sha1 == b_sha1,
+        _ => a.offset == b.offset && a.size == b.size,
+    };
+
+    let mut removed = old
+        .iter()
+        .filter(|(path, _)| !new_by_path.contains_key(path.as_str()))
+        .collect::<Vec<_>>();
+
+    let mut diffs = Vec::new();
+    for (path, info) in &new {
+        let (renamed_from, old_info) = match old_by_path.get(path.as_str()) {
+            Some(old_info) => (None, Some(*old_info)),
+            None => match removed.iter().position(|(_, old)| same_contents(old, info)) {
+                Some(index) => {
+                    let (old_path, old_info) = removed.remove(index);
+                    (Some(old_path.clone()), Some(old_info))
+                }
+                None => (None, None),
+            },
+        };
+
+        let mut changes = Vec::new();
+        match old_info {
+            None => changes.push(ChangeKind::Added),
+            Some(old_info) => {
+                if renamed_from.is_some() {
+                    changes.push(ChangeKind::Renamed);
+                }
+                if old_info.offset != info.offset {
+                    changes.push(ChangeKind::Moved);
+                }
+                if old_info.size != info.size {
+                    changes.push(ChangeKind::Resized);
+                } else if old_info.sha1 != info.sha1 {
+                    changes.push(ChangeKind::ContentChanged);
+                }
+            }
+        }
+
+        if !changes.is_empty() {
+            diffs.push(FileDiff {
+                path: path.clone(),
+                renamed_from,
+                changes,
+                old: old_info.cloned(),
+                new: Some(info.clone()),
+            });
+        }
+    }
+
+    diffs.extend(removed.into_iter().map(|(path, info)| FileDiff {
+        path: path.clone(),
+        renamed_from: None,
+        changes: vec![ChangeKind::Removed],
+        old: Some(info.clone()),
+        new: None,
+    }));
+    diffs
+}
+
+/// Compare two filesystem tables by offsets and sizes alone.
+pub fn diff_fst(old: &Fst, new: &Fst) -> Vec<FileDiff> {
+    diff_files(files(old), files(new))
+}
+
+/// Compare two disc images, including file contents.
+pub fn diff_discs(old: &Disc, new: &Disc) -> io::Result<Vec<FileDiff>> {
+    let with_hashes = |disc: &Disc| -> io::Result<Vec<(String, FileInfo)>> {
+        Ok(disc
+            .file_hashes()?
+            .into_iter()
+            .map(|(file, hashes)| {
+                (
+                    file.path.clone(),
+                    FileInfo {
+                        offset: file.offset,
+                        size: file.size,
+                        sha1: Some(hashes.sha1),
+                    },
+                )
+            })
+            .collect())
+    };
+
+    #[cfg(feature = "rayon")]
+    let (old, new) = rayon::join(|| with_hashes(old), || with_hashes(new));
+    #[cfg(not(feature = "rayon"))]
+    let (old, new) = (with_hashes(old), with_hashes(new));
+    Ok(diff_files(old?, new?))
+}
+
+/// Compare two images (or raw FSTs, for files smaller than an image header) on disk.
+pub fn diff_paths<P: AsRef<std::path::Path>>(old: P, new: P) -> io::Result<Vec<FileDiff>> {
+    let is_fst = |path: &P| -> io::Result<bool> {
+        Ok(std::fs::metadata(path)?.len() < super::fst::FST_OFFSET)
+    };
+
+    if is_fst(&old)? && is_fst(&new)? {
+        let old = Fst::parse(&std::fs::read(old)?)?;
+        let new = Fst::parse(&std::fs::read(new)?)?;
+        return Ok(diff_fst(&old, &new));
+    }
+
+    diff_discs(&Disc::open(old)?, &Disc::open(new)?)
+}
+
+/// Render a diff as a plain text table.
+pub fn to_table(diffs: &[FileDiff]) -> String {
+    let field = |info: &Option<FileInfo>, get: fn(&FileInfo) -> u32| {
+        info.as_ref()
+            .map(|info| format!("{:#010x}", get(info)))
+            .unwrap_or_else(|| "-".to_string())
+    };
+
+    let rows = diffs
+        .iter()
+        .map(|diff| {
+            let changes = diff
+                .changes
+                .iter()
+                .map(|change| format!("{change:?}").to_lowercase())
+                .collect::<Vec<_>>()
+                .join(",");
+            let path = match &diff.renamed_from {
+                Some(from) => format!("{from} -> {}", diff.path),
+                None => diff.path.clone(),
+            };
+            [
+                changes,
+                path,
+                format!(
+                    "{} -> {}",
+                    field(&diff.old, |i| i.offset),
+                    field(&diff.new, |i| i.offset)
+                ),
+                format!(
+                    "{} -> {}",
+                    field(&diff.old, |i| i.size),
+                    field(&diff.new, |i| i.size)
+                ),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let header = ["CHANGES", "PATH", "OFFSET", "SIZE"].map(str::to_string);
+    let widths = (0..4)
+        .map(|column| {
+            rows.iter()
+                .chain([&header])
+                .map(|row| row[column].len())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+
+    let mut table = String::new();
+    for row in [&header].into_iter().chain(rows.iter()) {
+        let line = (0..4)
+            .map(|column| format!("{:width$}", row[column], width = widths[column]))
+            .collect::<Vec<_>>()
+            .join("  ");
+        let _ = writeln!(table, "{}", line.trim_end());
+    }
+    table
+}
+
+/// Render a diff as JSON.
+pub fn to_json(diffs: &[FileDiff]) -> String {
+    serde_json::to_string_pretty(diffs).expect("failed to serialize diff")
+}
@@ -0,0 +1,403 @@
+//! Read-only access to the files of a disc image.
+//!
+//! [`Disc`] opens an image once and reads files by position, instead of
+//! reopening the ISO for every file like [`super::replace::read_file`].
+//!
+//! With the `rayon` feature, hashing and extracting every file is spread
+//! across threads. Results are always in FST order.
+//!
+//! With the `mmap` feature, [`Disc::open_mmap`] maps the image into memory,
+//! and [`Disc::file_data`] borrows file contents instead of copying them.
+use super::formats;
+use super::fst::{self, Entry, Fst};
+use super::progress::{self, Phase, Progress, Reporter};
+use super::vanilla::{self, VanillaFile};
+use super::verify::{self, Hashes};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Random access to the bytes of a disc image.
+pub trait Backend: Send + Sync {
+    /// Fill `buf` with the bytes starting at `offset`.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Size of the (uncompressed) image.
+    fn size(&self) -> io::Result<u64>;
+
+    /// The whole image, for backends that already hold it in memory.
+    fn as_slice(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+impl Backend for File {
+    #[cfg(unix)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_read(self, buf, offset)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                read => {
+                    buf = &mut buf[read..];
+                    offset += read as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl Backend for [u8] {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let start = offset as usize;
+        let data = self
+            .get(start..start + buf.len())
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+}
+
+impl Backend for Vec<u8> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self[..].read_at(offset, buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self[..].size()
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Backend for memmap2::Mmap {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self[..].read_at(offset, buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self[..].size()
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+}
+
+/// Sequential reads over a [`Backend`], e.g. for hashing a whole image.
+pub struct Reader<'a> {
+    backend: &'a dyn Backend,
+    position: u64,
+    size: u64,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(backend: &'a dyn Backend) -> io::Result<Reader<'a>> {
+        Ok(Reader {
+            backend,
+            position: 0,
+            size: backend.size()?,
+        })
+    }
+}
+
+impl Read for Reader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = (self.size.saturating_sub(self.position)).min(buf.len() as u64) as usize;
+        self.backend.read_at(self.position, &mut buf[..length])?;
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl Seek for Reader<'_> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+/// A file within the disc filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscFile {
+    /// Index of the FST entry.
+    pub index: usize,
+    /// Full FST path, e.g. `audio/1padv.ssm`.
+    pub path: String,
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// How a file differs from vanilla v1.02 NTSC GALE01.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    /// Present in vanilla, with different contents.
+    Modified,
+    /// Not present in vanilla.
+    Added,
+    /// Present in vanilla, but missing from this disc.
+    Removed,
+}
+
+/// A file that differs from vanilla.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModifiedFile {
+    pub path: String,
+    pub status: FileStatus,
+}
+
+/// Run `f` over every file, in parallel with the `rayon` feature.
+///
+/// Results are in the order of `files` either way.
+fn map_files<'a, T, F>(files: &'a [DiscFile], f: F) -> io::Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(&'a DiscFile) -> io::Result<T> + Send + Sync,
+{
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        files.par_iter().map(f).collect()
+    }
+    #[cfg(not(feature = "rayon"))]
+    {
+        files.iter().map(f).collect()
+    }
+}
+
+/// How many files to extract between progress reports.
+///
+/// The reporter is only called from the calling thread, so with the `rayon`
+/// feature each batch is extracted in parallel and then reported in order.
+const EXTRACT_BATCH: usize = if cfg!(feature = "rayon") { 64 } else { 1 };
+
+/// An opened disc image.
+pub struct Disc {
+    backend: Box<dyn Backend>,
+    pub fst: Fst,
+    files: Vec<DiscFile>,
+}
+
+impl Disc {
+    /// Open a disc image on disk, raw or compressed (see [`formats::open`]).
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Disc> {
+        Disc::from_backend(formats::open(path)?)
+    }
+
+    /// Map a disc image on disk into memory.
+    ///
+    /// The image must not be modified while the [`Disc`] is open; see
+    /// [`memmap2::Mmap::map`].
+    #[cfg(feature = "mmap")]
+    pub fn open_mmap<P: AsRef<Path>>(path: P) -> io::Result<Disc> {
+        let file = File::open(path)?;
+        // SAFETY: the image is opened read-only, and callers are told not to
+        // modify it while it is mapped
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Disc::from_backend(Box::new(map))
+    }
+
+    /// Open a disc image from any backend, reading its filesystem table.
+    pub fn from_backend(backend: Box<dyn Backend>) -> io::Result<Disc> {
+        let fst = Fst::parse(&fst::read(&mut Reader::new(&*backend)?)?)?;
+
+        let files = fst
+            .entries
+            .iter()
+            .zip(fst.paths())
+            .enumerate()
+            .filter_map(|(index, (entry, path))| match entry {
+                Entry::File { offset, size, .. } => Some(DiscFile {
+                    index,
+                    path,
+                    offset: *offset,
+                    size: *size,
+                }),
+                Entry::Directory { .. } => None,
+            })
+            .collect();
+
+        Ok(Disc {
+            backend,
+            fst,
+            files,
+        })
+    }
+
+    /// Every file on the disc, in FST order.
+    pub fn files(&self) -> &[DiscFile] {
+        &self.files
+    }
+
+    /// Look up a file by full path or unique file name.
+    pub fn file(&self, target: &str) -> io::Result<&DiscFile> {
+        let index = self.fst.find(target)?;
+        self.files
+            .iter()
+            .find(|file| file.index == index)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no disc file for {target:?}"),
+                )
+            })
+    }
+
+    /// Size of the (uncompressed) image.
+    pub fn size(&self) -> io::Result<u64> {
+        self.backend.size()
+    }
+
+    /// Read the whole image sequentially.
+    pub fn reader(&self) -> io::Result<Reader<'_>> {
+        Reader::new(&*self.backend)
+    }
+
+    /// Read raw bytes from the image.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.backend.read_at(offset, buf)
+    }
+
+    /// Read a file's contents.
+    pub fn read_file(&self, file: &DiscFile) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; file.size as usize];
+        self.read_at(file.offset as u64, &mut data)?;
+        Ok(data)
+    }
+
+    /// A file's contents, borrowed from in-memory backends without copying.
+    ///
+    /// Other backends read the file like [`Disc::read_file`].
+    pub fn file_data(&self, file: &DiscFile) -> io::Result<Cow<'_, [u8]>> {
+        match self.backend.as_slice() {
+            Some(image) => {
+                let start = file.offset as usize;
+                image
+                    .get(start..start + file.size as usize)
+                    .map(Cow::Borrowed)
+                    .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+            }
+            None => self.read_file(file).map(Cow::Owned),
+        }
+    }
+
+    /// Copy every file out to `dir`, keeping the FST directory structure.
+    ///
+    /// Progress is reported after each file, with its full FST path.
+    pub fn extract<P: AsRef<Path> + Sync>(
+        &self,
+        dir: P,
+        reporter: &mut dyn Reporter,
+    ) -> io::Result<()> {
+        let total = self.files.iter().map(|file| file.size as u64).sum();
+        let mut progress = Progress {
+            phase: Phase::Extract,
+            bytes: 0,
+            total,
+            file: None,
+        };
+        progress::report(reporter, progress)?;
+
+        for batch in self.files.chunks(EXTRACT_BATCH) {
+            map_files(batch, |file| {
+                if file
+                    .path
+                    .split('/')
+                    .any(|part| part.is_empty() || part == "..")
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("refusing to extract {:?}", file.path),
+                    ));
+                }
+
+                let out = dir.as_ref().join(&file.path);
+                if let Some(parent) = out.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(out, self.file_data(file)?)
+            })?;
+
+            for file in batch {
+                progress.bytes += file.size as u64;
+                progress.file = Some(&file.path);
+                progress::report(reporter, progress)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hash every file on the disc, in FST order.
+    pub fn file_hashes(&self) -> io::Result<Vec<(&DiscFile, Hashes)>> {
+        map_files(&self.files, |file| {
+            Ok((file, verify::hash_reader(&self.file_data(file)?[..])?))
+        })
+    }
+
+    /// Compare every file against a vanilla file table (see [`vanilla`]).
+    ///
+    /// Files are compared by size first, and only hashed when the sizes match.
+    /// `table` must be sorted by path.
+    pub fn modified_files_from(&self, table: &[VanillaFile]) -> io::Result<Vec<ModifiedFile>> {
+        let statuses = map_files(&self.files, |file| {
+            Ok(match vanilla::find(table, &file.path) {
+                None => Some(FileStatus::Added),
+                Some(vanilla) if vanilla.size != file.size => Some(FileStatus::Modified),
+                Some(vanilla) => {
+                    let hashes = verify::hash_reader(&self.file_data(file)?[..])?;
+                    (hashes.crc32 != vanilla.crc32 || hashes.sha1 != vanilla.sha1)
+                        .then_some(FileStatus::Modified)
+                }
+            })
+        })?;
+
+        let mut modified = self
+            .files
+            .iter()
+            .zip(statuses)
+            .filter_map(|(file, status)| {
+                Some(ModifiedFile {
+                    path: file.path.clone(),
+                    status: status?,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for vanilla in table {
+            if !self.files.iter().any(|file| file.path == vanilla.path) {
+                modified.push(ModifiedFile {
+                    path: vanilla.path.to_string(),
+                    status: FileStatus::Removed,
+                });
+            }
+        }
+
+        Ok(modified)
+    }
+}
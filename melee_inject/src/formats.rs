@@ -0,0 +1,911 @@
+//! Compressed disc image formats.
+//!
+//! [`open`] detects the format of an image from its first bytes, and returns
+//! a [`Backend`] reading the uncompressed image, so everything else works on
+//! compressed images transparently.
+//!
+//! - CISO: fixed size blocks, with blocks of zeros left out.
+//! - GCZ: Dolphin's zlib compressed blocks.
+//! - RVZ: Dolphin's chunked format, compressed with zstd or not at all. The
+//!   junk data between files is regenerated from its seed.
+//!
+//! WIA images, and RVZ images compressed with bzip2, LZMA or LZMA2, are
+//! detected but not read: convert them to RVZ (zstd), GCZ or ISO with
+//! Dolphin first.
+//!
+//! [`write`] compresses a built image as CISO, GCZ or RVZ, all of which
+//! Dolphin loads directly. Images are read a block at a time, so they don't
+//! have to be held in memory.
+use super::disc::{Backend, Reader};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Size of a full GameCube disc.
+pub const GCM_SIZE: u64 = 0x57058000;
+
+/// Size of a CISO header, including the block map.
+pub const CISO_HEADER_SIZE: u64 = 0x8000;
+
+/// Magic number at the start of a GCZ image (little endian).
+pub const GCZ_MAGIC: u32 = 0xb10bc001;
+
+/// Size of a GCZ header, before the block pointers and hashes.
+pub const GCZ_HEADER_SIZE: u64 = 0x20;
+
+/// Magic number at the start of an RVZ image.
+pub const RVZ_MAGIC: &[u8; 4] = b"RVZ\x01";
+
+/// Size of a WIA/RVZ file header (`wia_file_head_t`).
+pub const RVZ_HEAD_SIZE: u64 = 0x48;
+
+/// Size of a WIA/RVZ disc header (`wia_disc_t`).
+pub const RVZ_DISC_SIZE: u64 = 0xdc;
+
+/// Where NKit writes its marker in the disc header.
+pub const NKIT_OFFSET: u64 = 0x200;
+
+/// Marker of an NKit image.
+pub const NKIT_MAGIC: &[u8; 4] = b"NKIT";
+
+/// Block size used when writing CISO images.
+pub const CISO_BLOCK_SIZE: usize = 0x200000;
+
+/// Block size used when writing GCZ images, matching Dolphin's default.
+pub const GCZ_BLOCK_SIZE: usize = 0x8000;
+
+/// Chunk size used when writing RVZ images, matching Dolphin's default.
+pub const RVZ_CHUNK_SIZE: usize = 0x20000;
+
+/// zstd level used when writing RVZ images, matching Dolphin's default.
+pub const RVZ_ZSTD_LEVEL: i32 = 5;
+
+/// Disc image formats, as detected by [`Format::detect`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A raw GameCube disc image (`.iso`, `.gcm`).
+    Gcm,
+    Ciso,
+    Gcz,
+    Wia,
+    Rvz,
+}
+
+impl Format {
+    /// Detect the format of an image from its first four bytes.
+    pub fn detect(magic: [u8; 4]) -> Format {
+        match &magic {
+            b"CISO" => Format::Ciso,
+            b"WIA\x01" => Format::Wia,
+            b"RVZ\x01" => Format::Rvz,
+            _ if u32::from_le_bytes(magic) == GCZ_MAGIC => Format::Gcz,
+            _ => Format::Gcm,
+        }
+    }
+
+    /// Choose a format from a file extension, defaulting to a raw image.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Format {
+        let extension = path
+            .as_ref()
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("ciso") => Format::Ciso,
+            Some("gcz") => Format::Gcz,
+            Some("wia") => Format::Wia,
+            Some("rvz") => Format::Rvz,
+            _ => Format::Gcm,
+        }
+    }
+
+    /// Can [`write`] produce images in this format?
+    pub fn is_writable(self) -> bool {
+        self != Format::Wia
+    }
+}
+
+fn unsupported(format: Format) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{format:?} images are not supported, convert them to RVZ (zstd), GCZ or ISO with Dolphin first"),
+    )
+}
+
+fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+fn read_u32(backend: &dyn Backend, offset: u64) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    backend.read_at(offset, &mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(backend: &dyn Backend, offset: u64) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    backend.read_at(offset, &mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Split a read into reads within each block: `read(block, offset in block, buf)`.
+fn read_blocks(
+    offset: u64,
+    buf: &mut [u8],
+    size: u64,
+    block_size: u64,
+    mut read: impl FnMut(u64, usize, &mut [u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    if offset + buf.len() as u64 > size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let mut done = 0;
+    while done < buf.len() {
+        let position = offset + done as u64;
+        let within = (position % block_size) as usize;
+        let length = (buf.len() - done).min(block_size as usize - within);
+        read(position / block_size, within, &mut buf[done..done + length])?;
+        done += length;
+    }
+    Ok(())
+}
+
+/// A CISO image.
+///
+/// The header is the magic, the block size, and a map with one byte per
+/// block: stored blocks follow the header in order, missing blocks are zeros.
+pub struct Ciso<B: Backend> {
+    inner: B,
+    block_size: u64,
+    /// Where each block is stored in `inner`, if it is stored at all.
+    blocks: Vec<Option<u64>>,
+    size: u64,
+}
+
+impl<B: Backend> Ciso<B> {
+    pub fn new(inner: B) -> io::Result<Ciso<B>> {
+        let mut header = vec![0; CISO_HEADER_SIZE as usize];
+        inner.read_at(0, &mut header)?;
+        if &header[..4] != b"CISO" {
+            return Err(invalid("not a ciso image"));
+        }
+        let block_size = u32::from_le_bytes(header[4..8].try_into().expect("4 bytes")) as u64;
+        if block_size == 0 {
+            return Err(invalid("ciso block size is zero"));
+        }
+
+        let mut stored = 0;
+        let mut blocks = header[8..]
+            .iter()
+            .map(|present| {
+                (*present != 0).then(|| {
+                    stored += 1;
+                    CISO_HEADER_SIZE + (stored - 1) * block_size
+                })
+            })
+            .collect::<Vec<_>>();
+        while blocks.last() == Some(&None) {
+            blocks.pop();
+        }
+
+        // the last block of a full disc is padded past its end
+        let mut size = blocks.len() as u64 * block_size;
+        if size > GCM_SIZE && size - GCM_SIZE < block_size {
+            size = GCM_SIZE;
+        }
+
+        Ok(Ciso {
+            inner,
+            block_size,
+            blocks,
+            size,
+        })
+    }
+}
+
+impl<B: Backend> Backend for Ciso<B> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        read_blocks(
+            offset,
+            buf,
+            self.size,
+            self.block_size,
+            |block, within, buf| match self.blocks[block as usize] {
+                Some(start) => self.inner.read_at(start + within as u64, buf),
+                None => {
+                    buf.fill(0);
+                    Ok(())
+                }
+            },
+        )
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+}
+
+/// A GCZ image, as written by Dolphin.
+///
+/// The header is followed by a pointer to each block, an Adler-32 of each
+/// block, and then the blocks. Pointers with the top bit set are stored
+/// uncompressed, others are zlib streams.
+pub struct Gcz<B: Backend> {
+    inner: B,
+    block_size: u64,
+    /// Offset and length of each block in `inner`, and whether it is compressed.
+    blocks: Vec<(u64, u64, bool)>,
+    size: u64,
+    /// The most recently decompressed block.
+    cache: Mutex<Option<(u64, Vec<u8>)>>,
+}
+
+impl<B: Backend> Gcz<B> {
+    pub fn new(inner: B) -> io::Result<Gcz<B>> {
+        if read_u32(&inner, 0)? != GCZ_MAGIC {
+            return Err(invalid("not a gcz image"));
+        }
+        let compressed_size = read_u64(&inner, 0x08)?;
+        let size = read_u64(&inner, 0x10)?;
+        let block_size = read_u32(&inner, 0x18)? as u64;
+        let block_count = read_u32(&inner, 0x1c)? as u64;
+        if block_size == 0 || block_count * block_size < size {
+            return Err(invalid("gcz header is inconsistent"));
+        }
+
+        let data_offset = GCZ_HEADER_SIZE + block_count * 12;
+        let mut pointers = vec![0; block_count as usize * 8];
+        inner.read_at(GCZ_HEADER_SIZE, &mut pointers)?;
+        let pointers = pointers
+            .chunks_exact(8)
+            .map(|pointer| u64::from_le_bytes(pointer.try_into().expect("8 bytes")))
+            .collect::<Vec<_>>();
+
+        const UNCOMPRESSED: u64 = 1 << 63;
+        let blocks = pointers
+            .iter()
+            .enumerate()
+            .map(|(index, pointer)| {
+                let start = pointer & !UNCOMPRESSED;
+                let end = pointers
+                    .get(index + 1)
+                    .map_or(compressed_size, |next| next & !UNCOMPRESSED);
+                let length = end
+                    .checked_sub(start)
+                    .ok_or_else(|| invalid("gcz block pointers out of order"))?;
+                Ok((data_offset + start, length, pointer & UNCOMPRESSED == 0))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Gcz {
+            inner,
+            block_size,
+            blocks,
+            size,
+            cache: Mutex::new(None),
+        })
+    }
+
+    /// Read and decompress a whole block.
+    fn block(&self, index: u64) -> io::Result<Vec<u8>> {
+        let (start, length, compressed) = self.blocks[index as usize];
+        let mut stored = vec![0; length as usize];
+        self.inner.read_at(start, &mut stored)?;
+        if !compressed {
+            return Ok(stored);
+        }
+
+        let mut block = Vec::with_capacity(self.block_size as usize);
+        ZlibDecoder::new(&stored[..]).read_to_end(&mut block)?;
+        Ok(block)
+    }
+}
+
+impl<B: Backend> Backend for Gcz<B> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut cache = self.cache.lock().expect("gcz cache poisoned");
+        read_blocks(
+            offset,
+            buf,
+            self.size,
+            self.block_size,
+            |index, within, buf| {
+                if !matches!(&*cache, Some((cached, _)) if *cached == index) {
+                    *cache = Some((index, self.block(index)?));
+                }
+                let (_, block) = cache.as_ref().expect("block cached");
+                let data = block
+                    .get(within..within + buf.len())
+                    .ok_or_else(|| invalid(format!("gcz block {index} is too short")))?;
+                buf.copy_from_slice(data);
+                Ok(())
+            },
+        )
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+}
+
+/// RVZ compression methods (`wia_disc_t.compression`).
+const RVZ_NONE: u32 = 0;
+const RVZ_ZSTD: u32 = 5;
+
+/// Size of the blocks junk data is generated in, each from its own seed.
+const JUNK_BLOCK_SIZE: u64 = 0x8000;
+
+/// The lagged Fibonacci generator GameCube discs fill the space between
+/// files with, as implemented by Dolphin.
+struct Junk {
+    buffer: [u32; Junk::K],
+    /// Byte position within `buffer`.
+    position: usize,
+}
+
+impl Junk {
+    const K: usize = 521;
+    const J: usize = 32;
+    const SEED_SIZE: usize = 17;
+
+    fn new(seed: &[u8]) -> Junk {
+        let mut buffer = [0; Junk::K];
+        for (word, bytes) in buffer.iter_mut().zip(seed.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().expect("4 bytes"));
+        }
+        for i in Junk::SEED_SIZE..Junk::K {
+            buffer[i] = (buffer[i - 17] << 23) ^ (buffer[i - 16] >> 9) ^ buffer[i - 1];
+        }
+        // the output takes bits 18..26 instead of 16..24 for its third byte
+        for word in &mut buffer {
+            *word = (*word & 0xff00ffff) | ((*word >> 2) & 0x00ff0000);
+        }
+
+        let mut junk = Junk {
+            buffer,
+            position: 0,
+        };
+        for _ in 0..4 {
+            junk.forward();
+        }
+        junk
+    }
+
+    fn forward(&mut self) {
+        for i in 0..Junk::J {
+            self.buffer[i] ^= self.buffer[i + Junk::K - Junk::J];
+        }
+        for i in Junk::J..Junk::K {
+            self.buffer[i] ^= self.buffer[i - Junk::J];
+        }
+    }
+
+    fn skip(&mut self, count: usize) {
+        self.position += count;
+        while self.position >= Junk::K * 4 {
+            self.forward();
+            self.position -= Junk::K * 4;
+        }
+    }
+
+    fn fill(&mut self, out: &mut [u8]) {
+        for byte in out {
+            *byte = self.buffer[self.position / 4].to_be_bytes()[self.position % 4];
+            self.skip(1);
+        }
+    }
+}
+
+/// Unpack an RVZ group of `size` bytes starting at `offset` on the disc.
+///
+/// Packed data is a series of runs, each a big endian length, followed by
+/// that many bytes of data, or (with the top bit set) by the seed of junk.
+fn unpack_rvz(mut packed: &[u8], offset: u64, size: usize) -> io::Result<Vec<u8>> {
+    let mut take = |length: usize| -> io::Result<&[u8]> {
+        if packed.len() < length {
+            return Err(invalid("rvz packed data is truncated"));
+        }
+        let (taken, rest) = packed.split_at(length);
+        packed = rest;
+        Ok(taken)
+    };
+
+    let mut group = Vec::with_capacity(size);
+    while group.len() < size {
+        let run = u32::from_be_bytes(take(4)?.try_into().expect("4 bytes"));
+        let length = ((run & 0x7fffffff) as usize).min(size - group.len());
+        if run & 0x80000000 != 0 {
+            let mut junk = Junk::new(take(Junk::SEED_SIZE * 4)?);
+            junk.skip(((offset + group.len() as u64) % JUNK_BLOCK_SIZE) as usize);
+            let start = group.len();
+            group.resize(start + length, 0);
+            junk.fill(&mut group[start..]);
+        } else {
+            group.extend_from_slice(take(length)?);
+        }
+    }
+    Ok(group)
+}
+
+/// A group of an RVZ image, holding one chunk of the disc.
+#[derive(Debug, Clone, Copy)]
+struct RvzGroup {
+    /// Where the group starts on the disc, and how many bytes it holds.
+    offset: u64,
+    size: u64,
+    /// Where the group is stored in the file, and how many bytes it takes.
+    start: u64,
+    length: u64,
+    compressed: bool,
+    /// Size of the packed data, or zero if the group isn't packed.
+    packed: u64,
+}
+
+/// An RVZ image of a GameCube disc, as written by Dolphin.
+///
+/// The file and disc headers are followed by tables of raw data regions and
+/// groups (compressed like the groups themselves), and then the groups. The
+/// first 0x80 bytes of the disc are kept in the disc header.
+pub struct Rvz<B: Backend> {
+    inner: B,
+    disc_header: [u8; 0x80],
+    groups: Vec<RvzGroup>,
+    size: u64,
+    /// The most recently decompressed group.
+    cache: Mutex<Option<(usize, Vec<u8>)>>,
+}
+
+impl<B: Backend> Rvz<B> {
+    pub fn new(inner: B) -> io::Result<Rvz<B>> {
+        let mut head = [0; RVZ_HEAD_SIZE as usize];
+        inner.read_at(0, &mut head)?;
+        if &head[..4] != RVZ_MAGIC {
+            return Err(invalid("not an rvz image"));
+        }
+        let be32 = |bytes: &[u8], at: usize| {
+            u32::from_be_bytes(bytes[at..at + 4].try_into().expect("4 bytes"))
+        };
+        let be64 = |bytes: &[u8], at: usize| {
+            u64::from_be_bytes(bytes[at..at + 8].try_into().expect("8 bytes"))
+        };
+        let size = be64(&head, 0x24);
+
+        let mut disc = [0; RVZ_DISC_SIZE as usize];
+        inner.read_at(RVZ_HEAD_SIZE, &mut disc)?;
+        if be32(&disc, 0x00) != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "rvz image is not a GameCube disc",
+            ));
+        }
+        let compression = be32(&disc, 0x04);
+        if compression != RVZ_NONE && compression != RVZ_ZSTD {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("rvz compression {compression} is not supported, convert the image to RVZ with zstd, GCZ or ISO with Dolphin first"),
+            ));
+        }
+        let chunk_size = be32(&disc, 0x0c) as u64;
+        if chunk_size == 0 {
+            return Err(invalid("rvz chunk size is zero"));
+        }
+        let disc_header = disc[0x10..0x90].try_into().expect("0x80 bytes");
+        if be32(&disc, 0x90) != 0 {
+            return Err(invalid("rvz image of a GameCube disc has partitions"));
+        }
+
+        let table = |count: u32, entry_size: usize, offset: u64, length: u32| {
+            let mut stored = vec![0; length as usize];
+            inner.read_at(offset, &mut stored)?;
+            let expected = count as usize * entry_size;
+            let table = match compression {
+                RVZ_ZSTD => zstd::bulk::decompress(&stored, expected)?,
+                _ => stored,
+            };
+            if table.len() < expected {
+                return Err(invalid("rvz table is truncated"));
+            }
+            Ok::<_, io::Error>(table)
+        };
+        let raw_data = table(
+            be32(&disc, 0xb4),
+            0x18,
+            be64(&disc, 0xb8),
+            be32(&disc, 0xc0),
+        )?;
+        let group_entries = table(
+            be32(&disc, 0xc4),
+            0x0c,
+            be64(&disc, 0xc8),
+            be32(&disc, 0xd0),
+        )?;
+
+        let mut groups = Vec::new();
+        for raw in raw_data.chunks_exact(0x18).take(be32(&disc, 0xb4) as usize) {
+            // regions are stored from the start of the 0x8000 byte block they begin in
+            let offset = be64(raw, 0x00);
+            let start = offset - offset % 0x8000;
+            let end = offset + be64(raw, 0x08);
+            let first = be32(raw, 0x10) as usize;
+            for index in 0..be32(raw, 0x14) as usize {
+                let entry = group_entries
+                    .get((first + index) * 0x0c..(first + index + 1) * 0x0c)
+                    .ok_or_else(|| invalid("rvz group index out of bounds"))?;
+                let group_offset = start + index as u64 * chunk_size;
+                if group_offset >= end {
+                    return Err(invalid("rvz region has too many groups"));
+                }
+                let length = be32(entry, 0x04);
+                groups.push(RvzGroup {
+                    offset: group_offset,
+                    size: chunk_size.min(end - group_offset),
+                    start: (be32(entry, 0x00) as u64) << 2,
+                    length: (length & 0x7fffffff) as u64,
+                    compressed: compression == RVZ_ZSTD && length & 0x80000000 != 0,
+                    packed: be32(entry, 0x08) as u64,
+                });
+            }
+        }
+        groups.sort_by_key(|group| group.offset);
+
+        Ok(Rvz {
+            inner,
+            disc_header,
+            groups,
+            size,
+            cache: Mutex::new(None),
+        })
+    }
+
+    /// Read, decompress and unpack a whole group.
+    fn group(&self, group: &RvzGroup) -> io::Result<Vec<u8>> {
+        let size = group.size as usize;
+        if group.length == 0 {
+            return Ok(vec![0; size]);
+        }
+
+        let mut stored = vec![0; group.length as usize];
+        self.inner.read_at(group.start, &mut stored)?;
+        let data = match group.compressed {
+            true => zstd::bulk::decompress(&stored, size.max(group.packed as usize))?,
+            false => stored,
+        };
+        if group.packed != 0 {
+            return unpack_rvz(&data, group.offset, size);
+        }
+        if data.len() < size {
+            return Err(invalid(format!(
+                "rvz group at {:#x} is too short",
+                group.offset
+            )));
+        }
+        Ok(data)
+    }
+}
+
+impl<B: Backend> Backend for Rvz<B> {
+    fn read_at(&self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        if offset + buf.len() as u64 > self.size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        if offset < 0x80 {
+            let length = buf.len().min(0x80 - offset as usize);
+            buf[..length].copy_from_slice(&self.disc_header[offset as usize..][..length]);
+            offset += length as u64;
+            buf = &mut buf[length..];
+        }
+
+        let mut cache = self.cache.lock().expect("rvz cache poisoned");
+        while !buf.is_empty() {
+            let index = self
+                .groups
+                .partition_point(|group| group.offset + group.size <= offset);
+            let group = self
+                .groups
+                .get(index)
+                .filter(|group| group.offset <= offset)
+                .ok_or_else(|| invalid(format!("rvz image has no data at {offset:#x}")))?;
+            if !matches!(&*cache, Some((cached, _)) if *cached == index) {
+                *cache = Some((index, self.group(group)?));
+            }
+            let (_, data) = cache.as_ref().expect("group cached");
+
+            let within = (offset - group.offset) as usize;
+            let length = buf.len().min(group.size as usize - within);
+            buf[..length].copy_from_slice(&data[within..within + length]);
+            offset += length as u64;
+            buf = &mut buf[length..];
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+}
+
+/// Wrap `inner` in a reader for its format, detected from its first bytes.
+pub fn from_backend<B: Backend + 'static>(inner: B) -> io::Result<Box<dyn Backend>> {
+    let mut magic = [0; 4];
+    if inner.size()? >= 4 {
+        inner.read_at(0, &mut magic)?;
+    }
+
+    match Format::detect(magic) {
+        Format::Gcm => Ok(Box::new(inner)),
+        Format::Ciso => Ok(Box::new(Ciso::new(inner)?)),
+        Format::Gcz => Ok(Box::new(Gcz::new(inner)?)),
+        Format::Rvz => Ok(Box::new(Rvz::new(inner)?)),
+        Format::Wia => Err(unsupported(Format::Wia)),
+    }
+}
+
+/// Open a disc image on disk, raw or compressed.
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Backend>> {
+    from_backend(File::open(path)?)
+}
+
+/// Is this an NKit image?
+///
+/// NKit removes the junk data between files and packs them together, so
+/// files aren't at their vanilla offsets and the image doesn't hash like a
+/// vanilla dump. The FST still describes the trimmed layout, so reading files
+/// works, but patches made against it only apply to the same NKit image.
+pub fn is_nkit(backend: &dyn Backend) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    if backend.size()? < NKIT_OFFSET + 4 {
+        return Ok(false);
+    }
+    backend.read_at(NKIT_OFFSET, &mut magic)?;
+    Ok(&magic == NKIT_MAGIC)
+}
+
+/// Pad an image with zeros to the size of a full GameCube disc.
+///
+/// Built images end after their last file; some loaders and burning tools
+/// expect a full size image instead.
+pub fn pad_to_disc_size(image: &mut Vec<u8>) -> io::Result<()> {
+    if image.len() as u64 > GCM_SIZE {
+        return Err(invalid(format!(
+            "image is {:#x} bytes, larger than a full disc",
+            image.len()
+        )));
+    }
+    image.resize(GCM_SIZE as usize, 0);
+    Ok(())
+}
+
+/// Write a CISO image, leaving out blocks of zeros.
+///
+/// The image is read twice: once to find the blocks of zeros for the
+/// header, and once to copy the other blocks.
+pub fn write_ciso<W: Write>(image: &dyn Backend, mut out: W) -> io::Result<()> {
+    let size = image.size()?;
+    let blocks = size.div_ceil(CISO_BLOCK_SIZE as u64) as usize;
+    let mut header = vec![0; CISO_HEADER_SIZE as usize];
+    if blocks > header.len() - 8 {
+        return Err(invalid("image has too many blocks for a ciso header"));
+    }
+    header[..4].copy_from_slice(b"CISO");
+    header[4..8].copy_from_slice(&(CISO_BLOCK_SIZE as u32).to_le_bytes());
+
+    let mut block = vec![0; CISO_BLOCK_SIZE];
+    let read_block = |index: usize, block: &mut [u8]| {
+        let start = (index * CISO_BLOCK_SIZE) as u64;
+        let length = (size - start).min(CISO_BLOCK_SIZE as u64) as usize;
+        block.fill(0);
+        image.read_at(start, &mut block[..length])
+    };
+    for index in 0..blocks {
+        read_block(index, &mut block)?;
+        header[8 + index] = block.iter().any(|byte| *byte != 0) as u8;
+    }
+    out.write_all(&header)?;
+
+    for index in 0..blocks {
+        if header[8 + index] != 0 {
+            read_block(index, &mut block)?;
+            out.write_all(&block)?;
+        }
+    }
+    out.flush()
+}
+
+/// Adler-32 checksum, stored for each GCZ block.
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+/// Write a GCZ image, compressing each block with zlib.
+///
+/// Blocks that don't get smaller are stored uncompressed. The header is
+/// written last, once the size of every block is known.
+pub fn write_gcz<W: Write + Seek>(image: &dyn Backend, mut out: W) -> io::Result<()> {
+    const UNCOMPRESSED: u64 = 1 << 63;
+    let size = image.size()?;
+    let blocks = size.div_ceil(GCZ_BLOCK_SIZE as u64) as usize;
+    let data_offset = GCZ_HEADER_SIZE + blocks as u64 * 12;
+
+    let start = out.stream_position()?;
+    out.write_all(&vec![0; data_offset as usize])?;
+
+    let mut pointers = Vec::with_capacity(blocks);
+    let mut hashes = Vec::with_capacity(blocks);
+    let mut written = 0;
+    let mut block = vec![0; GCZ_BLOCK_SIZE];
+    for index in 0..blocks {
+        let offset = (index * GCZ_BLOCK_SIZE) as u64;
+        let block = &mut block[..(size - offset).min(GCZ_BLOCK_SIZE as u64) as usize];
+        image.read_at(offset, block)?;
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(block)?;
+        let compressed = encoder.finish()?;
+
+        let (stored, flag) = match compressed.len() < block.len() {
+            true => (&compressed[..], 0),
+            false => (&block[..], UNCOMPRESSED),
+        };
+        pointers.push(written | flag);
+        hashes.push(adler32(stored));
+        out.write_all(stored)?;
+        written += stored.len() as u64;
+    }
+
+    let mut header = Vec::with_capacity(data_offset as usize);
+    header.extend_from_slice(&GCZ_MAGIC.to_le_bytes());
+    // sub type: 0 for GameCube images
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&written.to_le_bytes());
+    header.extend_from_slice(&size.to_le_bytes());
+    header.extend_from_slice(&(GCZ_BLOCK_SIZE as u32).to_le_bytes());
+    header.extend_from_slice(&(blocks as u32).to_le_bytes());
+    for pointer in pointers {
+        header.extend_from_slice(&pointer.to_le_bytes());
+    }
+    for hash in hashes {
+        header.extend_from_slice(&hash.to_le_bytes());
+    }
+    out.seek(SeekFrom::Start(start))?;
+    out.write_all(&header)?;
+    out.seek(SeekFrom::End(0))?;
+    out.flush()
+}
+
+/// Write an RVZ image of a GameCube disc, compressing each chunk with zstd.
+///
+/// Chunks of zeros take no space, and chunks that don't get smaller are
+/// stored uncompressed. Junk data isn't packed as seeds, so it is stored
+/// as is. The group table and headers are written last, once the size of
+/// every group is known.
+pub fn write_rvz<W: Write + Seek>(image: &dyn Backend, mut out: W) -> io::Result<()> {
+    const COMPRESSED: u32 = 1 << 31;
+    let size = image.size()?;
+    if size < 0x80 {
+        return Err(invalid("image is too small for a disc header"));
+    }
+    let chunks = size.div_ceil(RVZ_CHUNK_SIZE as u64);
+    let compress = |data: &[u8]| zstd::bulk::compress(data, RVZ_ZSTD_LEVEL);
+
+    let start = out.stream_position()?;
+    let headers_size = RVZ_HEAD_SIZE + RVZ_DISC_SIZE;
+    out.write_all(&vec![0; headers_size as usize])?;
+
+    // one region of raw data, after the disc header
+    let mut raw_data = Vec::with_capacity(0x18);
+    raw_data.extend_from_slice(&0x80u64.to_be_bytes());
+    raw_data.extend_from_slice(&(size - 0x80).to_be_bytes());
+    raw_data.extend_from_slice(&0u32.to_be_bytes());
+    raw_data.extend_from_slice(&(chunks as u32).to_be_bytes());
+    let raw_data = compress(&raw_data)?;
+    let raw_data_offset = headers_size;
+    out.write_all(&raw_data)?;
+
+    let mut position = raw_data_offset + raw_data.len() as u64;
+    let mut groups = Vec::with_capacity(chunks as usize * 12);
+    let mut chunk = vec![0; RVZ_CHUNK_SIZE];
+    for index in 0..chunks {
+        // groups are stored at multiples of four
+        let padding = position.next_multiple_of(4) - position;
+        out.write_all(&[0; 4][..padding as usize])?;
+        position += padding;
+
+        let offset = index * RVZ_CHUNK_SIZE as u64;
+        let chunk = &mut chunk[..(size - offset).min(RVZ_CHUNK_SIZE as u64) as usize];
+        image.read_at(offset, chunk)?;
+
+        let (stored, flag) = if chunk.iter().all(|byte| *byte == 0) {
+            (Vec::new(), 0)
+        } else {
+            let compressed = compress(chunk)?;
+            match compressed.len() < chunk.len() {
+                true => (compressed, COMPRESSED),
+                false => (chunk.to_vec(), 0),
+            }
+        };
+        groups.extend_from_slice(&((position / 4) as u32).to_be_bytes());
+        groups.extend_from_slice(&(stored.len() as u32 | flag).to_be_bytes());
+        // not packed
+        groups.extend_from_slice(&0u32.to_be_bytes());
+        out.write_all(&stored)?;
+        position += stored.len() as u64;
+    }
+
+    let groups = compress(&groups)?;
+    let group_offset = position;
+    out.write_all(&groups)?;
+    let file_size = position + groups.len() as u64;
+
+    let mut header = vec![0; 0x80];
+    image.read_at(0, &mut header)?;
+    let mut disc = Vec::with_capacity(RVZ_DISC_SIZE as usize);
+    // GameCube
+    disc.extend_from_slice(&1u32.to_be_bytes());
+    disc.extend_from_slice(&RVZ_ZSTD.to_be_bytes());
+    disc.extend_from_slice(&RVZ_ZSTD_LEVEL.to_be_bytes());
+    disc.extend_from_slice(&(RVZ_CHUNK_SIZE as u32).to_be_bytes());
+    disc.extend_from_slice(&header);
+    // no partitions, with an empty table just before the raw data table
+    disc.extend_from_slice(&0u32.to_be_bytes());
+    disc.extend_from_slice(&0x30u32.to_be_bytes());
+    disc.extend_from_slice(&raw_data_offset.to_be_bytes());
+    disc.extend_from_slice(&Sha1::digest([])[..]);
+    disc.extend_from_slice(&1u32.to_be_bytes());
+    disc.extend_from_slice(&raw_data_offset.to_be_bytes());
+    disc.extend_from_slice(&(raw_data.len() as u32).to_be_bytes());
+    disc.extend_from_slice(&(chunks as u32).to_be_bytes());
+    disc.extend_from_slice(&group_offset.to_be_bytes());
+    disc.extend_from_slice(&(groups.len() as u32).to_be_bytes());
+    // no compressor properties for zstd
+    disc.extend_from_slice(&[0; 8]);
+
+    let mut head = Vec::with_capacity(RVZ_HEAD_SIZE as usize);
+    head.extend_from_slice(RVZ_MAGIC);
+    // version, and the oldest version able to read it
+    head.extend_from_slice(&0x01000000u32.to_be_bytes());
+    head.extend_from_slice(&0x00030000u32.to_be_bytes());
+    head.extend_from_slice(&(RVZ_DISC_SIZE as u32).to_be_bytes());
+    head.extend_from_slice(&Sha1::digest(&disc)[..]);
+    head.extend_from_slice(&size.to_be_bytes());
+    head.extend_from_slice(&file_size.to_be_bytes());
+    let hash = Sha1::digest(&head);
+    head.extend_from_slice(&hash[..]);
+
+    out.seek(SeekFrom::Start(start))?;
+    out.write_all(&head)?;
+    out.write_all(&disc)?;
+    out.seek(SeekFrom::End(0))?;
+    out.flush()
+}
+
+/// Write an image in the given format.
+pub fn write<W: Write + Seek>(image: &dyn Backend, format: Format, mut out: W) -> io::Result<()> {
+    match format {
+        Format::Gcm => {
+            io::copy(&mut Reader::new(image)?, &mut out)?;
+            out.flush()
+        }
+        Format::Ciso => write_ciso(image, out),
+        Format::Gcz => write_gcz(image, out),
+        Format::Rvz => write_rvz(image, out),
+        Format::Wia => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "WIA images can't be written, use RVZ instead",
+        )),
+    }
+}
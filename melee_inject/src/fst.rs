@@ -0,0 +1,346 @@
+//! Editable filesystem table.
+//!
+//! `gc_gcm::FileSystem` is read-only and drops the raw entry indices, which
+//! are needed to add or remove files. [`Fst`] keeps every entry in disc order
+//! (including the root) and can be serialized back into a table the game can
+//! load.
+use super::parse;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Offset of the filesystem table within v1.02 NTSC GALE01.
+pub const FST_OFFSET: u64 = 0x456e00;
+/// Length of the filesystem table within v1.02 NTSC GALE01.
+pub const FST_LENGTH: u64 = 0x7529;
+/// Size of a single file or directory entry.
+pub const ENTRY_SIZE: usize = 0x0c;
+/// Where the disc header records the offset of the filesystem table, followed
+/// by its size (0x428) and maximum size (0x42c).
+pub const HEADER_OFFSET: u64 = 0x424;
+
+/// Read the raw filesystem table from an image, at the offset and size
+/// recorded in its disc header.
+///
+/// Rebuilt images with added files have a larger table than vanilla.
+pub fn read<R: Read + Seek>(iso: &mut R) -> io::Result<Vec<u8>> {
+    let mut header = [0; 8];
+    iso.seek(SeekFrom::Start(HEADER_OFFSET))?;
+    iso.read_exact(&mut header)?;
+    let offset = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let size = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+
+    let mut fst = Vec::new();
+    iso.seek(SeekFrom::Start(offset as u64))?;
+    iso.take(size as u64).read_to_end(&mut fst)?;
+    if fst.len() != size as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("fst at {offset:#x} ({size:#x} bytes) runs past the end of the image"),
+        ));
+    }
+    Ok(fst)
+}
+
+/// A single file or directory entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    File {
+        name: String,
+        offset: u32,
+        size: u32,
+    },
+    Directory {
+        name: String,
+        /// Index of the parent directory entry.
+        parent: u32,
+        /// Index of the first entry following this directory.
+        next: u32,
+    },
+}
+
+impl Entry {
+    /// File or directory name, without any leading path.
+    pub fn name(&self) -> &str {
+        match self {
+            Entry::File { name, .. } | Entry::Directory { name, .. } => name,
+        }
+    }
+}
+
+/// A parsed filesystem table. `entries[0]` is always the root directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fst {
+    pub entries: Vec<Entry>,
+}
+
+impl Fst {
+    /// Parse a raw filesystem table (entries followed by the string table).
+    pub fn parse(fst: &[u8]) -> io::Result<Fst> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let node_at = |index: usize| -> io::Result<[u8; ENTRY_SIZE]> {
+            fst.get(index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE)
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| invalid(format!("fst entry {index} out of bounds")))
+        };
+
+        let root = node_at(0)?;
+        if !parse::node_is_directory(root) {
+            return Err(invalid("fst root entry is not a directory".to_string()));
+        }
+        let num_entries = parse::root_node_num_entries(root) as usize;
+        let string_table = num_entries * ENTRY_SIZE;
+
+        let mut entries = Vec::with_capacity(num_entries);
+        for index in 0..num_entries {
+            let node = node_at(index)?;
+            let name = if index == 0 {
+                String::new()
+            } else {
+                let start = string_table + parse::node_name_offset(node) as usize;
+                let bytes = fst
+                    .get(start..)
+                    .ok_or_else(|| invalid(format!("fst entry {index} name out of bounds")))?;
+                let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+                String::from_utf8_lossy(&bytes[..end]).into_owned()
+            };
+
+            entries.push(if parse::node_is_directory(node) {
+                Entry::Directory {
+                    name,
+                    parent: parse::node_file_offset(node),
+                    next: parse::node_file_size(node),
+                }
+            } else {
+                Entry::File {
+                    name,
+                    offset: parse::node_file_offset(node),
+                    size: parse::node_file_size(node),
+                }
+            });
+        }
+
+        Ok(Fst { entries })
+    }
+
+    /// Serialize entries and string table, in entry order.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut nodes = Vec::with_capacity(self.entries.len() * ENTRY_SIZE);
+        let mut strings: Vec<u8> = Vec::new();
+
+        for (index, entry) in self.entries.iter().enumerate() {
+            let name_offset = if index == 0 {
+                0
+            } else {
+                let offset = strings.len() as u32;
+                strings.extend(entry.name().as_bytes());
+                strings.push(0);
+                offset
+            };
+
+            let (flag, first, second) = match entry {
+                Entry::File { offset, size, .. } => (0u8, *offset, *size),
+                Entry::Directory { next, .. } if index == 0 => (1u8, 0, *next),
+                Entry::Directory { parent, next, .. } => (1u8, *parent, *next),
+            };
+
+            nodes.push(flag);
+            nodes.extend(&name_offset.to_be_bytes()[1..]);
+            nodes.extend(first.to_be_bytes());
+            nodes.extend(second.to_be_bytes());
+        }
+
+        nodes.extend(strings);
+        nodes
+    }
+
+    /// Full path of every entry, separated by `/` (the root is `""`).
+    pub fn paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = Vec::with_capacity(self.entries.len());
+        // stack of (directory path, index of first entry after the directory)
+        let mut stack: Vec<(String, u32)> = Vec::new();
+
+        for (index, entry) in self.entries.iter().enumerate() {
+            while matches!(stack.last(), Some((_, next)) if *next as usize <= index) {
+                stack.pop();
+            }
+
+            let path = match stack.last() {
+                Some((parent, _)) if !parent.is_empty() => format!("{parent}/{}", entry.name()),
+                _ => entry.name().to_string(),
+            };
+
+            if let Entry::Directory { next, .. } = entry {
+                stack.push((path.clone(), *next));
+            }
+            paths.push(path);
+        }
+
+        paths
+    }
+
+    /// Find a file entry by full path (`"audio/1padv.ssm"`) or, if the target
+    /// contains no `/`, by file name (`"PlCaGr.dat"`).
+    ///
+    /// File names must be unique within the table to match.
+    pub fn find(&self, target: &str) -> io::Result<usize> {
+        let target = target.trim_start_matches('/');
+        let by_path = target.contains('/');
+        let paths = self.paths();
+
+        let found = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(index, entry)| {
+                matches!(entry, Entry::File { .. })
+                    && if by_path {
+                        paths[*index] == target
+                    } else {
+                        entry.name() == target
+                    }
+            })
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+
+        match found.as_slice() {
+            [index] => Ok(*index),
+            [] => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no fst entry found for {target:?}"),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} fst entries found for {target:?}", found.len()),
+            )),
+        }
+    }
+
+    /// Index of the directory at `path`, or `0` for the root.
+    fn find_directory(&self, path: &str) -> Option<usize> {
+        if path.is_empty() {
+            return Some(0);
+        }
+        let paths = self.paths();
+        self.entries.iter().enumerate().position(|(index, entry)| {
+            matches!(entry, Entry::Directory { .. }) && paths[index] == path
+        })
+    }
+
+    /// Is `directory` the directory `ancestor`, or nested within it?
+    fn within(&self, directory: usize, ancestor: usize) -> bool {
+        let mut current = directory;
+        loop {
+            if current == ancestor {
+                return true;
+            }
+            match &self.entries[current] {
+                Entry::Directory { parent, .. } if current != 0 => current = *parent as usize,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Insert `entry` as the last child of the directory at index `parent`.
+    fn insert(&mut self, parent: usize, entry: Entry) -> usize {
+        let position = match &self.entries[parent] {
+            Entry::Directory { next, .. } => *next as usize,
+            Entry::File { .. } => panic!("fst entry {parent} is not a directory"),
+        };
+
+        for index in 0..self.entries.len() {
+            let encloses = index < position && self.within(parent, index);
+            if let Entry::Directory {
+                parent: dir_parent,
+                next,
+                ..
+            } = &mut self.entries[index]
+            {
+                if index >= position || encloses {
+                    *next += 1;
+                }
+                if index >= position && *dir_parent as usize >= position {
+                    *dir_parent += 1;
+                }
+            }
+        }
+
+        self.entries.insert(position, entry);
+        position
+    }
+
+    /// Add a file at `path`, creating any missing parent directories.
+    ///
+    /// Returns the index of the new entry.
+    pub fn add_file(&mut self, path: &str, offset: u32, size: u32) -> io::Result<usize> {
+        let path = path.trim_start_matches('/');
+        if self.find(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("fst entry already exists: {path:?}"),
+            ));
+        }
+
+        let (directory, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let mut parent = 0;
+        let mut current = String::new();
+        for component in directory.split('/').filter(|c| !c.is_empty()) {
+            if !current.is_empty() {
+                current.push('/');
+            }
+            current.push_str(component);
+
+            parent = match self.find_directory(&current) {
+                Some(index) => index,
+                None => {
+                    let next = match &self.entries[parent] {
+                        Entry::Directory { next, .. } => *next + 1,
+                        Entry::File { .. } => unreachable!(),
+                    };
+                    self.insert(
+                        parent,
+                        Entry::Directory {
+                            name: component.to_string(),
+                            parent: parent as u32,
+                            next,
+                        },
+                    )
+                }
+            };
+        }
+
+        Ok(self.insert(
+            parent,
+            Entry::File {
+                name: name.to_string(),
+                offset,
+                size,
+            },
+        ))
+    }
+
+    /// Remove the file entry at `index`.
+    pub fn remove_file(&mut self, index: usize) -> io::Result<Entry> {
+        match self.entries.get(index) {
+            Some(Entry::File { .. }) => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("fst entry {index} is not a file"),
+                ))
+            }
+        }
+
+        let removed = self.entries.remove(index);
+        for entry in &mut self.entries {
+            if let Entry::Directory { parent, next, .. } = entry {
+                if *parent as usize > index {
+                    *parent -= 1;
+                }
+                if *next as usize > index {
+                    *next -= 1;
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+}
@@ -1,6 +1,9 @@
 pub mod characters {
     #![allow(non_upper_case_globals)]
     //! Supported character files for replacement.
+    //!
+    //! Generated by `melee_inject_codegen`, including [`lookup`]; regenerate
+    //! rather than editing by hand.
 
     /// Supported files for Captain Falcon.
     #[non_exhaustive]
@@ -634,6 +637,229 @@ pub mod characters {
         /// White costume.
         pub const PlLkWh: &'static str = "PlLkWh.dat";
     }
+
+    /// Look up a supported file by its typed name, e.g. `"CaptainFalcon::PlCaGr"`.
+    ///
+    /// Returns the file name within the disc filesystem.
+    pub fn lookup(typed_name: &str) -> Option<&'static str> {
+        Some(match typed_name {
+            "CaptainFalcon::PlCa" => CaptainFalcon::PlCa,
+            "CaptainFalcon::PlCaBu" => CaptainFalcon::PlCaBu,
+            "CaptainFalcon::PlCaGr" => CaptainFalcon::PlCaGr,
+            "CaptainFalcon::PlCaGy" => CaptainFalcon::PlCaGy,
+            "CaptainFalcon::PlCaNr" => CaptainFalcon::PlCaNr,
+            "CaptainFalcon::PlCaRe" => CaptainFalcon::PlCaRe,
+            "CaptainFalcon::PlCaWh" => CaptainFalcon::PlCaWh,
+            "YoungLink::PlCl" => YoungLink::PlCl,
+            "YoungLink::PlClBk" => YoungLink::PlClBk,
+            "YoungLink::PlClBu" => YoungLink::PlClBu,
+            "YoungLink::PlClNr" => YoungLink::PlClNr,
+            "YoungLink::PlClRe" => YoungLink::PlClRe,
+            "YoungLink::PlClWh" => YoungLink::PlClWh,
+            "MaleWireframe::PlBo" => MaleWireframe::PlBo,
+            "MaleWireframe::PlBoNr" => MaleWireframe::PlBoNr,
+            "Falco::PlFc" => Falco::PlFc,
+            "Falco::PlFcBu" => Falco::PlFcBu,
+            "Falco::PlFcGr" => Falco::PlFcGr,
+            "Falco::PlFcNr" => Falco::PlFcNr,
+            "Falco::PlFcRe" => Falco::PlFcRe,
+            "MasterHand::PlMh" => MasterHand::PlMh,
+            "MasterHand::PlMhNr" => MasterHand::PlMhNr,
+            "Peach::PlPe" => Peach::PlPe,
+            "Peach::PlPeBu" => Peach::PlPeBu,
+            "Peach::PlPeGr" => Peach::PlPeGr,
+            "Peach::PlPeNr" => Peach::PlPeNr,
+            "Peach::PlPeWh" => Peach::PlPeWh,
+            "Peach::PlPeYe" => Peach::PlPeYe,
+            "GameNWatch::PlGw" => GameNWatch::PlGw,
+            "GameNWatch::PlGwNr" => GameNWatch::PlGwNr,
+            "Luigi::PlLg" => Luigi::PlLg,
+            "Luigi::PlLgAq" => Luigi::PlLgAq,
+            "Luigi::PlLgNr" => Luigi::PlLgNr,
+            "Luigi::PlLgPi" => Luigi::PlLgPi,
+            "Luigi::PlLgWh" => Luigi::PlLgWh,
+            "Pikachu::PlPk" => Pikachu::PlPk,
+            "Pikachu::PlPkBu" => Pikachu::PlPkBu,
+            "Pikachu::PlPkGr" => Pikachu::PlPkGr,
+            "Pikachu::PlPkNr" => Pikachu::PlPkNr,
+            "Pikachu::PlPkRe" => Pikachu::PlPkRe,
+            "Fox::PlFx" => Fox::PlFx,
+            "Fox::PlFxGr" => Fox::PlFxGr,
+            "Fox::PlFxLa" => Fox::PlFxLa,
+            "Fox::PlFxNr" => Fox::PlFxNr,
+            "Fox::PlFxOr" => Fox::PlFxOr,
+            "Jigglypuff::PlPr" => Jigglypuff::PlPr,
+            "Jigglypuff::PlPrBu" => Jigglypuff::PlPrBu,
+            "Jigglypuff::PlPrGr" => Jigglypuff::PlPrGr,
+            "Jigglypuff::PlPrNr" => Jigglypuff::PlPrNr,
+            "Jigglypuff::PlPrRe" => Jigglypuff::PlPrRe,
+            "Jigglypuff::PlPrYe" => Jigglypuff::PlPrYe,
+            "SandBag::PlSb" => SandBag::PlSb,
+            "SandBag::PlSbNr" => SandBag::PlSbNr,
+            "Marth::PlMs" => Marth::PlMs,
+            "Marth::PlMsBk" => Marth::PlMsBk,
+            "Marth::PlMsGr" => Marth::PlMsGr,
+            "Marth::PlMsNr" => Marth::PlMsNr,
+            "Marth::PlMsRe" => Marth::PlMsRe,
+            "Marth::PlMsWh" => Marth::PlMsWh,
+            "Samus::PlSs" => Samus::PlSs,
+            "Samus::PlSsBk" => Samus::PlSsBk,
+            "Samus::PlSsGr" => Samus::PlSsGr,
+            "Samus::PlSsLa" => Samus::PlSsLa,
+            "Samus::PlSsNr" => Samus::PlSsNr,
+            "Samus::PlSsPi" => Samus::PlSsPi,
+            "GigaBowser::PlGk" => GigaBowser::PlGk,
+            "GigaBowser::PlGkNr" => GigaBowser::PlGkNr,
+            "Ness::PlNs" => Ness::PlNs,
+            "Ness::PlNsBu" => Ness::PlNsBu,
+            "Ness::PlNsGr" => Ness::PlNsGr,
+            "Ness::PlNsNr" => Ness::PlNsNr,
+            "Ness::PlNsYe" => Ness::PlNsYe,
+            "Zelda::PlZd" => Zelda::PlZd,
+            "Zelda::PlZdBu" => Zelda::PlZdBu,
+            "Zelda::PlZdGr" => Zelda::PlZdGr,
+            "Zelda::PlZdNr" => Zelda::PlZdNr,
+            "Zelda::PlZdRe" => Zelda::PlZdRe,
+            "Zelda::PlZdWh" => Zelda::PlZdWh,
+            "IceClimbersNana::PlNn" => IceClimbersNana::PlNn,
+            "IceClimbersNana::PlNnAq" => IceClimbersNana::PlNnAq,
+            "IceClimbersNana::PlNnNr" => IceClimbersNana::PlNnNr,
+            "IceClimbersNana::PlNnWh" => IceClimbersNana::PlNnWh,
+            "IceClimbersNana::PlNnYe" => IceClimbersNana::PlNnYe,
+            "Pichu::PlPc" => Pichu::PlPc,
+            "Pichu::PlPcBu" => Pichu::PlPcBu,
+            "Pichu::PlPcGr" => Pichu::PlPcGr,
+            "Pichu::PlPcNr" => Pichu::PlPcNr,
+            "Pichu::PlPcRe" => Pichu::PlPcRe,
+            "CrazyHand::PlCh" => CrazyHand::PlCh,
+            "CrazyHand::PlChNr" => CrazyHand::PlChNr,
+            "Sheik::PlSk" => Sheik::PlSk,
+            "Sheik::PlSkBu" => Sheik::PlSkBu,
+            "Sheik::PlSkGr" => Sheik::PlSkGr,
+            "Sheik::PlSkNr" => Sheik::PlSkNr,
+            "Sheik::PlSkRe" => Sheik::PlSkRe,
+            "Sheik::PlSkWh" => Sheik::PlSkWh,
+            "FemaleWireframe::PlGl" => FemaleWireframe::PlGl,
+            "FemaleWireframe::PlGlNr" => FemaleWireframe::PlGlNr,
+            "Yoshi::PlYs" => Yoshi::PlYs,
+            "Yoshi::PlYsAq" => Yoshi::PlYsAq,
+            "Yoshi::PlYsBu" => Yoshi::PlYsBu,
+            "Yoshi::PlYsNr" => Yoshi::PlYsNr,
+            "Yoshi::PlYsPi" => Yoshi::PlYsPi,
+            "Yoshi::PlYsRe" => Yoshi::PlYsRe,
+            "Yoshi::PlYsYe" => Yoshi::PlYsYe,
+            "DonkeyKong::PlDk" => DonkeyKong::PlDk,
+            "DonkeyKong::PlDkBk" => DonkeyKong::PlDkBk,
+            "DonkeyKong::PlDkBu" => DonkeyKong::PlDkBu,
+            "DonkeyKong::PlDkGr" => DonkeyKong::PlDkGr,
+            "DonkeyKong::PlDkNr" => DonkeyKong::PlDkNr,
+            "DonkeyKong::PlDkRe" => DonkeyKong::PlDkRe,
+            "Mario::PlMr" => Mario::PlMr,
+            "Mario::PlMrBk" => Mario::PlMrBk,
+            "Mario::PlMrBu" => Mario::PlMrBu,
+            "Mario::PlMrGr" => Mario::PlMrGr,
+            "Mario::PlMrNr" => Mario::PlMrNr,
+            "Mario::PlMrYe" => Mario::PlMrYe,
+            "Ganondorf::PlGn" => Ganondorf::PlGn,
+            "Ganondorf::PlGnBu" => Ganondorf::PlGnBu,
+            "Ganondorf::PlGnGr" => Ganondorf::PlGnGr,
+            "Ganondorf::PlGnLa" => Ganondorf::PlGnLa,
+            "Ganondorf::PlGnNr" => Ganondorf::PlGnNr,
+            "Ganondorf::PlGnRe" => Ganondorf::PlGnRe,
+            "DrMario::PlDr" => DrMario::PlDr,
+            "DrMario::PlDrBk" => DrMario::PlDrBk,
+            "DrMario::PlDrBu" => DrMario::PlDrBu,
+            "DrMario::PlDrGr" => DrMario::PlDrGr,
+            "DrMario::PlDrNr" => DrMario::PlDrNr,
+            "DrMario::PlDrRe" => DrMario::PlDrRe,
+            "Kirby::PlKb" => Kirby::PlKb,
+            "Kirby::PlKbBu" => Kirby::PlKbBu,
+            "Kirby::PlKbBuCpDk" => Kirby::PlKbBuCpDk,
+            "Kirby::PlKbBuCpFc" => Kirby::PlKbBuCpFc,
+            "Kirby::PlKbBuCpMt" => Kirby::PlKbBuCpMt,
+            "Kirby::PlKbBuCpPr" => Kirby::PlKbBuCpPr,
+            "Kirby::PlKbCpCa" => Kirby::PlKbCpCa,
+            "Kirby::PlKbCpCl" => Kirby::PlKbCpCl,
+            "Kirby::PlKbCpDk" => Kirby::PlKbCpDk,
+            "Kirby::PlKbCpDr" => Kirby::PlKbCpDr,
+            "Kirby::PlKbCpFc" => Kirby::PlKbCpFc,
+            "Kirby::PlKbCpFe" => Kirby::PlKbCpFe,
+            "Kirby::PlKbCpFx" => Kirby::PlKbCpFx,
+            "Kirby::PlKbCpGn" => Kirby::PlKbCpGn,
+            "Kirby::PlKbCpGw" => Kirby::PlKbCpGw,
+            "Kirby::PlKbCpKp" => Kirby::PlKbCpKp,
+            "Kirby::PlKbCpLg" => Kirby::PlKbCpLg,
+            "Kirby::PlKbCpLk" => Kirby::PlKbCpLk,
+            "Kirby::PlKbCpMr" => Kirby::PlKbCpMr,
+            "Kirby::PlKbCpMs" => Kirby::PlKbCpMs,
+            "Kirby::PlKbCpMt" => Kirby::PlKbCpMt,
+            "Kirby::PlKbCpNs" => Kirby::PlKbCpNs,
+            "Kirby::PlKbCpPc" => Kirby::PlKbCpPc,
+            "Kirby::PlKbCpPe" => Kirby::PlKbCpPe,
+            "Kirby::PlKbCpPk" => Kirby::PlKbCpPk,
+            "Kirby::PlKbCpPp" => Kirby::PlKbCpPp,
+            "Kirby::PlKbCpPr" => Kirby::PlKbCpPr,
+            "Kirby::PlKbCpSk" => Kirby::PlKbCpSk,
+            "Kirby::PlKbCpSs" => Kirby::PlKbCpSs,
+            "Kirby::PlKbCpYs" => Kirby::PlKbCpYs,
+            "Kirby::PlKbCpZd" => Kirby::PlKbCpZd,
+            "Kirby::PlKbGr" => Kirby::PlKbGr,
+            "Kirby::PlKbGrCpDk" => Kirby::PlKbGrCpDk,
+            "Kirby::PlKbGrCpFc" => Kirby::PlKbGrCpFc,
+            "Kirby::PlKbGrCpMt" => Kirby::PlKbGrCpMt,
+            "Kirby::PlKbGrCpPr" => Kirby::PlKbGrCpPr,
+            "Kirby::PlKbNr" => Kirby::PlKbNr,
+            "Kirby::PlKbNrCpDk" => Kirby::PlKbNrCpDk,
+            "Kirby::PlKbNrCpFc" => Kirby::PlKbNrCpFc,
+            "Kirby::PlKbNrCpGw" => Kirby::PlKbNrCpGw,
+            "Kirby::PlKbNrCpMt" => Kirby::PlKbNrCpMt,
+            "Kirby::PlKbNrCpPr" => Kirby::PlKbNrCpPr,
+            "Kirby::PlKbRe" => Kirby::PlKbRe,
+            "Kirby::PlKbReCpDk" => Kirby::PlKbReCpDk,
+            "Kirby::PlKbReCpFc" => Kirby::PlKbReCpFc,
+            "Kirby::PlKbReCpMt" => Kirby::PlKbReCpMt,
+            "Kirby::PlKbReCpPr" => Kirby::PlKbReCpPr,
+            "Kirby::PlKbWh" => Kirby::PlKbWh,
+            "Kirby::PlKbWhCpDk" => Kirby::PlKbWhCpDk,
+            "Kirby::PlKbWhCpFc" => Kirby::PlKbWhCpFc,
+            "Kirby::PlKbWhCpMt" => Kirby::PlKbWhCpMt,
+            "Kirby::PlKbWhCpPr" => Kirby::PlKbWhCpPr,
+            "Kirby::PlKbYe" => Kirby::PlKbYe,
+            "Kirby::PlKbYeCpDk" => Kirby::PlKbYeCpDk,
+            "Kirby::PlKbYeCpFc" => Kirby::PlKbYeCpFc,
+            "Kirby::PlKbYeCpMt" => Kirby::PlKbYeCpMt,
+            "Kirby::PlKbYeCpPr" => Kirby::PlKbYeCpPr,
+            "Roy::PlFe" => Roy::PlFe,
+            "Roy::PlFeBu" => Roy::PlFeBu,
+            "Roy::PlFeGr" => Roy::PlFeGr,
+            "Roy::PlFeNr" => Roy::PlFeNr,
+            "Roy::PlFeRe" => Roy::PlFeRe,
+            "Roy::PlFeYe" => Roy::PlFeYe,
+            "Bowser::PlKp" => Bowser::PlKp,
+            "Bowser::PlKpBk" => Bowser::PlKpBk,
+            "Bowser::PlKpBu" => Bowser::PlKpBu,
+            "Bowser::PlKpNr" => Bowser::PlKpNr,
+            "Bowser::PlKpRe" => Bowser::PlKpRe,
+            "Mewtwo::PlMt" => Mewtwo::PlMt,
+            "Mewtwo::PlMtBu" => Mewtwo::PlMtBu,
+            "Mewtwo::PlMtGr" => Mewtwo::PlMtGr,
+            "Mewtwo::PlMtNr" => Mewtwo::PlMtNr,
+            "Mewtwo::PlMtRe" => Mewtwo::PlMtRe,
+            "IceClimbersPopo::PlPp" => IceClimbersPopo::PlPp,
+            "IceClimbersPopo::PlPpGr" => IceClimbersPopo::PlPpGr,
+            "IceClimbersPopo::PlPpNr" => IceClimbersPopo::PlPpNr,
+            "IceClimbersPopo::PlPpOr" => IceClimbersPopo::PlPpOr,
+            "IceClimbersPopo::PlPpRe" => IceClimbersPopo::PlPpRe,
+            "Common::PlCo" => Common::PlCo,
+            "Link::PlLk" => Link::PlLk,
+            "Link::PlLkBk" => Link::PlLkBk,
+            "Link::PlLkBu" => Link::PlLkBu,
+            "Link::PlLkNr" => Link::PlLkNr,
+            "Link::PlLkRe" => Link::PlLkRe,
+            "Link::PlLkWh" => Link::PlLkWh,
+            _ => return None,
+        })
+    }
 }
 
 pub mod parse {
@@ -680,6 +906,16 @@ pub mod parse {
         u32::from_be_bytes(bytes)
     }
 
+    /// Read bytes 0x01 -> 0x04 as a u24 (filename string table offset).
+    pub fn node_name_offset(node: [u8; 0x0c]) -> u32 {
+        u32::from_be_bytes([0, node[1], node[2], node[3]])
+    }
+
+    /// Read bytes 0x08 -> 0x0c as u32 (file_length, or next_offset for directories).
+    pub fn node_file_size(node: [u8; 0x0c]) -> u32 {
+        root_node_num_entries(node)
+    }
+
     /// Read bytes 0x00 -> 0x01 as u8 (directory flag).
     pub fn node_is_directory(node: [u8; 0x0c]) -> bool {
         let (file_or_directory, _) = &node[0..1].split_at(std::mem::size_of::<u8>());
@@ -705,37 +941,86 @@ pub mod parse {
             .read_to_end(&mut fst)
             .expect("failed to read fst");
 
-        io::stdout().write(&fst)?;
+        io::stdout().write_all(&fst)?;
 
         Ok(())
     }
 }
 
+pub mod fst;
+pub mod progress;
+
 pub mod replace {
     //! Replace characters and stage assets within the game.
     //!
     //! This library only handles replacing DAT files currently.
-    use super::parse;
-    use gc_gcm::{FsNode, GcmFile};
+    use super::dat;
+    use super::disc::Disc;
+    use super::formats;
+    use super::fst::{self, Entry, Fst};
+    use super::progress::{self, Phase, Progress, Reporter, Silent};
+    use super::texture;
+    use super::verify::{self, Policy};
+    use gc_gcm::FsNode;
+    use serde::Serialize;
     use std::collections::HashMap;
     use std::fmt;
     use std::io::Cursor;
     use std::io::{self, Read, Seek, SeekFrom, Write};
     use std::path::{Path, PathBuf};
+    use tracing::{debug, info_span, trace, warn};
 
     /// A queued replacement to be executed later.
     #[derive(Debug, Clone)]
     pub struct Replacement {
         /// Which file to replace?
         pub target_file: &'static str,
-        /// Path to replacement data.
+        /// Path to replacement data, or for `.dat` files a folder of PNGs to
+        /// inject into the original file's textures (see [`Changes::replace`]).
         pub replacement: PathBuf,
     }
 
+    /// A set of filesystem edits, applied together when rebuilding the FST.
+    ///
+    /// Targets are either a file name (`"PlCaGr.dat"`) or a full FST path
+    /// (`"audio/1padv.ssm"`).
+    #[derive(Debug, Clone, Default)]
+    pub struct Changes {
+        /// Existing files to replace, with the path to the new data.
+        ///
+        /// For `.dat` files the path can also be a folder of PNGs, named by
+        /// texture offset, to inject into the original file's textures.
+        pub replace: Vec<(String, PathBuf)>,
+        /// New files to add (by full FST path), with the path to their data.
+        pub add: Vec<(String, PathBuf)>,
+        /// Existing files to remove.
+        pub remove: Vec<String>,
+        /// How to treat a base image that isn't vanilla v1.02 NTSC GALE01.
+        pub verify: Policy,
+        /// How to treat replaced or added `.dat` files that fail [`dat::validate`].
+        pub validate: Policy,
+        /// Rename the symbols of costumes replacing another slot of the same
+        /// character, e.g. a green Falcon going into `PlCaNr.dat`.
+        pub rename_costumes: bool,
+    }
+
+    impl From<&[Replacement]> for Changes {
+        fn from(replacements: &[Replacement]) -> Self {
+            Changes {
+                replace: replacements
+                    .iter()
+                    .map(|r| (r.target_file.to_string(), r.replacement.clone()))
+                    .collect(),
+                ..Changes::default()
+            }
+        }
+    }
+
     /// An update to execute against the GCM FST.
     #[derive(Clone)]
     pub struct UpdateFST {
         pub name: String,
+        pub action: FileAction,
         pub original_offset: u32,
         pub updated_offset: u32,
         pub original_size: u32,
@@ -763,7 +1048,14 @@ pub mod replace {
     /// Can be used to create a bootable ISO.
     pub struct RebuiltFST {
         pub new_fst: Vec<u8>,
+        /// Existing files, indexed by their original offset.
+        ///
+        /// Removed files are kept with an `updated_size` of zero and no data.
         pub replacements: HashMap<u32, UpdateFST>,
+        /// Files added to the filesystem, with an `original_size` of zero.
+        pub additions: Vec<UpdateFST>,
+        /// Problems that didn't stop the build, e.g. a non-vanilla base image.
+        pub warnings: Vec<String>,
     }
 
     /// Look up and read a file entry within an ISO, returning a no-op UpdateFST action.
@@ -773,27 +1065,48 @@ pub mod replace {
         match file {
             FsNode::File { size, offset, name } => {
                 let mut file = std::fs::File::open(iso)?;
-                let mut data = Vec::with_capacity(*size as usize);
-                file.seek(SeekFrom::Start(*offset as u64))?;
-                Read::by_ref(&mut file)
-                    // file.by_ref()
-                    .take(*size as u64)
-                    .read_to_end(&mut data)?;
-
-                Ok(UpdateFST {
-                    name: name.to_string(),
-                    updated_offset: *offset,
-                    original_offset: *offset,
-
-                    original_size: *size,
-                    updated_size: *size,
-                    data,
-                })
+                read_entry(&mut file, name, *offset, *size)
             }
             _ => panic!("failure"),
         }
     }
 
+    /// Read a single file's data from an open ISO, returning a no-op UpdateFST action.
+    fn read_entry<R: Read + Seek>(
+        iso: &mut R,
+        name: &str,
+        offset: u32,
+        size: u32,
+    ) -> io::Result<UpdateFST> {
+        let mut data = Vec::with_capacity(size as usize);
+        iso.seek(SeekFrom::Start(offset as u64))?;
+        iso.take(size as u64).read_to_end(&mut data)?;
+
+        Ok(UpdateFST {
+            name: name.to_string(),
+            action: FileAction::Keep,
+            updated_offset: offset,
+            original_offset: offset,
+
+            original_size: size,
+            updated_size: size,
+            data,
+        })
+    }
+
+    /// Read the raw filesystem table from an ISO, at the offset and size
+    /// recorded in its disc header (see [`fst::read`]).
+    ///
+    /// GcmFile#fst_bytes returns a truncated version, so read it directly.
+    pub fn read_fst<R: Read + Seek>(iso: &mut R) -> io::Result<Vec<u8>> {
+        fst::read(iso)
+    }
+
+    /// Round `size` up to the 4 byte alignment used between files.
+    fn aligned(size: u32) -> i64 {
+        (size as i64 + 3) & !3
+    }
+
     /// Given a set of potential replacements, attempt to rebuild the FST.
     ///
     /// ```text
@@ -825,189 +1138,554 @@ pub mod replace {
     ///
     /// - there are 0x4bc entries, each 0x0c long
     /// - string table offset starts at (0x04bc * 0x0c) = 0x38d0
-    pub fn rebuild_fst<P: AsRef<Path>>(path: P, replacements: &Vec<Replacement>) -> RebuiltFST {
-        let iso = GcmFile::open(&path).expect("could not open ISO");
+    ///
+    /// # Panics
+    ///
+    /// Panics if the image can't be read, or a replacement can't be read or
+    /// doesn't fit. Use [`rebuild_fst_with_changes`] to handle these errors.
+    pub fn rebuild_fst<P: AsRef<Path>>(path: P, replacements: &[Replacement]) -> RebuiltFST {
+        rebuild_fst_with_changes(path, &Changes::from(replacements)).expect("failed to rebuild fst")
+    }
 
-        // read entire filesystem table (0x456e00 offset, 0x7529 length)
-        // GcmFile#fst_bytes returns a truncated version
-        let mut file = std::fs::File::open(&path).expect("failed to open ISO");
-        let mut fst = Vec::new();
-        file.seek(SeekFrom::Start(0x456e00))
-            .expect("failed to seek to fst");
-        Read::by_ref(&mut file)
-            .take(0x07529)
-            .read_to_end(&mut fst)
-            .expect("failed to read fst");
+    /// What happens to a file in a [`BuildPlan`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum FileAction {
+        /// Original data, possibly at a new offset.
+        Keep,
+        Replace,
+        Add,
+        Remove,
+    }
 
-        let new_fst = fst.clone();
+    /// A single file in a [`BuildPlan`].
+    ///
+    /// Added files have an `original_size` of zero, and an `original_offset`
+    /// matching where they are first placed.
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+    pub struct PlannedFile {
+        /// Full FST path.
+        pub path: String,
+        pub action: FileAction,
+        pub original_offset: u32,
+        pub updated_offset: u32,
+        pub original_size: u32,
+        pub updated_size: u32,
+        /// Path to the new data, for replaced and added files.
+        pub data: Option<PathBuf>,
+        /// The costume a replacement was made for, when its symbols are renamed
+        /// to match this file (see [`Changes::rename_costumes`]).
+        pub renamed_from: Option<dat::Slot>,
+    }
 
-        let mut replacement_map: HashMap<u32, UpdateFST> = HashMap::new();
-        for file in &iso.filesystem.files {
-            match file {
-                // for each file, insert a mutable UpdateFST, indexed by offset
-                file @ FsNode::File { offset, .. } => replacement_map.insert(
-                    *offset,
-                    read_file(&path, &file).expect("failed to read file"),
-                ),
-                _ => continue,
-            };
+    impl PlannedFile {
+        /// Does this file end up somewhere else, or with different contents?
+        pub fn is_changed(&self) -> bool {
+            self.action != FileAction::Keep || self.original_offset != self.updated_offset
         }
+    }
 
-        for replacement in replacements {
-            eprintln!("{replacement:?}");
-            // first, locate the FST entry (within the target ISO) for the replacement
-            // we search through the replacement_map we built up earlier
-            let search = replacement_map.clone();
-            let mut found = search
-                .values()
-                .filter(|update: &&UpdateFST| update.name == replacement.target_file)
-                .collect::<Vec<_>>();
-
-            // we should find exactly one entry for each replacement
-            // if not, abort
-            let num_found = found.len();
-            if num_found != 1 {
-                panic!(
-                    "did not match character {:?}: {num_found} found",
-                    replacement.target_file
-                );
+    /// The layout of a build.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct BuildPlan {
+        /// Every file, in original FST order, followed by added files.
+        pub files: Vec<PlannedFile>,
+        /// Size of the rebuilt filesystem table.
+        pub fst_size: u32,
+        /// Total bytes of original file data moved to a new offset.
+        pub moved_bytes: u64,
+        /// Size of the image [`build_iso`] will produce.
+        pub image_size: u64,
+        /// Problems that didn't stop the build, e.g. a non-vanilla base image.
+        pub warnings: Vec<String>,
+        /// The rebuilt filesystem table.
+        #[serde(skip)]
+        pub fst: Fst,
+        /// New `.dat` data read while planning, by full FST path, so building
+        /// doesn't read (or inject textures into) it again.
+        #[serde(skip)]
+        contents: HashMap<String, Vec<u8>>,
+    }
+
+    /// Is this an HSD archive, judging by its name?
+    fn is_dat(path: &str) -> bool {
+        Path::new(path)
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("dat"))
+    }
+
+    /// Read a replacement for `target`. With `rename`, a costume made for
+    /// another slot of the same character has its symbols renamed to match.
+    ///
+    /// Returns the data and, if renamed, the costume it was made for.
+    fn read_replacement(
+        disc: &Disc,
+        target: &str,
+        data: &Path,
+        rename: bool,
+    ) -> io::Result<(Vec<u8>, Option<dat::Slot>)> {
+        let bytes = match data.is_dir() {
+            true => inject_textures(disc, target, data)?,
+            false => std::fs::read(data)?,
+        };
+        let Some(to) = dat::Slot::from_file_name(target).filter(|_| rename) else {
+            return Ok((bytes, None));
+        };
+        let Ok(mut archive) = dat::DatFile::parse(&bytes) else {
+            return Ok((bytes, None));
+        };
+        match archive.costume_slot() {
+            Some(from) if from.code == to.code && from != to => {
+                archive.rename_costume(&from, &to);
+                Ok((archive.to_bytes(), Some(from)))
             }
-            let matching: &UpdateFST = found.pop().expect("failed to match character");
-
-            // once we have the entry, we need the length of the new data
-            // we load this using the path in the replacement definition
-            let new_data: Vec<u8> =
-                std::fs::read(&replacement.replacement).expect("could not open");
-            let new_data_length = new_data.len();
-
-            // now we can see whether the new data is larger or smaller
-            let length_delta: i32 =
-                ((matching.original_size as i32) - (new_data_length as i32)).wrapping_abs();
-            let offset_adjustment = dbg!(length_delta + length_delta.rem_euclid(4));
-
-            // bump updated_offset by length_delta for FST entries following the original offset
-            for file in replacement_map.values_mut() {
-                // if we find two matching offsets, this is the replacement target
-                if file.original_offset == matching.original_offset {
-                    // bump the size to match the new data
-                    file.updated_size = new_data_length as u32;
-                    // update the data with the replacement
-                    file.data = new_data.clone();
-                }
+            _ => Ok((bytes, None)),
+        }
+    }
 
-                // for everything following this offset,
-                if offset_adjustment != 0 && file.original_offset > matching.original_offset {
-                    // bump the offset to reflect the updated data length
-                    file.updated_offset -= offset_adjustment as u32;
-                }
+    /// The original `target` file, with the PNGs in `dir` injected into its
+    /// textures.
+    fn inject_textures(disc: &Disc, target: &str, dir: &Path) -> io::Result<Vec<u8>> {
+        let mut archive = dat::DatFile::parse(&disc.read_file(disc.file(target)?)?)?;
+        let injected = texture::inject_dir(&mut archive, dir, &texture::Options::default())
+            .map_err(|error| {
+                io::Error::new(
+                    error.kind(),
+                    format!("{target} ({}): {error}", dir.display()),
+                )
+            })?;
+        debug!(
+            file = target,
+            textures = injected.len(),
+            "injected textures"
+        );
+        Ok(archive.to_bytes())
+    }
+
+    /// Validate a new `.dat` file for `path`, according to `policy`.
+    fn validate_dat(
+        path: &str,
+        data: &Path,
+        bytes: &[u8],
+        policy: Policy,
+        warnings: &mut Vec<String>,
+    ) -> io::Result<()> {
+        if policy == Policy::Skip || !is_dat(path) {
+            return Ok(());
+        }
+
+        for problem in dat::validate(bytes, path) {
+            let message = format!("{path} ({}): {problem}", data.display());
+            if policy == Policy::Refuse {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
             }
+            warn!("{message}");
+            warnings.push(message);
         }
+        Ok(())
+    }
 
-        // create cursor over filesystem table
-        let mut cursor = Cursor::new(new_fst);
+    /// Find the planned file for a target, by the index of its FST entry.
+    fn planned_file<'a>(
+        files: &'a mut [(usize, PlannedFile)],
+        table: &Fst,
+        target: &str,
+    ) -> io::Result<&'a mut PlannedFile> {
+        let index = table.find(target)?;
+        files
+            .iter_mut()
+            .find(|(entry, _)| *entry == index)
+            .map(|(_, file)| file)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no planned file for {target:?}"),
+                )
+            })
+    }
 
-        // read the root node
-        let mut root = [0; 0xc];
-        cursor.read(&mut root).expect("failed to read root node");
+    /// Plan a build, replacing, adding and removing files.
+    ///
+    /// Files following a changed file are moved by the difference in (4 byte
+    /// aligned) size, so gaps between the remaining files are kept. Added files
+    /// are placed after the last file on disc. If the new table no longer fits
+    /// before the first file, every file is moved back to make room.
+    ///
+    /// The base image is verified first, according to `changes.verify`, which
+    /// hashes the whole image by default the first time it is planned (see
+    /// [`verify::verify`]). New `.dat` files are read in full:
+    /// texture folders are injected into the original file, costumes are
+    /// renamed, and the result is validated according to `changes.validate`.
+    /// The plan keeps this data for [`rebuild_fst_with_changes`]. Other new
+    /// files only have their size read.
+    pub fn plan<P: AsRef<Path>>(path: P, changes: &Changes) -> io::Result<BuildPlan> {
+        let _span = info_span!("plan", iso = %path.as_ref().display()).entered();
+        let mut warnings: Vec<String> = verify::check(&path, changes.verify)?.into_iter().collect();
+        for warning in &warnings {
+            warn!("{warning}");
+        }
 
-        let num_entries = parse::root_node_num_entries(root);
+        let disc = Disc::open(&path)?;
+        let mut table = disc.fst.clone();
+        let mut contents = HashMap::new();
+
+        // one planned file for each file entry, with the index of its entry
+        let mut files: Vec<(usize, PlannedFile)> = table
+            .entries
+            .iter()
+            .zip(table.paths())
+            .enumerate()
+            .filter_map(|(index, (entry, path))| match entry {
+                Entry::File { offset, size, .. } => Some((
+                    index,
+                    PlannedFile {
+                        path,
+                        action: FileAction::Keep,
+                        original_offset: *offset,
+                        updated_offset: *offset,
+                        original_size: *size,
+                        updated_size: *size,
+                        data: None,
+                        renamed_from: None,
+                    },
+                )),
+                Entry::Directory { .. } => None,
+            })
+            .collect();
+
+        for (target, replacement) in &changes.replace {
+            let file = planned_file(&mut files, &table, target)?;
+            let size = match is_dat(&file.path) {
+                true => {
+                    let (bytes, renamed_from) =
+                        read_replacement(&disc, &file.path, replacement, changes.rename_costumes)?;
+                    if let Some(from) = &renamed_from {
+                        debug!(file = %file.path, from = %from.file_name(), "renaming costume symbols");
+                    }
+                    validate_dat(
+                        &file.path,
+                        replacement,
+                        &bytes,
+                        changes.validate,
+                        &mut warnings,
+                    )?;
+                    file.renamed_from = renamed_from;
+                    let size = bytes.len() as u32;
+                    contents.insert(file.path.clone(), bytes);
+                    size
+                }
+                false if replacement.is_dir() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{}: textures can only be injected into .dat files",
+                            file.path
+                        ),
+                    ));
+                }
+                false => std::fs::metadata(replacement)?.len() as u32,
+            };
+            debug!(
+                file = %file.path,
+                replacement = %replacement.display(),
+                original_size = file.original_size,
+                updated_size = size,
+                "replacing file"
+            );
+            file.action = FileAction::Replace;
+            file.updated_size = size;
+            file.data = Some(replacement.clone());
+        }
 
-        // skip root entry
-        for entry_index in 1..num_entries {
-            // seek to the correct offset
-            let seek: u64 = (entry_index * 0x0c) as u64;
-            cursor
-                .seek(SeekFrom::Start(seek))
-                .expect("failed to seek to fst entry: {seek}");
+        for target in &changes.remove {
+            let file = planned_file(&mut files, &table, target)?;
+            contents.remove(&file.path);
+            debug!(file = %file.path, original_size = file.original_size, "removing file");
+            file.action = FileAction::Remove;
+            file.updated_size = 0;
+            file.data = None;
+        }
 
-            // read node
-            let mut node = [0; 0xc];
-            cursor.read(&mut node).expect("failed to read node: {seek}");
+        // bump updated_offset by the size difference of every preceding file
+        let mut by_offset = files.iter_mut().collect::<Vec<_>>();
+        by_offset.sort_by_key(|(_, file)| file.original_offset);
+        let mut shift: i64 = 0;
+        let mut end: i64 = 0;
+        for (_, file) in by_offset {
+            file.updated_offset = (file.original_offset as i64 + shift) as u32;
+            shift += aligned(file.updated_size) - aligned(file.original_size);
+            end = end.max(file.updated_offset as i64 + aligned(file.updated_size));
+        }
 
-            // skip directories
-            if parse::node_is_directory(node) {
-                continue;
+        for (index, file) in &files {
+            if let Entry::File { offset, size, .. } = &mut table.entries[*index] {
+                *offset = file.updated_offset;
+                *size = file.updated_size;
             }
+        }
+
+        let mut removed = files
+            .iter()
+            .filter(|(_, file)| file.action == FileAction::Remove)
+            .map(|(index, _)| *index)
+            .collect::<Vec<_>>();
+        removed.sort_unstable_by(|a, b| b.cmp(a));
+        for index in removed {
+            table.remove_file(index)?;
+        }
 
-            let file_offset = parse::node_file_offset(node);
-
-            match replacement_map.get(&file_offset) {
-                Some(UpdateFST {
-                    name,
-                    updated_offset,
-                    updated_size,
-                    ..
-                }) => {
-                    let fst_index = seek + 4;
-                    eprintln!(
-                        "fst entry {fst_index:#0x}: {file_offset:#0x} -> {updated_offset:#0x} [{name}]"
-                    );
-
-                    // seek to file offset
-                    cursor
-                        .seek(SeekFrom::Start(seek + 4))
-                        .expect("failed to seek to file offset");
-                    cursor
-                        .write(&updated_offset.to_be_bytes())
-                        .expect("failed to write offset");
-
-                    // seek to size offset
-                    cursor
-                        .seek(SeekFrom::Start(seek + 8))
-                        .expect("failed to seek to file offset");
-                    cursor
-                        .write(&updated_size.to_be_bytes())
-                        .expect("failed to write size");
+        let mut files = files.into_iter().map(|(_, file)| file).collect::<Vec<_>>();
+        for (target, addition) in &changes.add {
+            let path = target.trim_start_matches('/').to_string();
+            let size = match is_dat(target) {
+                true => {
+                    let bytes = std::fs::read(addition)?;
+                    validate_dat(target, addition, &bytes, changes.validate, &mut warnings)?;
+                    let size = bytes.len() as u32;
+                    contents.insert(path.clone(), bytes);
+                    size
                 }
-                None => panic!("no replacement map entry found for node: {seek:#0x}"),
+                false => std::fs::metadata(addition)?.len() as u32,
             };
+            table.add_file(target, end as u32, size)?;
+            debug!(file = %target, offset = end, size, "adding file");
+            files.push(PlannedFile {
+                path,
+                action: FileAction::Add,
+                original_offset: end as u32,
+                updated_offset: end as u32,
+                original_size: 0,
+                updated_size: size,
+                data: Some(addition.clone()),
+                renamed_from: None,
+            });
+            end += aligned(size);
         }
 
-        RebuiltFST {
-            new_fst: cursor.get_ref().to_vec(),
-            replacements: replacement_map,
+        // make room for a larger table before the first file
+        let first_file = files
+            .iter()
+            .filter(|file| file.action != FileAction::Add)
+            .map(|file| file.original_offset as u64)
+            .filter(|offset| *offset >= fst::FST_OFFSET)
+            .min()
+            .unwrap_or(fst::FST_OFFSET + fst::FST_LENGTH);
+        let room = first_file - fst::FST_OFFSET;
+        let fst_size = table.to_bytes().len() as u64;
+        if fst_size > room {
+            let growth = ((fst_size - room + 3) & !3) as u32;
+            debug!(fst_size, room, growth, "moving files to make room for fst");
+            files
+                .iter_mut()
+                .for_each(|file| file.updated_offset += growth);
+            for entry in &mut table.entries {
+                if let Entry::File { offset, .. } = entry {
+                    *offset += growth;
+                }
+            }
         }
+
+        for file in files.iter().filter(|file| file.is_changed()) {
+            trace!(
+                file = %file.path,
+                action = ?file.action,
+                original_offset = file.original_offset,
+                updated_offset = file.updated_offset,
+                original_size = file.original_size,
+                updated_size = file.updated_size,
+                "planned file"
+            );
+        }
+
+        // build_iso pads the end of the image
+        let end = files
+            .iter()
+            .map(|file| file.updated_offset as u64 + file.updated_size as u64)
+            .fold(fst::FST_OFFSET + fst_size, u64::max);
+        let image_size = end + end.rem_euclid(0x20) + 0x20;
+        let moved_bytes = files
+            .iter()
+            .filter(|file| file.action == FileAction::Keep && file.is_changed())
+            .map(|file| file.original_size as u64)
+            .sum();
+        debug!(fst_size, moved_bytes, image_size, "planned build");
+
+        Ok(BuildPlan {
+            moved_bytes,
+            image_size,
+            fst_size: fst_size as u32,
+            files,
+            warnings,
+            fst: table,
+            contents,
+        })
+    }
+
+    /// Rebuild the FST, replacing, adding and removing files.
+    ///
+    /// See [`plan`] for how files are laid out. Every file is read into memory,
+    /// reusing the `.dat` data already read while planning.
+    pub fn rebuild_fst_with_changes<P: AsRef<Path>>(
+        path: P,
+        changes: &Changes,
+    ) -> io::Result<RebuiltFST> {
+        let mut plan = plan(&path, changes)?;
+        let _span = info_span!("rebuild_fst", iso = %path.as_ref().display()).entered();
+        let disc = Disc::open(&path)?;
+
+        let mut replacement_map: HashMap<u32, UpdateFST> = HashMap::new();
+        let mut additions = Vec::new();
+        for file in plan.files {
+            let data = match (file.action, &file.data) {
+                (FileAction::Keep, _) => {
+                    let mut data = vec![0; file.original_size as usize];
+                    disc.read_at(file.original_offset as u64, &mut data)?;
+                    data
+                }
+                (FileAction::Remove, _) => Vec::new(),
+                (_, Some(path)) => match plan.contents.remove(&file.path) {
+                    Some(data) => data,
+                    None => std::fs::read(path)?,
+                },
+                (_, None) => unreachable!("replaced and added files have data"),
+            };
+            trace!(file = %file.path, action = ?file.action, size = data.len(), "read file");
+            if data.len() as u32 != file.updated_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} changed size while building", file.path),
+                ));
+            }
+
+            let update = UpdateFST {
+                name: file.path.rsplit('/').next().unwrap_or_default().to_string(),
+                action: file.action,
+                original_offset: file.original_offset,
+                updated_offset: file.updated_offset,
+                original_size: file.original_size,
+                updated_size: file.updated_size,
+                data,
+            };
+            match file.action {
+                FileAction::Add => additions.push(update),
+                _ => {
+                    replacement_map.insert(update.original_offset, update);
+                }
+            }
+        }
+
+        Ok(RebuiltFST {
+            new_fst: plan.fst.to_bytes(),
+            replacements: replacement_map,
+            additions,
+            warnings: plan.warnings,
+        })
     }
 
     /// Rebuild an ISO, given an updated filesystem table.
     ///
     /// The filesystem table has already been replaced with new data,
     /// so this function just writes a new disc image.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the image can't be read. Use [`build_iso_with_progress`] to
+    /// handle this error.
     pub fn build_iso<P: AsRef<Path>>(path: P, fst: &RebuiltFST) -> Vec<u8> {
-        let mut new_iso = Vec::with_capacity(0x456e00);
+        build_iso_with_progress(path, fst, &mut Silent).expect("failed to build iso")
+    }
 
-        let mut melee = std::fs::File::open(&path).expect("failed to open ISO");
-        Read::by_ref(&mut melee)
-            .take(0x456e00)
-            .read_to_end(&mut new_iso)
-            .expect("failed to read melee up to FST");
+    /// Rebuild an ISO like [`build_iso`], reporting progress after each file.
+    pub fn build_iso_with_progress<P: AsRef<Path>>(
+        path: P,
+        fst: &RebuiltFST,
+        reporter: &mut dyn Reporter,
+    ) -> io::Result<Vec<u8>> {
+        let _span = info_span!("build_iso", iso = %path.as_ref().display()).entered();
+        let mut new_iso = vec![0; fst::FST_OFFSET as usize];
+        formats::open(&path)?.read_at(0, &mut new_iso)?;
+
+        // the output no longer matches what NKit recorded, so don't claim to be NKit
+        let marker = formats::NKIT_OFFSET as usize;
+        if new_iso[marker..marker + 4] == formats::NKIT_MAGIC[..] {
+            debug!("clearing nkit marker");
+            new_iso[marker..marker + 4].fill(0);
+        }
+
+        // the boot header records the FST offset (0x424), size (0x428) and
+        // maximum size (0x42c), which may differ from the base image's
+        debug!(
+            fst_offset = fst::FST_OFFSET,
+            fst_size = fst.new_fst.len(),
+            "updating fst location in boot header"
+        );
+        let header = fst::HEADER_OFFSET as usize;
+        let size = (fst.new_fst.len() as u32).to_be_bytes();
+        new_iso[header..header + 4].copy_from_slice(&(fst::FST_OFFSET as u32).to_be_bytes());
+        new_iso[header + 4..header + 8].copy_from_slice(&size);
+        new_iso[header + 8..header + 12].copy_from_slice(&size);
 
         new_iso.extend(&fst.new_fst);
 
         let mut cursor = Cursor::new(new_iso);
 
-        let mut updates = fst.replacements.values().collect::<Vec<_>>();
-        updates.sort_by(|a, b| a.updated_offset.cmp(&b.updated_offset));
+        let mut updates = fst
+            .replacements
+            .values()
+            .chain(fst.additions.iter())
+            .collect::<Vec<_>>();
+        updates.sort_by_key(|update| update.updated_offset);
+
+        let mut written = cursor.get_ref().len() as u64;
+        let total = written
+            + updates
+                .iter()
+                .map(|update| update.data.len() as u64)
+                .sum::<u64>();
+        let mut progress = Progress {
+            phase: Phase::Build,
+            bytes: written,
+            total,
+            file: None,
+        };
+        progress::report(reporter, progress)?;
 
         for update in updates {
-            println!("{update:?}");
-            cursor
-                .seek(SeekFrom::Start(update.updated_offset as u64))
-                .expect("failed to seek");
-            cursor
-                .write(&update.data)
-                .expect("failed to write {update:?}");
+            trace!(
+                file = %update.name,
+                action = ?update.action,
+                original_offset = update.original_offset,
+                updated_offset = update.updated_offset,
+                original_size = update.original_size,
+                updated_size = update.updated_size,
+                "writing file"
+            );
+            cursor.seek(SeekFrom::Start(update.updated_offset as u64))?;
+            cursor.write_all(&update.data)?;
+
+            written += update.data.len() as u64;
+            progress.bytes = written;
+            progress.file = Some(&update.name);
+            progress::report(reporter, progress)?;
         }
 
-        let end_position = cursor
-            .seek(SeekFrom::End(0))
-            .expect("failed to seek to end");
+        let end_position = cursor.seek(SeekFrom::End(0))?;
 
-        let padding = std::iter::repeat(0).take(end_position.rem_euclid(0x20) as usize + 0x20);
+        let padding = vec![0; end_position.rem_euclid(0x20) as usize + 0x20];
 
-        cursor
-            .write(&padding.collect::<Vec<_>>())
-            .expect("failed to write extra padding");
-        cursor.get_mut().to_vec()
+        cursor.write_all(&padding)?;
+        debug!(size = cursor.get_ref().len(), "built image");
+        Ok(cursor.into_inner())
     }
 }
+
+pub mod dat;
+pub mod diagram;
+pub mod diff;
+pub mod disc;
+pub mod formats;
+pub mod manifest;
+pub mod patch;
+pub mod texture;
+pub mod vanilla;
+pub mod verify;
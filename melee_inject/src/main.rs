@@ -0,0 +1,74 @@
+use melee_inject::{dat, diff, patch, texture};
+use std::io;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage:
+    melee_inject diff <old.iso|old-fst.bin> <new.iso|new-fst.bin> [--json]
+    melee_inject apply-patch <vanilla.iso> <patch.bps|patch.xdelta> <out.iso>
+    melee_inject convert-costume <in.dat> <from-slot> <to-slot> <out.dat>
+    melee_inject export-textures <in.dat> <dir>
+    melee_inject import-textures <in.dat> <dir> <out.dat>";
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let result = match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["diff", old, new] => run_diff(old, new, false),
+        ["diff", old, new, "--json"] => run_diff(old, new, true),
+        ["apply-patch", source, patch, output] => patch::apply(source, patch, output),
+        ["convert-costume", input, from, to, output] => run_convert(input, from, to, output),
+        ["export-textures", input, dir] => run_export(input, dir),
+        ["import-textures", input, dir, output] => run_import(input, dir, output),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Compare two images or FSTs, printing a table or JSON.
+fn run_diff(old: &str, new: &str, json: bool) -> io::Result<()> {
+    let diffs = diff::diff_paths(old, new)?;
+    if json {
+        println!("{}", diff::to_json(&diffs));
+    } else {
+        print!("{}", diff::to_table(&diffs));
+    }
+    Ok(())
+}
+
+/// Convert a costume to another slot, e.g. `PlFxOr.dat` to `PlFxNr.dat`.
+fn run_convert(input: &str, from: &str, to: &str, output: &str) -> io::Result<()> {
+    let converted = dat::convert_costume(&std::fs::read(input)?, from, to)?;
+    std::fs::write(output, converted)
+}
+
+/// Export every texture in a DAT as PNGs.
+fn run_export(input: &str, dir: &str) -> io::Result<()> {
+    let archive = dat::DatFile::parse(&std::fs::read(input)?)?;
+    for path in texture::export(&archive, dir)? {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+/// Inject a folder of PNGs, named by texture offset, into a DAT's textures.
+fn run_import(input: &str, dir: &str, output: &str) -> io::Result<()> {
+    let mut archive = dat::DatFile::parse(&std::fs::read(input)?)?;
+    let injected = texture::inject_dir(&mut archive, dir, &texture::Options::default())?;
+    std::fs::write(output, archive.to_bytes())?;
+    println!("injected {} textures", injected.len());
+    Ok(())
+}
@@ -0,0 +1,376 @@
+//! Declarative build manifests.
+//!
+//! A manifest lists everything needed to produce a modded disc, so builds can
+//! be authored without writing Rust. Relative paths are resolved against the
+//! directory containing the manifest.
+//!
+//! ```toml
+//! # files to remove from the filesystem
+//! remove = ["MvEndCa.mth"]
+//! # refuse to build with malformed or misplaced .dat files
+//! validate = "refuse"
+//!
+//! [base]
+//! iso = "ssbm.iso"
+//! # refuse to build from anything but vanilla v1.02 NTSC GALE01
+//! verify = "refuse"
+//!
+//! [output]
+//! iso = "build/potemkin-melee.iso"
+//! fst = "build/potemkin-fst.bin"
+//! bps = "build/potemkin.bps"
+//!
+//! [header]
+//! game_id = "GALE01"
+//! internal_name = "Super Smash Bros Melee (Potemkin)"
+//!
+//! # targets are typed names, file names or full FST paths
+//! [[replace]]
+//! target = "CaptainFalcon::PlCaGr"
+//! file = "falcon/POTEMKIN FALCON.dat"
+//!
+//! [[add]]
+//! path = "audio/potemkin.hps"
+//! file = "falcon/potemkin.hps"
+//!
+//! # patch by RAM address (translated through the DOL section table) or DOL file offset
+//! [[dol_patch]]
+//! address = 0x801a4de4
+//! bytes = "60000000"
+//! ```
+use super::characters;
+use super::formats;
+use super::patch;
+use super::progress::{Reporter, Silent};
+use super::replace::{self, Changes};
+use super::verify::{self, Policy};
+use serde::Deserialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::{debug, info_span};
+
+/// Length of the internal game name field in the disc header (0x20 -> 0x400).
+const INTERNAL_NAME_LENGTH: usize = 0x3e0;
+
+/// A batch build, loaded from a TOML manifest.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// The vanilla disc image to build from.
+    pub base: Base,
+    /// Where to write the build.
+    pub output: Output,
+    /// Edits to the disc header.
+    #[serde(default)]
+    pub header: Header,
+    /// Files to replace.
+    #[serde(default)]
+    pub replace: Vec<ReplaceFile>,
+    /// Files to add.
+    #[serde(default)]
+    pub add: Vec<AddFile>,
+    /// Files to remove, by file name or full FST path.
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// Patches to the main executable.
+    #[serde(default, rename = "dol_patch")]
+    pub dol_patches: Vec<DolPatch>,
+    /// How to treat `.dat` files that don't look like they belong where
+    /// they're going (see [`super::dat::validate`]).
+    #[serde(default)]
+    pub validate: Policy,
+    /// Rename the symbols of costumes made for another slot of the same
+    /// character, instead of reporting them.
+    #[serde(default)]
+    pub rename_costumes: bool,
+    /// Directory relative paths are resolved against.
+    #[serde(skip)]
+    pub root: PathBuf,
+}
+
+/// The disc image to build from.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Base {
+    pub iso: PathBuf,
+    /// Expected SHA-1 of the image, as hex. Checked before building.
+    pub sha1: Option<String>,
+    /// How to treat an image that isn't vanilla v1.02 NTSC GALE01:
+    /// `"refuse"`, `"warn"` (the default) or `"skip"`.
+    ///
+    /// Ignored when `sha1` is set.
+    #[serde(default)]
+    pub verify: Policy,
+}
+
+/// Output paths for the build.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Output {
+    /// The built image. Written as CISO or GCZ for `.ciso` or `.gcz` paths.
+    pub iso: PathBuf,
+    /// Also write the rebuilt filesystem table here.
+    pub fst: Option<PathBuf>,
+    /// Also write a BPS patch against the base image here.
+    pub bps: Option<PathBuf>,
+    /// Pad the image to the size of a full disc.
+    #[serde(default)]
+    pub pad: bool,
+}
+
+/// Edits to the disc header.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Header {
+    /// Six character game ID, e.g. `GALE01`.
+    pub game_id: Option<String>,
+    /// Internal game name, shown by loaders such as Dolphin.
+    pub internal_name: Option<String>,
+}
+
+/// A file to replace.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplaceFile {
+    /// Typed name (`CaptainFalcon::PlCaGr`), file name or full FST path.
+    pub target: String,
+    /// Path to the replacement data, or a folder of PNGs to inject into a
+    /// `.dat` file's textures.
+    pub file: PathBuf,
+}
+
+/// A file to add.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AddFile {
+    /// Full FST path of the new file.
+    pub path: String,
+    /// Path to the file data.
+    pub file: PathBuf,
+}
+
+/// Bytes to write into the main executable.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DolPatch {
+    /// RAM address to patch, e.g. `0x801a4de4`.
+    pub address: Option<u32>,
+    /// Offset within the DOL file to patch.
+    pub offset: Option<u32>,
+    /// Bytes to write, as hex.
+    pub bytes: String,
+}
+
+fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+fn read_u32(image: &[u8], offset: usize) -> io::Result<u32> {
+    image
+        .get(offset..offset + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().expect("4 bytes")))
+        .ok_or_else(|| invalid(format!("offset {offset:#0x} out of bounds")))
+}
+
+impl FromStr for Manifest {
+    type Err = io::Error;
+
+    /// Parse a manifest, resolving relative paths against the working directory.
+    fn from_str(manifest: &str) -> io::Result<Self> {
+        let mut manifest: Manifest = toml::from_str(manifest).map_err(invalid)?;
+        manifest.root = PathBuf::from(".");
+        Ok(manifest)
+    }
+}
+
+impl DolPatch {
+    /// Decode the hex patch bytes.
+    pub fn data(&self) -> io::Result<Vec<u8>> {
+        let hex = self
+            .bytes
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+        if hex.len() % 2 != 0 {
+            return Err(invalid(format!("odd length dol patch: {:?}", self.bytes)));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(invalid))
+            .collect()
+    }
+
+    /// Offset within the DOL file this patch starts at.
+    ///
+    /// Addresses are translated using the section table of the DOL at `dol`.
+    pub fn dol_offset(&self, dol: &[u8]) -> io::Result<u32> {
+        match (self.address, self.offset) {
+            (None, Some(offset)) => Ok(offset),
+            (Some(address), None) => {
+                // 7 text and 11 data sections: offsets, then addresses, then lengths
+                for section in 0..18 {
+                    let offset = read_u32(dol, section * 4)?;
+                    let start = read_u32(dol, 0x48 + section * 4)?;
+                    let length = read_u32(dol, 0x90 + section * 4)?;
+                    if length != 0 && (start..start + length).contains(&address) {
+                        return Ok(offset + (address - start));
+                    }
+                }
+                Err(invalid(format!(
+                    "address {address:#0x} not in any dol section"
+                )))
+            }
+            _ => Err(invalid("dol patch needs exactly one of address or offset")),
+        }
+    }
+}
+
+impl Manifest {
+    /// Load a manifest from disk.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Manifest> {
+        let mut manifest: Manifest = std::fs::read_to_string(&path)?.parse()?;
+        manifest.root = path
+            .as_ref()
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        Ok(manifest)
+    }
+
+    /// Resolve a path relative to the manifest.
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.root.join(path)
+    }
+
+    /// Filesystem edits described by the manifest, with paths resolved.
+    pub fn changes(&self) -> Changes {
+        Changes {
+            replace: self
+                .replace
+                .iter()
+                .map(|r| {
+                    let target = characters::lookup(&r.target).unwrap_or(&r.target);
+                    (target.to_string(), self.resolve(&r.file))
+                })
+                .collect(),
+            add: self
+                .add
+                .iter()
+                .map(|a| (a.path.clone(), self.resolve(&a.file)))
+                .collect(),
+            remove: self.remove.clone(),
+            verify: match self.base.sha1 {
+                Some(_) => Policy::Skip,
+                None => self.base.verify,
+            },
+            validate: self.validate,
+            rename_costumes: self.rename_costumes,
+        }
+    }
+
+    /// Apply header edits to a disc image.
+    pub fn patch_header(&self, image: &mut [u8]) -> io::Result<()> {
+        if let Some(game_id) = &self.header.game_id {
+            if game_id.len() != 6 || !game_id.is_ascii() {
+                return Err(invalid(format!(
+                    "game id must be 6 characters: {game_id:?}"
+                )));
+            }
+            image
+                .get_mut(0..6)
+                .ok_or_else(|| invalid("image too short for a game id"))?
+                .copy_from_slice(game_id.as_bytes());
+        }
+
+        if let Some(internal_name) = &self.header.internal_name {
+            if internal_name.len() >= INTERNAL_NAME_LENGTH {
+                return Err(invalid(format!(
+                    "internal name too long: {internal_name:?}"
+                )));
+            }
+            let field = image
+                .get_mut(0x20..0x20 + INTERNAL_NAME_LENGTH)
+                .ok_or_else(|| invalid("image too short for an internal name"))?;
+            field.fill(0);
+            field[..internal_name.len()].copy_from_slice(internal_name.as_bytes());
+        }
+
+        Ok(())
+    }
+
+    /// Apply DOL patches to a disc image.
+    pub fn patch_dol(&self, image: &mut [u8]) -> io::Result<()> {
+        let dol_offset = read_u32(image, 0x420)? as usize;
+
+        for patch in &self.dol_patches {
+            let data = patch.data()?;
+            let dol = image
+                .get(dol_offset..)
+                .ok_or_else(|| invalid(format!("dol offset {dol_offset:#0x} out of bounds")))?;
+            let start = dol_offset + patch.dol_offset(dol)? as usize;
+            image
+                .get_mut(start..start + data.len())
+                .ok_or_else(|| invalid(format!("dol patch out of bounds: {patch:?}")))?
+                .copy_from_slice(&data);
+        }
+
+        Ok(())
+    }
+
+    /// Build the disc image described by the manifest, and write it to the output paths.
+    pub fn apply(&self) -> io::Result<()> {
+        self.apply_with_progress(&mut Silent)
+    }
+
+    /// Build and write the disc image like [`Manifest::apply`], reporting
+    /// progress while the image is built.
+    pub fn apply_with_progress(&self, reporter: &mut dyn Reporter) -> io::Result<()> {
+        let base = self.resolve(&self.base.iso);
+        let _span = info_span!("apply_manifest", base = %base.display()).entered();
+
+        if let Some(expected) = &self.base.sha1 {
+            let actual = verify::hash_image(&base)?.sha1;
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(invalid(format!(
+                    "base iso sha1 mismatch: expected {expected}, found {actual}"
+                )));
+            }
+        }
+
+        let output = self.resolve(&self.output.iso);
+        let format = formats::Format::from_path(&output);
+        if !format.is_writable() {
+            return Err(invalid(format!("can't write {format:?} images")));
+        }
+
+        let rebuilt = replace::rebuild_fst_with_changes(&base, &self.changes())?;
+        let mut image = replace::build_iso_with_progress(&base, &rebuilt, reporter)?;
+        self.patch_header(&mut image)?;
+        self.patch_dol(&mut image)?;
+        if self.output.pad {
+            formats::pad_to_disc_size(&mut image)?;
+        }
+
+        if let Some(parent) = output.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        debug!(iso = %output.display(), size = image.len(), ?format, "writing image");
+        let out = io::BufWriter::new(std::fs::File::create(&output)?);
+        formats::write(&image, format, out)?;
+
+        if let Some(fst) = &self.output.fst {
+            let fst = self.resolve(fst);
+            debug!(fst = %fst.display(), size = rebuilt.new_fst.len(), "writing fst");
+            std::fs::write(fst, &rebuilt.new_fst)?;
+        }
+
+        if let Some(bps) = &self.output.bps {
+            debug!(bps = %self.resolve(bps).display(), "writing bps patch");
+            let out = io::BufWriter::new(std::fs::File::create(self.resolve(bps))?);
+            patch::create_bps(&base, &image, &rebuilt, out)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,905 @@
+//! Distributable BPS patches, and applying BPS and VCDIFF (xdelta) patches.
+//!
+//! Disc images can't be shared, so builds are shared as patches against a
+//! vanilla image instead. Patches use the [BPS] format (as used by Floating
+//! IPS and beat).
+//!
+//! Rather than diffing two whole images, the target is split into the regions
+//! known from a [`RebuiltFST`] (header, FST, and each file at its new offset),
+//! and each region is only compared against where its data came from.
+//!
+//! Patches can be applied to a vanilla image with [`apply`], to install
+//! community builds distributed as BPS or as [VCDIFF] (the format written by
+//! xdelta3). VCDIFF patches using xdelta3's secondary compression (`-S djw`,
+//! `-S lzma` and so on) or a custom code table aren't supported.
+//!
+//! [BPS]: <https://www.romhacking.net/documents/746/>
+//! [VCDIFF]: <https://www.rfc-editor.org/rfc/rfc3284>
+use super::disc::{Backend, Reader};
+use super::formats;
+use super::fst;
+use super::progress::{self, Phase, Progress, Reporter, Silent};
+use super::replace::RebuiltFST;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use tracing::{debug, info_span};
+
+/// Magic bytes at the start of every BPS patch.
+pub const BPS_MAGIC: &[u8; 4] = b"BPS1";
+/// Magic bytes at the start of every VCDIFF patch: `VCD` with the high bits
+/// set, then version 0.
+pub const VCDIFF_MAGIC: &[u8; 4] = &[0xd6, 0xc3, 0xc4, 0x00];
+
+/// Granularity of region comparisons.
+const CHUNK_SIZE: usize = 0x1000;
+
+/// BPS actions, stored in the low two bits of each command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    SourceRead = 0,
+    TargetRead = 1,
+    SourceCopy = 2,
+    TargetCopy = 3,
+}
+
+/// Writer that keeps a running CRC32 of everything written.
+struct Crc32Writer<W> {
+    inner: W,
+    crc32: crc32fast::Hasher,
+}
+
+impl<W: Write> Write for Crc32Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.crc32.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Write a BPS variable length number.
+fn write_number<W: Write>(out: &mut W, mut number: u64) -> io::Result<()> {
+    loop {
+        let low = (number & 0x7f) as u8;
+        number >>= 7;
+        if number == 0 {
+            return out.write_all(&[0x80 | low]);
+        }
+        out.write_all(&[low])?;
+        number -= 1;
+    }
+}
+
+/// Read a BPS variable length number.
+fn read_number<R: Read>(input: &mut R) -> io::Result<u64> {
+    let (mut number, mut shift) = (0u64, 1u64);
+    loop {
+        let mut byte = [0];
+        input.read_exact(&mut byte)?;
+        number = (byte[0] as u64 & 0x7f)
+            .checked_mul(shift)
+            .and_then(|value| number.checked_add(value))
+            .ok_or_else(|| invalid("bps number overflow"))?;
+        if byte[0] & 0x80 != 0 {
+            return Ok(number);
+        }
+        shift = shift
+            .checked_shl(7)
+            .ok_or_else(|| invalid("bps number overflow"))?;
+        number += shift;
+    }
+}
+
+/// Read a BPS signed relative offset.
+fn read_relative<R: Read>(input: &mut R) -> io::Result<i64> {
+    let number = read_number(input)?;
+    let magnitude = (number >> 1) as i64;
+    Ok(if number & 1 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    })
+}
+
+fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Accumulates actions, merging runs, and writes them to the patch.
+struct Encoder<'a, W> {
+    out: Crc32Writer<W>,
+    target: &'a [u8],
+    /// Pending action: kind, target start, length, source start.
+    pending: Option<(Action, u64, u64, u64)>,
+    source_relative: u64,
+}
+
+impl<W: Write> Encoder<'_, W> {
+    /// Queue an action producing `length` target bytes from `target_start`.
+    fn push(
+        &mut self,
+        action: Action,
+        target_start: u64,
+        length: u64,
+        source: u64,
+    ) -> io::Result<()> {
+        if let Some((kind, start, pending_length, pending_source)) = &mut self.pending {
+            let contiguous = *start + *pending_length == target_start
+                && (action == Action::TargetRead || *pending_source + *pending_length == source);
+            if *kind == action && contiguous {
+                *pending_length += length;
+                return Ok(());
+            }
+        }
+
+        self.flush()?;
+        self.pending = Some((action, target_start, length, source));
+        Ok(())
+    }
+
+    /// Write out the pending action.
+    fn flush(&mut self) -> io::Result<()> {
+        let Some((action, start, length, source)) = self.pending.take() else {
+            return Ok(());
+        };
+
+        write_number(&mut self.out, ((length - 1) << 2) | action as u64)?;
+        match action {
+            Action::SourceRead => {}
+            Action::TargetRead => self
+                .out
+                .write_all(&self.target[start as usize..(start + length) as usize])?,
+            Action::TargetCopy => unreachable!("target copies are not emitted"),
+            Action::SourceCopy => {
+                let relative = source as i64 - self.source_relative as i64;
+                write_number(
+                    &mut self.out,
+                    (relative.unsigned_abs() << 1) | (relative < 0) as u64,
+                )?;
+                self.source_relative = source + length;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A region of the target image, and where it is expected to come from in the source.
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    target: u64,
+    length: u64,
+    source: u64,
+}
+
+/// Split the target image into regions, using the rebuilt FST.
+fn regions(rebuilt: &RebuiltFST, target_length: u64) -> Vec<Region> {
+    let mut known = vec![Region {
+        target: 0,
+        length: fst::FST_OFFSET,
+        source: 0,
+    }];
+    known.extend(
+        rebuilt
+            .replacements
+            .values()
+            .chain(rebuilt.additions.iter())
+            .filter(|update| update.updated_size > 0)
+            .map(|update| Region {
+                target: update.updated_offset as u64,
+                length: update.updated_size as u64,
+                source: update.original_offset as u64,
+            }),
+    );
+    known.sort_by_key(|region| region.target);
+
+    // fill gaps (the FST, alignment, and trailing padding) in place
+    let mut regions = Vec::with_capacity(known.len() * 2);
+    let mut position = 0;
+    for region in known {
+        if region.target > position {
+            regions.push(Region {
+                target: position,
+                length: region.target - position,
+                source: position,
+            });
+        }
+        regions.push(region);
+        position = position.max(region.target + region.length);
+    }
+    if target_length > position {
+        regions.push(Region {
+            target: position,
+            length: target_length - position,
+            source: position,
+        });
+    }
+
+    regions
+}
+
+/// CRC32 of everything in a reader.
+fn crc32_reader<R: Read>(mut reader: R) -> io::Result<u32> {
+    let mut crc32 = crc32fast::Hasher::new();
+    let mut buffer = vec![0; 0x100000];
+    loop {
+        match reader.read(&mut buffer)? {
+            0 => return Ok(crc32.finalize()),
+            read => crc32.update(&buffer[..read]),
+        }
+    }
+}
+
+/// Write a BPS patch turning the image at `source` into `target`.
+///
+/// `target` is the output of [`super::replace::build_iso`] for `rebuilt`
+/// (optionally with header or DOL edits applied afterwards).
+pub fn create_bps<P: AsRef<Path>, W: Write>(
+    source: P,
+    target: &[u8],
+    rebuilt: &RebuiltFST,
+    out: W,
+) -> io::Result<()> {
+    let _span = info_span!("create_bps", source = %source.as_ref().display()).entered();
+    let source_image = formats::open(&source)?;
+    let source_length = source_image.size()?;
+    let source_crc32 = crc32_reader(Reader::new(&*source_image)?)?;
+
+    let mut encoder = Encoder {
+        out: Crc32Writer {
+            inner: out,
+            crc32: crc32fast::Hasher::new(),
+        },
+        target,
+        pending: None,
+        source_relative: 0,
+    };
+
+    encoder.out.write_all(BPS_MAGIC)?;
+    write_number(&mut encoder.out, source_length)?;
+    write_number(&mut encoder.out, target.len() as u64)?;
+    write_number(&mut encoder.out, 0)?;
+
+    let mut source_chunk = vec![0; CHUNK_SIZE];
+    for region in regions(rebuilt, target.len() as u64) {
+        let mut offset = 0;
+        while offset < region.length {
+            let length = (region.length - offset).min(CHUNK_SIZE as u64);
+            let target_start = region.target + offset;
+            let source_start = region.source + offset;
+            let target_chunk = &target[target_start as usize..(target_start + length) as usize];
+
+            let matches = source_start + length <= source_length && {
+                let source_chunk = &mut source_chunk[..length as usize];
+                source_image.read_at(source_start, source_chunk)?;
+                source_chunk == target_chunk
+            };
+
+            let action = match (matches, source_start == target_start) {
+                (false, _) => Action::TargetRead,
+                (true, true) => Action::SourceRead,
+                (true, false) => Action::SourceCopy,
+            };
+            encoder.push(action, target_start, length, source_start)?;
+            offset += length;
+        }
+    }
+    encoder.flush()?;
+    debug!(
+        source_length,
+        target_length = target.len(),
+        "encoded bps actions"
+    );
+
+    let mut out = encoder.out;
+    out.write_all(&source_crc32.to_le_bytes())?;
+    out.write_all(&crc32fast::hash(target).to_le_bytes())?;
+    let patch_crc32 = out.crc32.clone().finalize();
+    out.inner.write_all(&patch_crc32.to_le_bytes())?;
+    out.inner.flush()
+}
+
+/// Apply a BPS patch to the image at `source`, writing the result to `target`.
+///
+/// The patch checksum and the source size and checksum are validated before
+/// anything is written. The target is written as it is decoded; if its
+/// checksum doesn't match the patch, it is removed and an error returned.
+pub fn apply_bps<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
+    source: P,
+    patch: Q,
+    target: R,
+) -> io::Result<()> {
+    apply_bps_with_progress(source, patch, target, &mut Silent)
+}
+
+/// Apply a BPS patch like [`apply_bps`], reporting progress after each action.
+///
+/// A cancelled target is removed, like one that fails its checksum.
+pub fn apply_bps_with_progress<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
+    source: P,
+    patch: Q,
+    target: R,
+    reporter: &mut dyn Reporter,
+) -> io::Result<()> {
+    let _span = info_span!(
+        "apply_bps",
+        source = %source.as_ref().display(),
+        target = %target.as_ref().display()
+    )
+    .entered();
+    let patch = std::fs::read(patch)?;
+    if patch.len() < BPS_MAGIC.len() + 12 || &patch[..4] != BPS_MAGIC {
+        return Err(invalid("not a bps patch"));
+    }
+
+    let footer = |index: usize| {
+        let start = patch.len() - 12 + index * 4;
+        u32::from_le_bytes(patch[start..start + 4].try_into().expect("4 bytes"))
+    };
+    let (source_crc32, target_crc32, patch_crc32) = (footer(0), footer(1), footer(2));
+    if crc32fast::hash(&patch[..patch.len() - 4]) != patch_crc32 {
+        return Err(invalid("bps patch checksum mismatch"));
+    }
+
+    let mut actions = &patch[4..patch.len() - 12];
+    let source_length = read_number(&mut actions)?;
+    let target_length = read_number(&mut actions)?;
+    let metadata_length = read_number(&mut actions)? as usize;
+    actions = actions
+        .get(metadata_length..)
+        .ok_or_else(|| invalid("bps metadata out of bounds"))?;
+
+    let source_image = formats::open(&source)?;
+    if source_image.size()? != source_length {
+        return Err(invalid(format!(
+            "source image is {:#x} bytes, patch expects {source_length:#x}",
+            source_image.size()?
+        )));
+    }
+    if crc32_reader(Reader::new(&*source_image)?)? != source_crc32 {
+        return Err(invalid("source image checksum mismatch"));
+    }
+
+    debug!(source_length, target_length, "applying bps patch");
+    write_target(target, |output| {
+        let crc32 = decode(&*source_image, actions, output, target_length, reporter)?;
+        (crc32 == target_crc32)
+            .then_some(())
+            .ok_or_else(|| invalid("target image checksum mismatch"))
+    })
+}
+
+/// Create `target` and fill it with `write`, removing it again if `write` fails.
+fn write_target<P: AsRef<Path>, F>(target: P, write: F) -> io::Result<()>
+where
+    F: FnOnce(&File) -> io::Result<()>,
+{
+    let output = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&target)?;
+    let written = write(&output);
+
+    if let Err(error) = &written {
+        debug!(%error, "removing incomplete target");
+        drop(output);
+        std::fs::remove_file(&target)?;
+    }
+    written
+}
+
+/// Decode BPS actions into `output`, returning the CRC32 of everything written.
+fn decode(
+    source: &dyn Backend,
+    mut actions: &[u8],
+    output: &File,
+    target_length: u64,
+    reporter: &mut dyn Reporter,
+) -> io::Result<u32> {
+    let mut writer = Crc32Writer {
+        inner: io::BufWriter::new(output),
+        crc32: crc32fast::Hasher::new(),
+    };
+    let mut buffer = vec![0; 0x100000];
+    let (mut output_offset, mut source_relative, mut target_relative) = (0u64, 0i64, 0i64);
+
+    while !actions.is_empty() {
+        let command = read_number(&mut actions)?;
+        let length = (command >> 2) + 1;
+        if output_offset + length > target_length {
+            return Err(invalid("bps action writes past the end of the target"));
+        }
+
+        let action = match command & 3 {
+            0 => Action::SourceRead,
+            1 => Action::TargetRead,
+            2 => Action::SourceCopy,
+            _ => Action::TargetCopy,
+        };
+
+        let mut copied = 0;
+        match action {
+            Action::SourceRead | Action::SourceCopy => {
+                let start = if action == Action::SourceRead {
+                    output_offset
+                } else {
+                    source_relative += read_relative(&mut actions)?;
+                    let start = source_relative as u64;
+                    source_relative += length as i64;
+                    start
+                };
+                while copied < length {
+                    let chunk = &mut buffer[..(length - copied).min(0x100000) as usize];
+                    source.read_at(start + copied, chunk)?;
+                    writer.write_all(chunk)?;
+                    copied += chunk.len() as u64;
+                }
+            }
+            Action::TargetRead => {
+                let data = actions
+                    .get(..length as usize)
+                    .ok_or_else(|| invalid("bps target read out of bounds"))?;
+                writer.write_all(data)?;
+                actions = &actions[length as usize..];
+            }
+            Action::TargetCopy => {
+                target_relative += read_relative(&mut actions)?;
+                if target_relative < 0 || target_relative as u64 >= output_offset {
+                    return Err(invalid("bps target copy out of bounds"));
+                }
+                // copies may overlap the bytes they produce, so never read past what's written
+                while copied < length {
+                    let distance = output_offset + copied - target_relative as u64;
+                    let chunk_length = (length - copied).min(distance).min(0x100000);
+                    let chunk = &mut buffer[..chunk_length as usize];
+                    writer.flush()?;
+                    output.read_at(target_relative as u64, chunk)?;
+                    writer.write_all(chunk)?;
+                    target_relative += chunk_length as i64;
+                    copied += chunk_length;
+                }
+            }
+        }
+        output_offset += length;
+        progress::report(
+            reporter,
+            Progress {
+                phase: Phase::Patch,
+                bytes: output_offset,
+                total: target_length,
+                file: None,
+            },
+        )?;
+    }
+
+    if output_offset != target_length {
+        return Err(invalid("bps patch does not produce the full target"));
+    }
+    writer.flush()?;
+    Ok(writer.crc32.finalize())
+}
+
+/// Apply a BPS or VCDIFF patch to the image at `source`, writing the result to
+/// `target`. The format is detected from the first bytes of the patch.
+pub fn apply<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
+    source: P,
+    patch: Q,
+    target: R,
+) -> io::Result<()> {
+    apply_with_progress(source, patch, target, &mut Silent)
+}
+
+/// Apply a BPS or VCDIFF patch like [`apply`], reporting progress as the
+/// target is written.
+pub fn apply_with_progress<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
+    source: P,
+    patch: Q,
+    target: R,
+    reporter: &mut dyn Reporter,
+) -> io::Result<()> {
+    let mut magic = [0; 4];
+    File::open(&patch)?.read_exact(&mut magic).map_err(|_| {
+        invalid(format!(
+            "{} is not a BPS or VCDIFF patch",
+            patch.as_ref().display()
+        ))
+    })?;
+    match &magic {
+        BPS_MAGIC => apply_bps_with_progress(source, patch, target, reporter),
+        VCDIFF_MAGIC => apply_vcdiff_with_progress(source, patch, target, reporter),
+        _ => Err(invalid(format!(
+            "{} is not a BPS or VCDIFF patch",
+            patch.as_ref().display()
+        ))),
+    }
+}
+
+/// VCDIFF window indicator: copies address a segment of the source.
+const VCD_SOURCE: u8 = 0x01;
+/// VCDIFF window indicator: copies address a segment of the target so far.
+const VCD_TARGET: u8 = 0x02;
+/// VCDIFF window indicator (xdelta3): the window has an Adler-32 checksum.
+const VCD_ADLER32: u8 = 0x04;
+/// VCDIFF header indicator: a secondary compressor ID follows.
+const VCD_DECOMPRESS: u8 = 0x01;
+/// VCDIFF header indicator: a custom code table follows.
+const VCD_CODETABLE: u8 = 0x02;
+/// VCDIFF header indicator (xdelta3): application data follows.
+const VCD_APPHEADER: u8 = 0x04;
+
+/// VCDIFF instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Instruction {
+    Noop,
+    Add,
+    Run,
+    Copy,
+}
+
+/// An entry in a VCDIFF code table: two instructions, each with a size (zero
+/// if the size follows in the instruction section) and a copy mode.
+type Code = [(Instruction, u64, u8); 2];
+
+/// The default VCDIFF code table (RFC 3284, section 5.6).
+fn default_code_table() -> Vec<Code> {
+    use Instruction::{Add, Copy, Noop, Run};
+    let none = (Noop, 0, 0);
+
+    let mut table = vec![[(Run, 0, 0), none]];
+    table.extend((0..=17).map(|size| [(Add, size, 0), none]));
+    for mode in 0..=8 {
+        table.push([(Copy, 0, mode), none]);
+        table.extend((4..=18).map(|size| [(Copy, size, mode), none]));
+    }
+    for mode in 0..=8 {
+        let copy_sizes = if mode <= 5 { 4..=6 } else { 4..=4 };
+        for add_size in 1..=4 {
+            table.extend(
+                copy_sizes
+                    .clone()
+                    .map(|copy_size| [(Add, add_size, 0), (Copy, copy_size, mode)]),
+            );
+        }
+    }
+    table.extend((0..=8).map(|mode| [(Copy, 4, mode), (Add, 1, 0)]));
+    table
+}
+
+/// Read a VCDIFF variable length number: big endian base 128, with the high
+/// bit set on every byte but the last.
+fn read_vcdiff_number(input: &mut &[u8]) -> io::Result<u64> {
+    let mut number = 0u64;
+    loop {
+        let (&byte, rest) = input
+            .split_first()
+            .ok_or_else(|| invalid("vcdiff number out of bounds"))?;
+        *input = rest;
+        number = number
+            .checked_mul(0x80)
+            .ok_or_else(|| invalid("vcdiff number overflow"))?
+            | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(number);
+        }
+    }
+}
+
+/// Read a single byte from a VCDIFF section.
+fn read_vcdiff_byte(input: &mut &[u8]) -> io::Result<u8> {
+    let (&byte, rest) = input
+        .split_first()
+        .ok_or_else(|| invalid("vcdiff section out of bounds"))?;
+    *input = rest;
+    Ok(byte)
+}
+
+/// Take the next `length` bytes from a VCDIFF section.
+fn take<'a>(input: &mut &'a [u8], length: u64, what: &str) -> io::Result<&'a [u8]> {
+    if length > input.len() as u64 {
+        return Err(invalid(format!("vcdiff {what} out of bounds")));
+    }
+    let (taken, rest) = input.split_at(length as usize);
+    *input = rest;
+    Ok(taken)
+}
+
+/// Where a window's copies below `length` read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment {
+    Source,
+    Target,
+}
+
+/// A parsed VCDIFF window, not yet decoded.
+struct Window<'a> {
+    /// Segment kind, offset and length, if the window copies from one.
+    segment: Option<(Segment, u64, u64)>,
+    target_length: u64,
+    adler32: Option<u32>,
+    data: &'a [u8],
+    instructions: &'a [u8],
+    addresses: &'a [u8],
+}
+
+/// Parse the header and every window of a VCDIFF patch.
+fn vcdiff_windows(patch: &[u8]) -> io::Result<Vec<Window<'_>>> {
+    let mut input = patch
+        .strip_prefix(VCDIFF_MAGIC)
+        .ok_or_else(|| invalid("not a vcdiff patch"))?;
+    let header = read_vcdiff_byte(&mut input)?;
+    if header & VCD_DECOMPRESS != 0 {
+        let compressor = read_vcdiff_byte(&mut input)?;
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("vcdiff secondary compression ({compressor}) is not supported: recreate the patch with `xdelta3 -S none`"),
+        ));
+    }
+    if header & VCD_CODETABLE != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "vcdiff custom code tables are not supported",
+        ));
+    }
+    if header & VCD_APPHEADER != 0 {
+        let length = read_vcdiff_number(&mut input)?;
+        take(&mut input, length, "application header")?;
+    }
+
+    let mut windows = Vec::new();
+    while !input.is_empty() {
+        let indicator = read_vcdiff_byte(&mut input)?;
+        let segment = match indicator & (VCD_SOURCE | VCD_TARGET) {
+            0 => None,
+            VCD_SOURCE | VCD_TARGET => {
+                let length = read_vcdiff_number(&mut input)?;
+                let offset = read_vcdiff_number(&mut input)?;
+                let kind = match indicator & VCD_SOURCE {
+                    0 => Segment::Target,
+                    _ => Segment::Source,
+                };
+                Some((kind, offset, length))
+            }
+            _ => return Err(invalid("vcdiff window copies from both source and target")),
+        };
+
+        let length = read_vcdiff_number(&mut input)?;
+        let mut delta = take(&mut input, length, "delta encoding")?;
+        let target_length = read_vcdiff_number(&mut delta)?;
+        if read_vcdiff_byte(&mut delta)? != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "vcdiff secondary compression is not supported: recreate the patch with `xdelta3 -S none`",
+            ));
+        }
+        let data_length = read_vcdiff_number(&mut delta)?;
+        let instructions_length = read_vcdiff_number(&mut delta)?;
+        let addresses_length = read_vcdiff_number(&mut delta)?;
+        let adler32 = match indicator & VCD_ADLER32 {
+            0 => None,
+            _ => {
+                let bytes = take(&mut delta, 4, "window checksum")?;
+                Some(u32::from_be_bytes(bytes.try_into().expect("4 bytes")))
+            }
+        };
+
+        windows.push(Window {
+            segment,
+            target_length,
+            adler32,
+            data: take(&mut delta, data_length, "data section")?,
+            instructions: take(&mut delta, instructions_length, "instruction section")?,
+            addresses: take(&mut delta, addresses_length, "address section")?,
+        });
+    }
+
+    Ok(windows)
+}
+
+/// Recently used copy addresses (RFC 3284, section 5.1), with the default
+/// sizes of 4 near and 3 same slots.
+struct AddressCache {
+    near: [u64; 4],
+    next_slot: usize,
+    same: Vec<u64>,
+}
+
+impl AddressCache {
+    fn new() -> AddressCache {
+        AddressCache {
+            near: [0; 4],
+            next_slot: 0,
+            same: vec![0; 3 * 256],
+        }
+    }
+
+    /// Decode the address of a copy in `mode`, at position `here`.
+    fn decode(&mut self, addresses: &mut &[u8], here: u64, mode: u8) -> io::Result<u64> {
+        let address = match mode {
+            0 => read_vcdiff_number(addresses)?,
+            1 => here
+                .checked_sub(read_vcdiff_number(addresses)?)
+                .ok_or_else(|| invalid("vcdiff copy address out of bounds"))?,
+            2..=5 => self.near[mode as usize - 2]
+                .checked_add(read_vcdiff_number(addresses)?)
+                .ok_or_else(|| invalid("vcdiff copy address out of bounds"))?,
+            _ => {
+                let index = (mode as usize - 6) * 256 + read_vcdiff_byte(addresses)? as usize;
+                *self
+                    .same
+                    .get(index)
+                    .ok_or_else(|| invalid(format!("vcdiff copy mode {mode} out of bounds")))?
+            }
+        };
+        if address >= here {
+            return Err(invalid("vcdiff copy address out of bounds"));
+        }
+
+        self.near[self.next_slot] = address;
+        self.next_slot = (self.next_slot + 1) % self.near.len();
+        let same = self.same.len() as u64;
+        self.same[(address % same) as usize] = address;
+        Ok(address)
+    }
+}
+
+/// Decode a single VCDIFF window.
+///
+/// Copies from the target read whatever `output` holds so far, so it must be
+/// flushed before decoding a window with a target segment.
+fn decode_window(
+    window: &Window,
+    table: &[Code],
+    source: &dyn Backend,
+    output: &File,
+) -> io::Result<Vec<u8>> {
+    let (kind, segment_offset, segment_length) = window.segment.unwrap_or((Segment::Source, 0, 0));
+    let segment: &dyn Backend = match kind {
+        Segment::Source => source,
+        Segment::Target => output,
+    };
+
+    let mut target = Vec::with_capacity(window.target_length.min(0x4000000) as usize);
+    let (mut data, mut instructions, mut addresses) =
+        (window.data, window.instructions, window.addresses);
+    let mut cache = AddressCache::new();
+
+    while !instructions.is_empty() {
+        let code = table[read_vcdiff_byte(&mut instructions)? as usize];
+        for (instruction, size, mode) in code {
+            if instruction == Instruction::Noop {
+                continue;
+            }
+            let size = match size {
+                0 => read_vcdiff_number(&mut instructions)?,
+                size => size,
+            };
+            if size > window.target_length - target.len() as u64 {
+                return Err(invalid(
+                    "vcdiff instruction writes past the end of the window",
+                ));
+            }
+
+            match instruction {
+                Instruction::Noop => {}
+                Instruction::Add => target.extend(take(&mut data, size, "data section")?),
+                Instruction::Run => {
+                    let byte = read_vcdiff_byte(&mut data)?;
+                    target.resize(target.len() + size as usize, byte);
+                }
+                Instruction::Copy => {
+                    let here = segment_length + target.len() as u64;
+                    let mut address = cache.decode(&mut addresses, here, mode)?;
+                    let mut remaining = size;
+                    // from the segment, then (possibly overlapping) from this window
+                    if address < segment_length {
+                        let length = remaining.min(segment_length - address);
+                        let start = target.len();
+                        target.resize(start + length as usize, 0);
+                        segment.read_at(segment_offset + address, &mut target[start..])?;
+                        address += length;
+                        remaining -= length;
+                    }
+                    let from = address.saturating_sub(segment_length) as usize;
+                    for index in from..from + remaining as usize {
+                        target.push(target[index]);
+                    }
+                }
+            }
+        }
+    }
+
+    if target.len() as u64 != window.target_length {
+        return Err(invalid("vcdiff window does not produce its full target"));
+    }
+    if let Some(expected) = window.adler32 {
+        if adler2::adler32_slice(&target) != expected {
+            return Err(invalid("target window checksum mismatch"));
+        }
+    }
+    Ok(target)
+}
+
+/// Apply a VCDIFF (xdelta3) patch to the image at `source`, writing the result
+/// to `target`.
+///
+/// VCDIFF patches carry no checksum of the source or of the whole target.
+/// Instead, every window's source segment is checked against the size of the
+/// source before anything is written, and windows with an xdelta3 Adler-32
+/// checksum are checked as they are decoded. If a check fails, the target is
+/// removed and an error returned.
+pub fn apply_vcdiff<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
+    source: P,
+    patch: Q,
+    target: R,
+) -> io::Result<()> {
+    apply_vcdiff_with_progress(source, patch, target, &mut Silent)
+}
+
+/// Apply a VCDIFF patch like [`apply_vcdiff`], reporting progress after each window.
+///
+/// A cancelled target is removed, like one that fails its checksum.
+pub fn apply_vcdiff_with_progress<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
+    source: P,
+    patch: Q,
+    target: R,
+    reporter: &mut dyn Reporter,
+) -> io::Result<()> {
+    let _span = info_span!(
+        "apply_vcdiff",
+        source = %source.as_ref().display(),
+        target = %target.as_ref().display()
+    )
+    .entered();
+    let patch = std::fs::read(patch)?;
+    let windows = vcdiff_windows(&patch)?;
+
+    let source_image = formats::open(&source)?;
+    let source_length = source_image.size()?;
+    for (kind, offset, length) in windows.iter().filter_map(|window| window.segment) {
+        if kind == Segment::Source && offset.saturating_add(length) > source_length {
+            return Err(invalid(format!(
+                "source image is {source_length:#x} bytes, patch reads up to {:#x}",
+                offset.saturating_add(length)
+            )));
+        }
+    }
+
+    let target_length = windows.iter().map(|window| window.target_length).sum();
+    let table = default_code_table();
+    debug!(
+        source_length,
+        target_length,
+        windows = windows.len(),
+        "applying vcdiff patch"
+    );
+    write_target(target, |output| {
+        let mut writer = io::BufWriter::new(output);
+        let mut written = 0;
+        for window in &windows {
+            if let Some((Segment::Target, offset, length)) = window.segment {
+                if offset.saturating_add(length) > written {
+                    return Err(invalid("vcdiff target segment out of bounds"));
+                }
+                writer.flush()?;
+            }
+            let data = decode_window(window, &table, &*source_image, output)?;
+            writer.write_all(&data)?;
+            written += data.len() as u64;
+            progress::report(
+                reporter,
+                Progress {
+                    phase: Phase::Patch,
+                    bytes: written,
+                    total: target_length,
+                    file: None,
+                },
+            )?;
+        }
+        writer.flush()
+    })
+}
@@ -0,0 +1,74 @@
+//! Progress reporting for long operations.
+//!
+//! Building an image, extracting files, and applying a patch all take a
+//! [`Reporter`], which is called regularly and can cancel the operation.
+//! Cancelled operations return an [`io::ErrorKind::Interrupted`] error.
+use std::io;
+
+/// Which operation is reporting progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Writing a rebuilt image.
+    Build,
+    /// Copying files out of an image.
+    Extract,
+    /// Applying a BPS or VCDIFF patch.
+    Patch,
+}
+
+/// A progress update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress<'a> {
+    pub phase: Phase,
+    /// Bytes written so far.
+    pub bytes: u64,
+    /// Bytes to write in total.
+    pub total: u64,
+    /// The file just written, if any.
+    pub file: Option<&'a str>,
+}
+
+/// Whether to keep going after a progress update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Control {
+    #[default]
+    Continue,
+    Cancel,
+}
+
+/// Receives progress updates.
+///
+/// Implemented for closures, so a build can be watched with
+/// `&mut |progress: Progress| { ...; Control::Continue }`.
+pub trait Reporter {
+    fn report(&mut self, progress: Progress<'_>) -> Control;
+}
+
+impl<F: FnMut(Progress<'_>) -> Control> Reporter for F {
+    fn report(&mut self, progress: Progress<'_>) -> Control {
+        self(progress)
+    }
+}
+
+/// Ignores progress, and never cancels.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Silent;
+
+impl Reporter for Silent {
+    fn report(&mut self, _: Progress<'_>) -> Control {
+        Control::Continue
+    }
+}
+
+/// Was this error caused by a [`Reporter`] cancelling?
+pub fn is_cancelled(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::Interrupted
+}
+
+/// Report progress, turning cancellation into an error.
+pub(crate) fn report(reporter: &mut dyn Reporter, progress: Progress<'_>) -> io::Result<()> {
+    match reporter.report(progress) {
+        Control::Continue => Ok(()),
+        Control::Cancel => Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled")),
+    }
+}
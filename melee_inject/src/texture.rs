@@ -0,0 +1,1673 @@
+//! GameCube (GX) texture formats, decoded to RGBA.
+//!
+//! GX textures are stored in tiles: the image is split into blocks (8x8
+//! pixels for 4 bit formats, 8x4 for 8 bit, 4x4 for 16 and 32 bit), stored
+//! left to right, top to bottom, each 32 bytes. Images whose size isn't a
+//! multiple of the block size are padded out to whole blocks.
+//!
+//! - `I4`, `I8`: intensity (grey), opaque.
+//! - `IA4`, `IA8`: intensity with alpha.
+//! - `RGB565`: opaque colour.
+//! - `RGB5A3`: `RGB555` when the top bit is set, `RGB444` with 3 bits of
+//!   alpha when it isn't.
+//! - `RGBA8`: 64 byte blocks, the alpha and red of 16 pixels followed by
+//!   their green and blue.
+//! - `CI4`, `CI8`, `CI14x2`: indices into a palette of `IA8`, `RGB565` or
+//!   `RGB5A3` colours.
+//! - `CMPR`: DXT1, each 8x8 block four 4x4 sub-blocks of two `RGB565`
+//!   colours and 2 bit indices.
+//!
+//! [`encode`] goes the other way, from an image (usually a PNG, see
+//! [`Image::from_png`]) to any of these formats: `CMPR` blocks are fitted to
+//! each block's colours, and paletted formats get a palette by median cut.
+//!
+//! [`find`] lists the textures in a DAT, and [`export`] writes them out as
+//! PNGs. [`inject_dir`] reads them back in, re-encoded in place, or
+//! [`relocate`]d to the end of the data block when their size or format
+//! changes.
+use super::dat::DatFile;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::debug;
+
+/// A texture format, with its GX format id as its value.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Format {
+    I4 = 0,
+    I8 = 1,
+    IA4 = 2,
+    IA8 = 3,
+    RGB565 = 4,
+    RGB5A3 = 5,
+    RGBA8 = 6,
+    CI4 = 8,
+    CI8 = 9,
+    CI14x2 = 10,
+    CMPR = 14,
+}
+
+/// The format of a paletted texture's colours, with its GX id as its value.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PaletteFormat {
+    IA8 = 0,
+    RGB565 = 1,
+    RGB5A3 = 2,
+}
+
+/// A palette for `CI4`, `CI8` and `CI14x2` textures: big endian 16 bit colours.
+#[derive(Debug, Clone, Copy)]
+pub struct Palette<'a> {
+    pub format: PaletteFormat,
+    pub data: &'a [u8],
+}
+
+/// A decoded image, 4 bytes per pixel, rows top to bottom.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub rgba: Vec<u8>,
+}
+
+fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+impl Format {
+    pub const ALL: [Format; 11] = [
+        Format::I4,
+        Format::I8,
+        Format::IA4,
+        Format::IA8,
+        Format::RGB565,
+        Format::RGB5A3,
+        Format::RGBA8,
+        Format::CI4,
+        Format::CI8,
+        Format::CI14x2,
+        Format::CMPR,
+    ];
+
+    /// The format with GX id `id`.
+    pub fn from_id(id: u32) -> Option<Format> {
+        Format::ALL.into_iter().find(|format| format.id() == id)
+    }
+
+    pub fn id(self) -> u32 {
+        self as u32
+    }
+
+    /// The format's name, as DAT Texture Wizard writes it.
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::I4 => "I4",
+            Format::I8 => "I8",
+            Format::IA4 => "IA4",
+            Format::IA8 => "IA8",
+            Format::RGB565 => "RGB565",
+            Format::RGB5A3 => "RGB5A3",
+            Format::RGBA8 => "RGBA8",
+            Format::CI4 => "CI4",
+            Format::CI8 => "CI8",
+            Format::CI14x2 => "CI14x2",
+            Format::CMPR => "CMPR",
+        }
+    }
+
+    pub fn bits_per_pixel(self) -> usize {
+        match self {
+            Format::I4 | Format::CI4 | Format::CMPR => 4,
+            Format::I8 | Format::IA4 | Format::CI8 => 8,
+            Format::IA8 | Format::RGB565 | Format::RGB5A3 | Format::CI14x2 => 16,
+            Format::RGBA8 => 32,
+        }
+    }
+
+    /// Width and height of a block, in pixels.
+    pub fn block_size(self) -> (usize, usize) {
+        match self.bits_per_pixel() {
+            4 => (8, 8),
+            8 => (8, 4),
+            _ => (4, 4),
+        }
+    }
+
+    /// Does the format index into a palette?
+    pub fn is_paletted(self) -> bool {
+        matches!(self, Format::CI4 | Format::CI8 | Format::CI14x2)
+    }
+
+    /// Size of the image data for a `width` by `height` texture.
+    pub fn data_size(self, width: usize, height: usize) -> usize {
+        let (block_width, block_height) = self.block_size();
+        let blocks = width.div_ceil(block_width) * height.div_ceil(block_height);
+        blocks * block_width * block_height * self.bits_per_pixel() / 8
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Format {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> io::Result<Format> {
+        Format::ALL
+            .into_iter()
+            .find(|format| format.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| invalid(format!("unknown texture format {name}")))
+    }
+}
+
+impl PaletteFormat {
+    /// The palette format with GX id `id`.
+    pub fn from_id(id: u32) -> Option<PaletteFormat> {
+        [
+            PaletteFormat::IA8,
+            PaletteFormat::RGB565,
+            PaletteFormat::RGB5A3,
+        ]
+        .into_iter()
+        .find(|format| *format as u32 == id)
+    }
+
+    pub fn id(self) -> u32 {
+        self as u32
+    }
+
+    /// Decode one palette colour.
+    pub fn decode(self, colour: u16) -> [u8; 4] {
+        match self {
+            PaletteFormat::IA8 => ia8(colour),
+            PaletteFormat::RGB565 => rgb565(colour),
+            PaletteFormat::RGB5A3 => rgb5a3(colour),
+        }
+    }
+}
+
+impl fmt::Display for PaletteFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PaletteFormat::IA8 => "IA8",
+            PaletteFormat::RGB565 => "RGB565",
+            PaletteFormat::RGB5A3 => "RGB5A3",
+        })
+    }
+}
+
+/// Widen an `bits` bit channel to 8 bits, repeating its high bits.
+fn expand(value: u16, bits: u32) -> u8 {
+    let value = u32::from(value) & ((1 << bits) - 1);
+    let mut wide = value << (8 - bits);
+    let mut shift = bits;
+    while shift < 8 {
+        wide |= wide >> shift;
+        shift *= 2;
+    }
+    wide as u8
+}
+
+fn ia8(value: u16) -> [u8; 4] {
+    let intensity = value as u8;
+    [intensity, intensity, intensity, (value >> 8) as u8]
+}
+
+fn rgb565(value: u16) -> [u8; 4] {
+    [
+        expand(value >> 11, 5),
+        expand(value >> 5, 6),
+        expand(value, 5),
+        0xff,
+    ]
+}
+
+fn rgb5a3(value: u16) -> [u8; 4] {
+    if value & 0x8000 != 0 {
+        [
+            expand(value >> 10, 5),
+            expand(value >> 5, 5),
+            expand(value, 5),
+            0xff,
+        ]
+    } else {
+        [
+            expand(value >> 8, 4),
+            expand(value >> 4, 4),
+            expand(value, 4),
+            expand(value >> 12, 3),
+        ]
+    }
+}
+
+/// Decode a texture to RGBA.
+///
+/// `data` must hold at least [`Format::data_size`] bytes; paletted formats
+/// need a `palette` holding every colour they index.
+pub fn decode(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    format: Format,
+    palette: Option<Palette>,
+) -> io::Result<Image> {
+    let size = format.data_size(width, height);
+    let data = data.get(..size).ok_or_else(|| {
+        invalid(format!(
+            "{width}x{height} {format} texture needs {size:#x} bytes, but only {:#x} are left",
+            data.len()
+        ))
+    })?;
+    if format.is_paletted() && palette.is_none() {
+        return Err(invalid(format!("{format} texture has no palette")));
+    }
+
+    let (block_width, block_height) = format.block_size();
+    let blocks_wide = width.div_ceil(block_width);
+    let block_bytes = block_width * block_height * format.bits_per_pixel() / 8;
+    let mut rgba = vec![0; width * height * 4];
+    for (index, block) in data.chunks_exact(block_bytes).enumerate() {
+        let pixels = decode_block(block, format, palette)?;
+        let (left, top) = (
+            index % blocks_wide * block_width,
+            index / blocks_wide * block_height,
+        );
+        for (pixel, colour) in pixels.iter().enumerate() {
+            let (x, y) = (left + pixel % block_width, top + pixel / block_width);
+            if x < width && y < height {
+                let at = (y * width + x) * 4;
+                rgba[at..at + 4].copy_from_slice(colour);
+            }
+        }
+    }
+    Ok(Image {
+        width,
+        height,
+        rgba,
+    })
+}
+
+/// Decode one block to its pixels, left to right, top to bottom.
+fn decode_block(
+    block: &[u8],
+    format: Format,
+    palette: Option<Palette>,
+) -> io::Result<Vec<[u8; 4]>> {
+    let halfword = |index: usize| u16::from_be_bytes([block[index * 2], block[index * 2 + 1]]);
+    let nibble = |index: usize| (block[index / 2] >> (4 - index % 2 * 4)) & 0x0f;
+    let lookup = |index: u16| {
+        let palette = palette.expect("checked by decode");
+        let at = index as usize * 2;
+        palette
+            .data
+            .get(at..at + 2)
+            .map(|colour| {
+                palette
+                    .format
+                    .decode(u16::from_be_bytes([colour[0], colour[1]]))
+            })
+            .ok_or_else(|| {
+                invalid(format!(
+                    "palette index {index} is past the end of the palette"
+                ))
+            })
+    };
+
+    Ok(match format {
+        Format::I4 => (0..64)
+            .map(|index| {
+                let intensity = nibble(index) * 0x11;
+                [intensity, intensity, intensity, 0xff]
+            })
+            .collect(),
+        Format::I8 => block
+            .iter()
+            .map(|&intensity| [intensity, intensity, intensity, 0xff])
+            .collect(),
+        Format::IA4 => block
+            .iter()
+            .map(|&byte| {
+                let intensity = (byte & 0x0f) * 0x11;
+                [intensity, intensity, intensity, (byte >> 4) * 0x11]
+            })
+            .collect(),
+        Format::IA8 => (0..16).map(|index| ia8(halfword(index))).collect(),
+        Format::RGB565 => (0..16).map(|index| rgb565(halfword(index))).collect(),
+        Format::RGB5A3 => (0..16).map(|index| rgb5a3(halfword(index))).collect(),
+        Format::RGBA8 => (0..16)
+            .map(|index| {
+                let (ar, gb) = (&block[index * 2..], &block[32 + index * 2..]);
+                [ar[1], gb[0], gb[1], ar[0]]
+            })
+            .collect(),
+        Format::CI4 => (0..64)
+            .map(|index| lookup(u16::from(nibble(index))))
+            .collect::<io::Result<_>>()?,
+        Format::CI8 => block
+            .iter()
+            .map(|&index| lookup(u16::from(index)))
+            .collect::<io::Result<_>>()?,
+        Format::CI14x2 => (0..16)
+            .map(|index| lookup(halfword(index) & 0x3fff))
+            .collect::<io::Result<_>>()?,
+        Format::CMPR => {
+            let mut pixels = vec![[0; 4]; 64];
+            for (sub, chunk) in block.chunks_exact(8).enumerate() {
+                let colours = cmpr_palette(
+                    u16::from_be_bytes([chunk[0], chunk[1]]),
+                    u16::from_be_bytes([chunk[2], chunk[3]]),
+                );
+                let (left, top) = (sub % 2 * 4, sub / 2 * 4);
+                for (row, bits) in chunk[4..].iter().enumerate() {
+                    for column in 0..4 {
+                        let index = (bits >> (6 - column * 2)) & 0x03;
+                        pixels[(top + row) * 8 + left + column] = colours[index as usize];
+                    }
+                }
+            }
+            pixels
+        }
+    })
+}
+
+/// The four colours of a `CMPR` sub-block.
+///
+/// With `first > second` the two inner colours are blends at thirds;
+/// otherwise the third is the average, and the fourth transparent.
+fn cmpr_palette(first: u16, second: u16) -> [[u8; 4]; 4] {
+    let (a, b) = (rgb565(first), rgb565(second));
+    let blend = |weight_a: u16, weight_b: u16| {
+        let mut colour = [0, 0, 0, 0xff];
+        for channel in 0..3 {
+            colour[channel] = ((u16::from(a[channel]) * weight_a
+                + u16::from(b[channel]) * weight_b)
+                / (weight_a + weight_b)) as u8;
+        }
+        colour
+    };
+    if first > second {
+        [a, b, blend(2, 1), blend(1, 2)]
+    } else {
+        [a, b, blend(1, 1), [0; 4]]
+    }
+}
+
+/// A texture in a GX format, as stored in a DAT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Texture {
+    pub format: Format,
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+    /// Format of the palette, for paletted formats.
+    pub palette_format: PaletteFormat,
+    /// Big endian palette colours, empty for formats without a palette.
+    pub palette: Vec<u8>,
+}
+
+impl Texture {
+    /// The texture's palette, if it has one.
+    pub fn palette(&self) -> Option<Palette<'_>> {
+        self.format.is_paletted().then_some(Palette {
+            format: self.palette_format,
+            data: &self.palette,
+        })
+    }
+
+    pub fn decode(&self) -> io::Result<Image> {
+        decode(
+            &self.data,
+            self.width,
+            self.height,
+            self.format,
+            self.palette(),
+        )
+    }
+}
+
+/// What to encode an image as: usually the format and size of the texture
+/// it replaces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target {
+    pub format: Format,
+    pub width: usize,
+    pub height: usize,
+    /// Palette format, for paletted formats.
+    pub palette_format: PaletteFormat,
+    /// Most colours the palette may have, for paletted formats.
+    pub max_colours: usize,
+}
+
+impl Target {
+    /// A `width` by `height` texture in `format`, with as many `RGB5A3`
+    /// palette colours as the format can index.
+    pub fn new(format: Format, width: usize, height: usize) -> Target {
+        Target {
+            format,
+            width,
+            height,
+            palette_format: PaletteFormat::RGB5A3,
+            max_colours: format.max_colours(),
+        }
+    }
+
+    /// The format and size of an existing texture, and no more palette
+    /// colours than it has.
+    pub fn matching(texture: &Texture) -> Target {
+        Target {
+            palette_format: texture.palette_format,
+            max_colours: (texture.palette.len() / 2).min(texture.format.max_colours()),
+            ..Target::new(texture.format, texture.width, texture.height)
+        }
+    }
+}
+
+/// How to encode an image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Spread the error of reducing colour precision to neighbouring pixels
+    /// (Floyd-Steinberg). Applies to every format but `CMPR`.
+    pub dither: bool,
+    /// Scale images that aren't the target's size, instead of refusing them.
+    pub resize: bool,
+}
+
+impl Format {
+    /// How many palette colours the format can index; 0 for formats
+    /// without a palette.
+    pub fn max_colours(self) -> usize {
+        match self {
+            Format::CI4 => 16,
+            Format::CI8 => 256,
+            Format::CI14x2 => 1 << 14,
+            _ => 0,
+        }
+    }
+}
+
+impl PaletteFormat {
+    /// Encode one palette colour, the nearest this format can hold.
+    pub fn encode(self, rgba: [u8; 4]) -> u16 {
+        match self {
+            PaletteFormat::IA8 => (u16::from(rgba[3]) << 8) | u16::from(luma(rgba)),
+            PaletteFormat::RGB565 => to_rgb565(rgba),
+            PaletteFormat::RGB5A3 => {
+                let [r, g, b, a] = rgba.map(u16::from);
+                let rgb444 =
+                    (narrow(a, 3) << 12) | (narrow(r, 4) << 8) | (narrow(g, 4) << 4) | narrow(b, 4);
+                if narrow(a, 3) < 7 {
+                    return rgb444;
+                }
+                // opaque: RGB555, unless the RGB444 colour is closer
+                let rgb555 = 0x8000 | (narrow(r, 5) << 10) | (narrow(g, 5) << 5) | narrow(b, 5);
+                [rgb555, rgb444]
+                    .into_iter()
+                    .min_by_key(|&value| distance(rgb5a3(value), rgba, false))
+                    .expect("two colours")
+            }
+        }
+    }
+}
+
+/// Round an 8 bit channel to `bits` bits.
+fn narrow(value: u16, bits: u32) -> u16 {
+    let max = (1 << bits) - 1;
+    (value * max + 127) / 255
+}
+
+fn to_rgb565(rgba: [u8; 4]) -> u16 {
+    let [r, g, b, _] = rgba.map(u16::from);
+    (narrow(r, 5) << 11) | (narrow(g, 6) << 5) | narrow(b, 5)
+}
+
+/// Perceptual brightness of a colour, for the intensity formats.
+fn luma(rgba: [u8; 4]) -> u8 {
+    let [r, g, b, _] = rgba.map(u32::from);
+    ((r * 299 + g * 587 + b * 114 + 500) / 1000) as u8
+}
+
+impl Image {
+    /// Read a PNG of any colour type as RGBA.
+    pub fn from_png(bytes: &[u8]) -> io::Result<Image> {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info().map_err(invalid)?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).map_err(invalid)?;
+        let pixels = &buffer[..info.buffer_size()];
+        let rgba = match info.color_type {
+            png::ColorType::Rgba => pixels.to_vec(),
+            png::ColorType::Rgb => pixels
+                .chunks_exact(3)
+                .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 0xff])
+                .collect(),
+            png::ColorType::GrayscaleAlpha => pixels
+                .chunks_exact(2)
+                .flat_map(|ia| [ia[0], ia[0], ia[0], ia[1]])
+                .collect(),
+            png::ColorType::Grayscale => pixels.iter().flat_map(|&i| [i, i, i, 0xff]).collect(),
+            png::ColorType::Indexed => {
+                return Err(invalid("paletted PNG wasn't expanded"));
+            }
+        };
+        Ok(Image {
+            width: info.width as usize,
+            height: info.height as usize,
+            rgba,
+        })
+    }
+
+    /// Write the image as an RGBA PNG.
+    pub fn to_png(&self) -> io::Result<Vec<u8>> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(invalid)?;
+        writer.write_image_data(&self.rgba).map_err(invalid)?;
+        writer.finish().map_err(invalid)?;
+        Ok(png)
+    }
+
+    /// The pixel at `x`, `y`, clamped to the edges of the image.
+    fn pixel(&self, x: usize, y: usize) -> [u8; 4] {
+        let at = (y.min(self.height - 1) * self.width + x.min(self.width - 1)) * 4;
+        self.rgba[at..at + 4].try_into().expect("4 bytes")
+    }
+
+    /// Scale the image to `width` by `height`.
+    ///
+    /// A tent filter as wide as the scale factor: bilinear when growing,
+    /// averaging when shrinking. Colours are weighted by alpha so
+    /// transparent pixels don't bleed into their neighbours.
+    pub fn resize(&self, width: usize, height: usize) -> Image {
+        if self.width == 0 || self.height == 0 {
+            return Image {
+                width,
+                height,
+                rgba: vec![0; width * height * 4],
+            };
+        }
+        let premultiplied = self
+            .rgba
+            .chunks_exact(4)
+            .map(|pixel| {
+                let [r, g, b, a] = [pixel[0], pixel[1], pixel[2], pixel[3]].map(f32::from);
+                [r * a / 255.0, g * a / 255.0, b * a / 255.0, a]
+            })
+            .collect::<Vec<_>>();
+        let columns = weights(self.width, width);
+        let rows = weights(self.height, height);
+
+        let mut rgba = Vec::with_capacity(width * height * 4);
+        for row in &rows {
+            for column in &columns {
+                let mut sum = [0.0; 4];
+                for &(y, row_weight) in row {
+                    for &(x, column_weight) in column {
+                        let pixel = premultiplied[y * self.width + x];
+                        for channel in 0..4 {
+                            sum[channel] += pixel[channel] * row_weight * column_weight;
+                        }
+                    }
+                }
+                let alpha = sum[3];
+                for value in &sum[..3] {
+                    let colour = if alpha > 0.0 {
+                        value * 255.0 / alpha
+                    } else {
+                        0.0
+                    };
+                    rgba.push(colour.round().clamp(0.0, 255.0) as u8);
+                }
+                rgba.push(alpha.round().clamp(0.0, 255.0) as u8);
+            }
+        }
+        Image {
+            width,
+            height,
+            rgba,
+        }
+    }
+}
+
+/// For each of `to` output pixels, the `from` input pixels it samples and
+/// their weights.
+fn weights(from: usize, to: usize) -> Vec<Vec<(usize, f32)>> {
+    let scale = from as f32 / to as f32;
+    let radius = scale.max(1.0);
+    (0..to)
+        .map(|index| {
+            let centre = (index as f32 + 0.5) * scale - 0.5;
+            let first = (centre - radius).floor().max(0.0) as usize;
+            let last = ((centre + radius).ceil().max(0.0) as usize).min(from - 1);
+            let mut taps = (first..=last)
+                .map(|source| {
+                    let weight = 1.0 - (source as f32 - centre).abs() / radius;
+                    (source, weight)
+                })
+                .filter(|(_, weight)| *weight > 0.0)
+                .collect::<Vec<_>>();
+            if taps.is_empty() {
+                let nearest = centre.round().clamp(0.0, (from - 1) as f32) as usize;
+                taps.push((nearest, 1.0));
+            }
+            let total = taps.iter().map(|(_, weight)| weight).sum::<f32>();
+            taps.iter_mut().for_each(|(_, weight)| *weight /= total);
+            taps
+        })
+        .collect()
+}
+
+/// Encode an image as `target`.
+///
+/// Images must be the target's size, unless [`Options::resize`] is set.
+pub fn encode(image: &Image, target: &Target, options: &Options) -> io::Result<Texture> {
+    let (width, height) = (target.width, target.height);
+    if width == 0 || height == 0 {
+        return Err(invalid("can't encode an empty texture"));
+    }
+    if image.rgba.len() != image.width * image.height * 4 {
+        return Err(invalid("image has the wrong number of pixels"));
+    }
+    let resized;
+    let image = if (image.width, image.height) == (width, height) {
+        image
+    } else if options.resize {
+        resized = image.resize(width, height);
+        &resized
+    } else {
+        return Err(invalid(format!(
+            "image is {}x{}, but the texture is {width}x{height}",
+            image.width, image.height
+        )));
+    };
+
+    let format = target.format;
+    let mut colours = Vec::new();
+    if format.is_paletted() {
+        let max_colours = target.max_colours.min(format.max_colours());
+        if max_colours == 0 {
+            return Err(invalid(format!(
+                "{format} texture needs at least one palette colour"
+            )));
+        }
+        colours = build_palette(image, target.palette_format, max_colours);
+    }
+
+    Ok(Texture {
+        format,
+        width,
+        height,
+        data: encode_pixels(image, target, options, &colours),
+        palette_format: target.palette_format,
+        palette: colours
+            .iter()
+            .flat_map(|colour| colour.to_be_bytes())
+            .collect(),
+    })
+}
+
+/// Encode an image that's already the target's size, with `colours` as the
+/// palette for paletted formats.
+fn encode_pixels(image: &Image, target: &Target, options: &Options, colours: &[u16]) -> Vec<u8> {
+    let format = target.format;
+    let values = match format {
+        Format::CMPR => Vec::new(),
+        Format::I4 => quantize(image, options.dither, |rgba| {
+            let intensity = narrow(u16::from(luma(rgba)), 4) as u8;
+            let grey = intensity * 0x11;
+            (u32::from(intensity), [grey, grey, grey, 0xff])
+        }),
+        Format::I8 => quantize(image, options.dither, |rgba| {
+            let grey = luma(rgba);
+            (u32::from(grey), [grey, grey, grey, 0xff])
+        }),
+        Format::IA4 => quantize(image, options.dither, |rgba| {
+            let intensity = narrow(u16::from(luma(rgba)), 4) as u8;
+            let alpha = narrow(u16::from(rgba[3]), 4) as u8;
+            let grey = intensity * 0x11;
+            (
+                u32::from((alpha << 4) | intensity),
+                [grey, grey, grey, alpha * 0x11],
+            )
+        }),
+        Format::IA8 | Format::RGB565 | Format::RGB5A3 => {
+            let colours = match format {
+                Format::IA8 => PaletteFormat::IA8,
+                Format::RGB565 => PaletteFormat::RGB565,
+                _ => PaletteFormat::RGB5A3,
+            };
+            quantize(image, options.dither, |rgba| {
+                let value = colours.encode(rgba);
+                (u32::from(value), colours.decode(value))
+            })
+        }
+        Format::RGBA8 => quantize(image, false, |rgba| (u32::from_be_bytes(rgba), rgba)),
+        Format::CI4 | Format::CI8 | Format::CI14x2 => {
+            let decoded = colours
+                .iter()
+                .map(|&colour| target.palette_format.decode(colour))
+                .collect::<Vec<_>>();
+            let mut nearest = HashMap::new();
+            quantize(image, options.dither, |rgba| {
+                let index = *nearest
+                    .entry(rgba)
+                    .or_insert_with(|| closest(&decoded, rgba, false));
+                (index as u32, decoded[index])
+            })
+        }
+    };
+    match format {
+        Format::CMPR => encode_cmpr(image),
+        _ => pack(&values, image.width, image.height, format),
+    }
+}
+
+/// Reduce each pixel to a format's value with `encode`, which also returns
+/// the colour the value decodes to, optionally dithering.
+///
+/// Returns one value per pixel, rows top to bottom.
+fn quantize(
+    image: &Image,
+    dither: bool,
+    mut encode: impl FnMut([u8; 4]) -> (u32, [u8; 4]),
+) -> Vec<u32> {
+    let width = image.width;
+    let mut values = Vec::with_capacity(width * image.height);
+    // errors carried to this row and the next, offset by one pixel
+    let mut errors = vec![[0.0f32; 4]; width + 2];
+    let mut next = vec![[0.0f32; 4]; width + 2];
+    for y in 0..image.height {
+        for x in 0..width {
+            let pixel = image.pixel(x, y);
+            let wanted =
+                std::array::from_fn(|channel| f32::from(pixel[channel]) + errors[x + 1][channel]);
+            let (value, actual) = encode(wanted.map(|value| value.round().clamp(0.0, 255.0) as u8));
+            values.push(value);
+            if dither {
+                for channel in 0..4 {
+                    let error = wanted[channel] - f32::from(actual[channel]);
+                    errors[x + 2][channel] += error * 7.0 / 16.0;
+                    next[x][channel] += error * 3.0 / 16.0;
+                    next[x + 1][channel] += error * 5.0 / 16.0;
+                    next[x + 2][channel] += error / 16.0;
+                }
+            }
+        }
+        errors = std::mem::replace(&mut next, vec![[0.0; 4]; width + 2]);
+    }
+    values
+}
+
+/// Lay out per-pixel values in blocks, padding partial blocks with the
+/// nearest edge pixel.
+fn pack(values: &[u32], width: usize, height: usize, format: Format) -> Vec<u8> {
+    let (block_width, block_height) = format.block_size();
+    let mut data = Vec::with_capacity(format.data_size(width, height));
+    for top in (0..height).step_by(block_height) {
+        for left in (0..width).step_by(block_width) {
+            let block = (0..block_width * block_height)
+                .map(|pixel| {
+                    let x = (left + pixel % block_width).min(width - 1);
+                    let y = (top + pixel / block_width).min(height - 1);
+                    values[y * width + x]
+                })
+                .collect::<Vec<_>>();
+            match format.bits_per_pixel() {
+                4 => data.extend(
+                    block
+                        .chunks_exact(2)
+                        .map(|pair| ((pair[0] << 4) | pair[1]) as u8),
+                ),
+                8 => data.extend(block.iter().map(|&value| value as u8)),
+                16 => data.extend(block.iter().flat_map(|&value| (value as u16).to_be_bytes())),
+                // RGBA8: alpha and red, then green and blue
+                _ => {
+                    let pixels = block.iter().map(|value| value.to_be_bytes());
+                    data.extend(pixels.clone().flat_map(|[r, _, _, a]| [a, r]));
+                    data.extend(pixels.flat_map(|[_, g, b, _]| [g, b]));
+                }
+            }
+        }
+    }
+    data
+}
+
+/// Squared distance between two colours, ignoring alpha if `opaque`.
+fn distance(a: [u8; 4], b: [u8; 4], opaque: bool) -> u32 {
+    let channels = if opaque { 3 } else { 4 };
+    (0..channels)
+        .map(|channel| {
+            let difference = i32::from(a[channel]) - i32::from(b[channel]);
+            (difference * difference) as u32
+        })
+        .sum()
+}
+
+/// Index of the colour in `colours` closest to `rgba`.
+fn closest(colours: &[[u8; 4]], rgba: [u8; 4], opaque: bool) -> usize {
+    (0..colours.len())
+        .min_by_key(|&index| distance(colours[index], rgba, opaque))
+        .expect("at least one colour")
+}
+
+/// Choose up to `max_colours` palette colours for an image.
+///
+/// Images with few enough colours (at the palette format's precision) get
+/// exactly those; otherwise the colours are split by median cut.
+fn build_palette(image: &Image, format: PaletteFormat, max_colours: usize) -> Vec<u16> {
+    let mut counts = HashMap::new();
+    for pixel in image.rgba.chunks_exact(4) {
+        let pixel = pixel.try_into().expect("4 bytes");
+        *counts.entry(format.encode(pixel)).or_insert(0usize) += 1;
+    }
+    let mut colours = counts.into_iter().collect::<Vec<_>>();
+    colours.sort_unstable();
+    if colours.len() <= max_colours {
+        return colours.into_iter().map(|(colour, _)| colour).collect();
+    }
+
+    let mut boxes = vec![colours
+        .into_iter()
+        .map(|(colour, count)| (format.decode(colour), count))
+        .collect::<Vec<_>>()];
+    while boxes.len() < max_colours {
+        // split the box with the widest channel at its weighted median
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colours)| colours.len() > 1)
+            .flat_map(|(index, colours)| {
+                (0..4).map(move |channel| {
+                    let values = colours.iter().map(|(colour, _)| colour[channel]);
+                    let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+                    (range, index, channel)
+                })
+            })
+            .max();
+        let Some((_, index, channel)) = widest else {
+            break;
+        };
+        let mut colours = boxes.swap_remove(index);
+        colours.sort_by_key(|(colour, _)| colour[channel]);
+        let total = colours.iter().map(|(_, count)| count).sum::<usize>();
+        let mut seen = 0;
+        let median = colours
+            .iter()
+            .position(|(_, count)| {
+                seen += count;
+                seen * 2 >= total
+            })
+            .unwrap_or(0);
+        let rest = colours.split_off((median + 1).clamp(1, colours.len() - 1));
+        boxes.push(colours);
+        boxes.push(rest);
+    }
+
+    let mut palette = boxes
+        .iter()
+        .map(|colours| {
+            let total = colours.iter().map(|(_, count)| count).sum::<usize>();
+            let average = std::array::from_fn(|channel| {
+                let sum = colours
+                    .iter()
+                    .map(|(colour, count)| usize::from(colour[channel]) * count)
+                    .sum::<usize>();
+                ((sum + total / 2) / total) as u8
+            });
+            format.encode(average)
+        })
+        .collect::<Vec<_>>();
+    palette.sort_unstable();
+    palette.dedup();
+    palette
+}
+
+/// Encode an image as `CMPR`: 8x8 blocks of four 4x4 sub-blocks.
+fn encode_cmpr(image: &Image) -> Vec<u8> {
+    let mut data = Vec::with_capacity(Format::CMPR.data_size(image.width, image.height));
+    for top in (0..image.height).step_by(8) {
+        for left in (0..image.width).step_by(8) {
+            for sub in 0..4 {
+                let (x, y) = (left + sub % 2 * 4, top + sub / 2 * 4);
+                let pixels = std::array::from_fn(|pixel| image.pixel(x + pixel % 4, y + pixel / 4));
+                data.extend(encode_cmpr_block(&pixels));
+            }
+        }
+    }
+    data
+}
+
+/// Pixels with less alpha than this are transparent in `CMPR`.
+const CMPR_ALPHA_THRESHOLD: u8 = 0x80;
+
+/// Encode a 4x4 sub-block.
+///
+/// Candidate endpoints come from the colours' principal axis and bounding
+/// box, then are refined by least squares. Each is tried in four and three
+/// colour mode, and whichever decodes closest to the pixels wins. Blocks
+/// with transparent pixels have to use three colour mode.
+fn encode_cmpr_block(pixels: &[[u8; 4]; 16]) -> [u8; 8] {
+    let opaque = pixels
+        .iter()
+        .filter(|pixel| pixel[3] >= CMPR_ALPHA_THRESHOLD)
+        .map(|pixel| pixel.map(f32::from))
+        .collect::<Vec<_>>();
+    if opaque.is_empty() {
+        return [0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+    }
+    let transparent = opaque.len() < 16;
+
+    let mut candidates = endpoint_candidates(&opaque);
+    let mut best: Option<(u32, [u8; 8])> = None;
+    let mut tried = 0;
+    while let Some(&(a, b)) = candidates.get(tried) {
+        tried += 1;
+        let (a, b) = (to_rgb565(rgb(a)), to_rgb565(rgb(b)));
+        for four in [true, false] {
+            if four && (transparent || a == b) {
+                continue;
+            }
+            // four colour mode needs the first colour greater, three colour
+            // mode the second
+            let (first, second) = if (a > b) == four { (a, b) } else { (b, a) };
+            let (error, block, indices) = cmpr_fit(pixels, first, second);
+            if best.is_none_or(|(best, _)| error < best) {
+                best = Some((error, block));
+            }
+            // refine the first candidates' endpoints, once
+            if tried <= 2 {
+                candidates.extend(refine(pixels, &indices, four));
+            }
+        }
+    }
+    best.expect("at least one candidate").1
+}
+
+fn rgb(colour: [f32; 3]) -> [u8; 4] {
+    let [r, g, b] = colour.map(|channel| channel.round().clamp(0.0, 255.0) as u8);
+    [r, g, b, 0xff]
+}
+
+/// Endpoints at the extremes of the colours' principal axis, and the
+/// corners of their bounding box.
+fn endpoint_candidates(colours: &[[f32; 4]]) -> Vec<([f32; 3], [f32; 3])> {
+    let count = colours.len() as f32;
+    let mean: [f32; 3] = std::array::from_fn(|channel| {
+        colours.iter().map(|colour| colour[channel]).sum::<f32>() / count
+    });
+    let mut covariance = [[0.0f32; 3]; 3];
+    for colour in colours {
+        for (i, row) in covariance.iter_mut().enumerate() {
+            for (j, cell) in row.iter_mut().enumerate() {
+                *cell += (colour[i] - mean[i]) * (colour[j] - mean[j]);
+            }
+        }
+    }
+    // power iteration for the principal axis
+    let mut axis = [1.0f32; 3];
+    for _ in 0..8 {
+        let next: [f32; 3] =
+            std::array::from_fn(|i| (0..3).map(|j| covariance[i][j] * axis[j]).sum());
+        let length = next.iter().map(|value| value * value).sum::<f32>().sqrt();
+        if length < 1e-6 {
+            break;
+        }
+        axis = next.map(|value| value / length);
+    }
+    let project = |colour: &[f32; 4]| {
+        (0..3)
+            .map(|channel| (colour[channel] - mean[channel]) * axis[channel])
+            .sum::<f32>()
+    };
+    let (mut low, mut high) = (f32::MAX, f32::MIN);
+    let (mut min, mut max) = ([f32::MAX; 3], [f32::MIN; 3]);
+    for colour in colours {
+        low = low.min(project(colour));
+        high = high.max(project(colour));
+        for channel in 0..3 {
+            min[channel] = min[channel].min(colour[channel]);
+            max[channel] = max[channel].max(colour[channel]);
+        }
+    }
+    let along = |t: f32| std::array::from_fn(|channel| mean[channel] + axis[channel] * t);
+    vec![(along(high), along(low)), (max, min)]
+}
+
+/// Index each pixel against a sub-block's colours, returning the squared
+/// error, the encoded sub-block and the indices.
+fn cmpr_fit(pixels: &[[u8; 4]; 16], first: u16, second: u16) -> (u32, [u8; 8], [u8; 16]) {
+    let colours = cmpr_palette(first, second);
+    let choices = if first > second { 4 } else { 3 };
+    let mut error = 0;
+    let mut indices = [0u8; 16];
+    for (pixel, index) in pixels.iter().zip(&mut indices) {
+        *index = if pixel[3] < CMPR_ALPHA_THRESHOLD {
+            3
+        } else {
+            let nearest = closest(&colours[..choices], *pixel, true);
+            error += distance(colours[nearest], *pixel, true);
+            nearest as u8
+        };
+    }
+    let mut block = [0; 8];
+    block[0..2].copy_from_slice(&first.to_be_bytes());
+    block[2..4].copy_from_slice(&second.to_be_bytes());
+    for (row, bits) in block[4..].iter_mut().enumerate() {
+        *bits = indices[row * 4..row * 4 + 4]
+            .iter()
+            .fold(0, |bits, index| (bits << 2) | index);
+    }
+    (error, block, indices)
+}
+
+/// The endpoints that best reproduce the pixels with the given indices,
+/// by least squares.
+fn refine(pixels: &[[u8; 4]; 16], indices: &[u8; 16], four: bool) -> Option<([f32; 3], [f32; 3])> {
+    // how much of the first endpoint each index's colour is
+    let weight = |index: u8| match (index, four) {
+        (0, _) => Some(1.0),
+        (1, _) => Some(0.0),
+        (2, true) => Some(2.0 / 3.0),
+        (3, true) => Some(1.0 / 3.0),
+        (2, false) => Some(0.5),
+        _ => None,
+    };
+    let (mut aa, mut ab, mut bb) = (0.0f32, 0.0f32, 0.0f32);
+    let (mut ax, mut bx) = ([0.0f32; 3], [0.0f32; 3]);
+    for (pixel, &index) in pixels.iter().zip(indices) {
+        let Some(t) = weight(index) else {
+            continue;
+        };
+        let s = 1.0 - t;
+        aa += t * t;
+        ab += t * s;
+        bb += s * s;
+        for channel in 0..3 {
+            ax[channel] += t * f32::from(pixel[channel]);
+            bx[channel] += s * f32::from(pixel[channel]);
+        }
+    }
+    let determinant = aa * bb - ab * ab;
+    if determinant.abs() < 1e-6 {
+        return None;
+    }
+    let a = std::array::from_fn(|channel| (ax[channel] * bb - bx[channel] * ab) / determinant);
+    let b = std::array::from_fn(|channel| (bx[channel] * aa - ax[channel] * ab) / determinant);
+    Some((a, b))
+}
+
+/// A JObj's flag for a spline in place of its DObjs.
+const JOBJ_SPLINE: u32 = 1 << 14;
+/// A JObj's flag for a particle generator in place of its DObjs.
+const JOBJ_PTCL: u32 = 1 << 5;
+
+/// An `ImageHeader` in a DAT: where a texture's data is, and its size and
+/// format.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageHeader {
+    /// Offset of the header in the data block.
+    pub offset: u32,
+    /// Offset of the image data in the data block.
+    pub data: u32,
+    pub width: usize,
+    pub height: usize,
+    pub format: Format,
+    /// Whether smaller mipmap levels follow the image.
+    pub mipmaps: bool,
+    pub max_lod: f32,
+}
+
+/// A `PaletteHeader` in a DAT, for paletted textures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaletteHeader {
+    /// Offset of the header in the data block.
+    pub offset: u32,
+    /// Offset of the colours in the data block.
+    pub data: u32,
+    pub format: PaletteFormat,
+    pub colours: usize,
+}
+
+/// A texture found in a DAT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DatTexture {
+    pub image: ImageHeader,
+    pub palette: Option<PaletteHeader>,
+}
+
+impl ImageHeader {
+    /// Parse the `ImageHeader` at `offset` in the data block.
+    ///
+    /// ```text
+    /// 0x00  image data pointer
+    /// 0x04  width (u16), height (u16)
+    /// 0x08  format
+    /// 0x0c  mipmap flag
+    /// 0x10  min LOD (f32)
+    /// 0x14  max LOD (f32)
+    /// ```
+    pub fn parse(dat: &DatFile, offset: u32) -> io::Result<ImageHeader> {
+        let data = dat
+            .read_pointer(offset)?
+            .ok_or_else(|| invalid(format!("image header at {offset:#x} has no data")))?;
+        let size = dat.read_u32(offset + 0x04)?;
+        let id = dat.read_u32(offset + 0x08)?;
+        let format = Format::from_id(id).ok_or_else(|| {
+            invalid(format!(
+                "image header at {offset:#x} has unknown format {id}"
+            ))
+        })?;
+        Ok(ImageHeader {
+            offset,
+            data,
+            width: (size >> 16) as usize,
+            height: (size & 0xffff) as usize,
+            format,
+            mipmaps: dat.read_u32(offset + 0x0c)? != 0,
+            max_lod: f32::from_bits(dat.read_u32(offset + 0x14)?),
+        })
+    }
+
+    /// Size of the image data, not counting any mipmaps.
+    pub fn data_size(&self) -> usize {
+        self.format.data_size(self.width, self.height)
+    }
+}
+
+impl PaletteHeader {
+    /// Parse the `PaletteHeader` at `offset` in the data block.
+    ///
+    /// ```text
+    /// 0x00  colour data pointer
+    /// 0x04  format
+    /// 0x08  TLUT name
+    /// 0x0c  colour count (u16)
+    /// ```
+    pub fn parse(dat: &DatFile, offset: u32) -> io::Result<PaletteHeader> {
+        let data = dat
+            .read_pointer(offset)?
+            .ok_or_else(|| invalid(format!("palette header at {offset:#x} has no data")))?;
+        let id = dat.read_u32(offset + 0x04)?;
+        let format = PaletteFormat::from_id(id).ok_or_else(|| {
+            invalid(format!(
+                "palette header at {offset:#x} has unknown format {id}"
+            ))
+        })?;
+        Ok(PaletteHeader {
+            offset,
+            data,
+            format,
+            colours: (dat.read_u32(offset + 0x0a)? & 0xffff) as usize,
+        })
+    }
+}
+
+/// Borrow `size` bytes at `offset` in the data block.
+fn slice(dat: &DatFile, offset: u32, size: usize) -> io::Result<&[u8]> {
+    dat.data
+        .get(offset as usize..offset as usize + size)
+        .ok_or_else(|| {
+            invalid(format!(
+                "{size:#x} bytes at {offset:#x} run past the end of the data block"
+            ))
+        })
+}
+
+impl DatTexture {
+    /// Read the texture's data and palette out of the archive.
+    pub fn read(&self, dat: &DatFile) -> io::Result<Texture> {
+        let image = &self.image;
+        let palette = match self.palette {
+            Some(palette) => slice(dat, palette.data, palette.colours * 2)?.to_vec(),
+            None => Vec::new(),
+        };
+        Ok(Texture {
+            format: image.format,
+            width: image.width,
+            height: image.height,
+            data: slice(dat, image.data, image.data_size())?.to_vec(),
+            palette_format: self
+                .palette
+                .map_or(PaletteFormat::RGB5A3, |palette| palette.format),
+            palette,
+        })
+    }
+
+    /// The file name DAT Texture Wizard exports the texture as: its data
+    /// offset, format and size, e.g. `0x1c3a0_CMPR_128x128.png`.
+    pub fn file_name(&self) -> String {
+        let image = &self.image;
+        format!(
+            "{:#x}_{}_{}x{}.png",
+            image.data, image.format, image.width, image.height
+        )
+    }
+}
+
+/// Is a root symbol a model's root JObj, e.g. `PlyCaptain5K_Share_joint`?
+fn is_joint(symbol: &str) -> bool {
+    symbol.ends_with("_joint") && !symbol.ends_with("anim_joint")
+}
+
+/// Find every texture in an archive, by data offset.
+///
+/// Walks the `*_joint` roots' JObj trees, their DObjs, MObjs and TObjs down
+/// to the image and palette headers. Textures sharing image data are only
+/// listed once. Textures only used by material animations aren't found.
+///
+/// ```text
+/// JObj  0x04 flags, 0x08 child, 0x0c next, 0x10 DObj
+/// DObj  0x04 next, 0x08 MObj
+/// MObj  0x08 TObj
+/// TObj  0x04 next, 0x4c ImageHeader, 0x50 PaletteHeader
+/// ```
+pub fn find(dat: &DatFile) -> io::Result<Vec<DatTexture>> {
+    textures(dat, &tobjs(dat)?)
+}
+
+/// The textures used by `tobjs`, like [`find`].
+fn textures(dat: &DatFile, tobjs: &[u32]) -> io::Result<Vec<DatTexture>> {
+    let mut textures = BTreeMap::new();
+    for &tobj in tobjs {
+        if let Some(header) = dat.read_pointer(tobj + 0x4c)? {
+            let image = ImageHeader::parse(dat, header)?;
+            let palette = dat
+                .read_pointer(tobj + 0x50)?
+                .map(|header| PaletteHeader::parse(dat, header))
+                .transpose()?;
+            textures
+                .entry(image.data)
+                .or_insert(DatTexture { image, palette });
+        }
+    }
+    Ok(textures.into_values().collect())
+}
+
+/// Every TObj reachable from the archive's `*_joint` roots.
+fn tobjs(dat: &DatFile) -> io::Result<Vec<u32>> {
+    let mut tobjs = Vec::new();
+    let mut seen = HashSet::new();
+    let mut joints = dat
+        .roots
+        .iter()
+        .filter(|node| is_joint(&node.symbol))
+        .map(|node| node.offset)
+        .collect::<Vec<_>>();
+    while let Some(joint) = joints.pop() {
+        if !seen.insert(joint) {
+            continue;
+        }
+        let flags = dat.read_u32(joint + 0x04)?;
+        joints.extend(dat.read_pointer(joint + 0x0c)?);
+        joints.extend(dat.read_pointer(joint + 0x08)?);
+        if flags & (JOBJ_SPLINE | JOBJ_PTCL) != 0 {
+            continue;
+        }
+
+        let mut dobj = dat.read_pointer(joint + 0x10)?;
+        while let Some(offset) = dobj.filter(|offset| seen.insert(*offset)) {
+            let mut tobj = match dat.read_pointer(offset + 0x08)? {
+                Some(mobj) => dat.read_pointer(mobj + 0x08)?,
+                None => None,
+            };
+            while let Some(offset) = tobj.filter(|offset| seen.insert(*offset)) {
+                tobjs.push(offset);
+                tobj = dat.read_pointer(offset + 0x04)?;
+            }
+            dobj = dat.read_pointer(offset + 0x04)?;
+        }
+    }
+    Ok(tobjs)
+}
+
+/// Export every texture in an archive to `dir` as PNGs, named by
+/// [`DatTexture::file_name`].
+///
+/// Returns the paths written.
+pub fn export<P: AsRef<Path>>(dat: &DatFile, dir: P) -> io::Result<Vec<PathBuf>> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir)?;
+    find(dat)?
+        .iter()
+        .map(|texture| {
+            let path = dir.join(texture.file_name());
+            let png = texture.read(dat)?.decode()?.to_png()?;
+            std::fs::write(&path, png)?;
+            debug!(path = %path.display(), "exported texture");
+            Ok(path)
+        })
+        .collect()
+}
+
+impl ImageHeader {
+    /// How many images are stored: 1, or the mipmap levels down to
+    /// `max_lod`.
+    pub fn levels(&self) -> usize {
+        match self.mipmaps {
+            true => (self.max_lod.max(0.0) as usize + 1).min(11),
+            false => 1,
+        }
+    }
+
+    /// Width and height of mipmap `level`.
+    fn level_size(&self, level: usize) -> (usize, usize) {
+        ((self.width >> level).max(1), (self.height >> level).max(1))
+    }
+
+    /// Size of the image data and all its mipmaps.
+    pub fn total_size(&self) -> usize {
+        (0..self.levels())
+            .map(|level| {
+                let (width, height) = self.level_size(level);
+                self.format.data_size(width, height)
+            })
+            .sum()
+    }
+}
+
+/// The texture offset a PNG is named for: `0x1c3a0_CMPR_128x128.png` is the
+/// texture at 0x1c3a0. Anything after the offset is ignored.
+pub fn offset_from_file_name(name: &str) -> Option<u32> {
+    FileName::parse(name).map(|name| name.offset)
+}
+
+/// What a PNG's name, like `0x1c3a0_CMPR_128x128.png`, says about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileName {
+    /// Offset of the texture data it replaces.
+    pub offset: u32,
+    pub format: Option<Format>,
+    /// Width and height.
+    pub size: Option<(usize, usize)>,
+}
+
+impl FileName {
+    /// Parse a PNG's file name; only the offset is required.
+    pub fn parse(name: &str) -> Option<FileName> {
+        let mut parts = name.strip_suffix(".png")?.split('_');
+        let hex = parts.next()?.strip_prefix("0x")?;
+        let offset = u32::from_str_radix(hex, 16).ok()?;
+        let format = parts.next().and_then(|format| format.parse().ok());
+        let size = parts.next().and_then(|size| {
+            let (width, height) = size.split_once('x')?;
+            Some((width.parse().ok()?, height.parse().ok()?))
+        });
+        Some(FileName {
+            offset,
+            format,
+            size,
+        })
+    }
+}
+
+/// Encode an image for `header`, followed by the mipmap levels it has,
+/// scaled down from the image. Every level shares the first's palette.
+fn encode_levels(
+    image: &Image,
+    header: &ImageHeader,
+    target: &Target,
+    options: &Options,
+) -> io::Result<Texture> {
+    let mut encoded = encode(image, target, options)?;
+    let colours = encoded
+        .palette
+        .chunks_exact(2)
+        .map(|colour| u16::from_be_bytes([colour[0], colour[1]]))
+        .collect::<Vec<_>>();
+    for level in 1..header.levels() {
+        let (width, height) = header.level_size(level);
+        let smaller = image.resize(width, height);
+        let target = Target {
+            width,
+            height,
+            ..*target
+        };
+        encoded
+            .data
+            .extend(encode_pixels(&smaller, &target, options, &colours));
+    }
+    Ok(encoded)
+}
+
+/// Largest width or height GX textures can have.
+pub const MAX_SIZE: usize = 1024;
+
+/// Replace the texture whose data is at `offset` with `image`, encoded as
+/// `target`, which can be a different size or format.
+///
+/// The new data is appended to the data block, and every `ImageHeader`
+/// using the texture is pointed at it and given the new size and format.
+/// Palettes are appended and pointed at too, and TObjs that need a
+/// `PaletteHeader` but don't have one get a new one. The old data is left
+/// where it was, unused.
+///
+/// Returns the offset of the new data.
+pub fn relocate(
+    dat: &mut DatFile,
+    offset: u32,
+    image: &Image,
+    target: &Target,
+    options: &Options,
+) -> io::Result<u32> {
+    let tobjs = tobjs(dat)?;
+    relocate_in(dat, &tobjs, offset, image, target, options)
+}
+
+/// [`relocate`], given every TObj in the archive.
+fn relocate_in(
+    dat: &mut DatFile,
+    tobjs: &[u32],
+    offset: u32,
+    image: &Image,
+    target: &Target,
+    options: &Options,
+) -> io::Result<u32> {
+    if target.width > MAX_SIZE || target.height > MAX_SIZE {
+        return Err(invalid(format!(
+            "{}x{} is larger than GX textures can be ({MAX_SIZE}x{MAX_SIZE})",
+            target.width, target.height
+        )));
+    }
+
+    // the TObjs using the texture, and their image headers
+    let mut users = Vec::new();
+    let mut headers: Vec<ImageHeader> = Vec::new();
+    for &tobj in tobjs {
+        let Some(header) = dat.read_pointer(tobj + 0x4c)? else {
+            continue;
+        };
+        let header = ImageHeader::parse(dat, header)?;
+        if header.data == offset {
+            users.push(tobj);
+            if !headers.iter().any(|seen| seen.offset == header.offset) {
+                headers.push(header);
+            }
+        }
+    }
+    let Some(first) = headers.first() else {
+        return Err(invalid(format!("no texture at {offset:#x}")));
+    };
+
+    let resized = ImageHeader {
+        width: target.width,
+        height: target.height,
+        format: target.format,
+        ..*first
+    };
+    let encoded = encode_levels(image, &resized, target, options)
+        .map_err(|error| invalid(format!("texture at {offset:#x}: {error}")))?;
+    let data = dat.append(&encoded.data, 32);
+    for header in &headers {
+        dat.set_pointer(header.offset, data)?;
+        let size = ((target.width << 16) | target.height) as u32;
+        dat.write_u32(header.offset + 0x04, size)?;
+        dat.write_u32(header.offset + 0x08, target.format.id())?;
+    }
+
+    if target.format.is_paletted() {
+        let colours = dat.append(&encoded.palette, 32);
+        let count = (encoded.palette.len() / 2) as u32;
+        let mut created = None;
+        for tobj in &users {
+            let header = match (dat.read_pointer(tobj + 0x50)?, created) {
+                (Some(header), _) | (None, Some(header)) => header,
+                (None, None) => {
+                    let header = dat.append(&[0; 0x10], 4);
+                    created = Some(header);
+                    header
+                }
+            };
+            dat.set_pointer(tobj + 0x50, header)?;
+            dat.set_pointer(header, colours)?;
+            dat.write_u32(header + 0x04, target.palette_format.id())?;
+            let padding = dat.read_u32(header + 0x0c)? & 0xffff;
+            dat.write_u32(header + 0x0c, (count << 16) | padding)?;
+        }
+    } else {
+        for tobj in &users {
+            if dat.is_pointer(tobj + 0x50) {
+                dat.clear_pointer(tobj + 0x50)?;
+            }
+        }
+    }
+    debug!(
+        offset,
+        new_offset = data,
+        format = %target.format,
+        width = target.width,
+        height = target.height,
+        "relocated texture"
+    );
+    Ok(data)
+}
+
+/// Replace the texture whose data is at `offset` with `image`, in place.
+///
+/// The image is encoded in the texture's original format and size, with no
+/// more palette colours than it had; images of a different size are refused
+/// unless [`Options::resize`] is set. Mipmaps are regenerated from the image.
+pub fn inject(dat: &mut DatFile, offset: u32, image: &Image, options: &Options) -> io::Result<()> {
+    let found = find(dat)?
+        .into_iter()
+        .find(|texture| texture.image.data == offset)
+        .ok_or_else(|| invalid(format!("no texture at {offset:#x}")))?;
+    inject_found(dat, &found, image, options)
+}
+
+/// [`inject`], given the texture found at the offset.
+fn inject_found(
+    dat: &mut DatFile,
+    found: &DatTexture,
+    image: &Image,
+    options: &Options,
+) -> io::Result<()> {
+    let offset = found.image.data;
+    let header = found.image;
+    let target = Target::matching(&found.read(dat)?);
+    let encoded = encode_levels(image, &header, &target, options)
+        .map_err(|error| invalid(format!("texture at {offset:#x}: {error}")))?;
+    let data = encoded.data;
+
+    let space = header.total_size();
+    if data.len() > space {
+        return Err(invalid(format!(
+            "texture at {offset:#x} needs {:#x} bytes, but only has {space:#x}",
+            data.len()
+        )));
+    }
+    dat.data
+        .get_mut(offset as usize..offset as usize + data.len())
+        .ok_or_else(|| {
+            invalid(format!(
+                "texture at {offset:#x} runs past the end of the data block"
+            ))
+        })?
+        .copy_from_slice(&data);
+
+    if let Some(palette) = found.palette {
+        let mut colours = encoded.palette;
+        colours.resize(palette.colours * 2, 0);
+        let start = palette.data as usize;
+        dat.data
+            .get_mut(start..start + colours.len())
+            .ok_or_else(|| {
+                invalid(format!(
+                    "palette at {start:#x} runs past the end of the data block"
+                ))
+            })?
+            .copy_from_slice(&colours);
+    }
+    debug!(offset, format = %header.format, width = header.width, height = header.height, "injected texture");
+    Ok(())
+}
+
+/// Inject every PNG in `dir` named by texture offset, as
+/// [`DatTexture::file_name`] names them.
+///
+/// PNGs named with the texture's own format and size (or just its offset)
+/// are injected in place with [`inject`]. Renaming a PNG to another format
+/// or size, e.g. `0x1c3a0_CMPR_256x256.png` for a 128x128 texture, moves
+/// the texture with [`relocate`] instead.
+///
+/// Returns the offsets of the textures replaced.
+pub fn inject_dir<P: AsRef<Path>>(
+    dat: &mut DatFile,
+    dir: P,
+    options: &Options,
+) -> io::Result<Vec<u32>> {
+    let mut pngs = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    pngs.sort();
+
+    // textures keep their TObjs and headers when others are replaced, so
+    // the archive is only walked once
+    let tobjs = tobjs(dat)?;
+    let mut textures = textures(dat, &tobjs)?
+        .into_iter()
+        .map(|texture| (texture.image.data, texture))
+        .collect::<HashMap<_, _>>();
+
+    let mut injected = Vec::new();
+    for path in pngs {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let Some(name) = FileName::parse(&name) else {
+            debug!(path = %path.display(), "skipping file not named by texture offset");
+            continue;
+        };
+        let image = Image::from_png(&std::fs::read(&path)?)
+            .map_err(|error| invalid(format!("{}: {error}", path.display())))?;
+
+        let offset = name.offset;
+        let found = *textures
+            .get(&offset)
+            .ok_or_else(|| invalid(format!("{}: no texture at {offset:#x}", path.display())))?;
+        let original = found.image;
+        let format = name.format.unwrap_or(original.format);
+        let (width, height) = name.size.unwrap_or((original.width, original.height));
+        if (format, width, height) == (original.format, original.width, original.height) {
+            inject_found(dat, &found, &image, options)?;
+        } else {
+            let target = Target {
+                palette_format: found
+                    .palette
+                    .map_or(PaletteFormat::RGB5A3, |palette| palette.format),
+                ..Target::new(format, width, height)
+            };
+            relocate_in(dat, &tobjs, offset, &image, &target, options)?;
+            // its data isn't at `offset` any more
+            textures.remove(&offset);
+        }
+        injected.push(offset);
+    }
+    Ok(injected)
+}
@@ -0,0 +1,26 @@
+//! Sizes and hashes of the files in vanilla v1.02 NTSC GALE01, keyed by FST path.
+//!
+//! No table ships with the crate. Generate one from a verified image with:
+//!
+//! ```text
+//! cargo run -p melee_inject_codegen -- vanilla-hashes ssbm.iso
+//! ```
+//!
+//! and compare a disc against it with [`super::disc::Disc::modified_files_from`].
+
+/// A single file in the vanilla filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VanillaFile {
+    pub path: &'static str,
+    pub size: u32,
+    pub crc32: u32,
+    pub sha1: &'static str,
+}
+
+/// Look up a file by full FST path in a table sorted by path.
+pub fn find<'a>(table: &'a [VanillaFile], path: &str) -> Option<&'a VanillaFile> {
+    table
+        .binary_search_by(|file| file.path.cmp(path))
+        .ok()
+        .map(|index| &table[index])
+}
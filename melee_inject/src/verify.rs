@@ -0,0 +1,282 @@
+//! Check disc images against known vanilla dumps.
+//!
+//! Everything else in this crate assumes v1.02 NTSC GALE01. Verifying the
+//! input first turns a confusing broken build into a clear error.
+//!
+//! Only v1.02 NTSC is known so far. Dumps of other revisions and regions
+//! (v1.00, v1.01, PAL) are recognized as Melee by their header, and reported
+//! as [`Verification::UnknownRevision`], since they can't be checked.
+use super::disc::{Disc, Reader};
+use super::formats;
+use md5::Md5;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::SystemTime;
+use tracing::debug;
+
+/// A known-good disc image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownImage {
+    pub game_id: &'static str,
+    pub revision: u8,
+    pub description: &'static str,
+    pub md5: &'static str,
+    pub sha1: &'static str,
+}
+
+/// Known vanilla dumps, as listed by Redump.
+///
+/// Only v1.02 NTSC is listed so far. Add other revisions here as their
+/// hashes are confirmed.
+pub const KNOWN_IMAGES: &[KnownImage] = &[KnownImage {
+    game_id: "GALE01",
+    revision: 2,
+    description: "Super Smash Bros. Melee (USA) (En,Ja) (Rev 2) [v1.02]",
+    md5: "0e63d4223b01d9aba596259dc155a174",
+    sha1: "d4e70c064cc714ba8400a849cf299dbd1aa326fc",
+}];
+
+/// Game IDs of Super Smash Bros. Melee releases.
+pub const MELEE_GAME_IDS: &[&str] = &["GALE01", "GALP01", "GALJ01"];
+
+/// CRC32, and hex encoded MD5 and SHA-1 digests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hashes {
+    pub crc32: u32,
+    pub md5: String,
+    pub sha1: String,
+}
+
+/// Result of verifying a disc image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verification {
+    /// Matches a known vanilla image.
+    Vanilla(&'static KnownImage),
+    /// A known Melee revision, with different contents: modded.
+    Modified {
+        game_id: String,
+        revision: u8,
+        hashes: Hashes,
+    },
+    /// Melee, but a revision or region with no known hashes, so it may or may
+    /// not be vanilla.
+    UnknownRevision {
+        game_id: String,
+        revision: u8,
+        hashes: Hashes,
+    },
+    /// Not a Melee disc image.
+    Unknown {
+        game_id: String,
+        revision: u8,
+        hashes: Hashes,
+    },
+    /// An NKit image, which never hashes like a vanilla dump (see
+    /// [`formats::is_nkit`]).
+    NKit { game_id: String, revision: u8 },
+}
+
+impl Verification {
+    /// Is this a known vanilla v1.02 NTSC GALE01 image?
+    pub fn is_supported(&self) -> bool {
+        matches!(
+            self,
+            Verification::Vanilla(KnownImage {
+                game_id: "GALE01",
+                revision: 2,
+                ..
+            })
+        )
+    }
+}
+
+/// How to treat an image that isn't a vanilla v1.02 NTSC GALE01 image.
+///
+/// This includes other revisions and regions, vanilla or not.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    /// Return an error.
+    Refuse,
+    /// Continue, reporting a warning.
+    #[default]
+    Warn,
+    /// Don't verify the image at all.
+    Skip,
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Hash everything left in a reader, in chunks.
+pub fn hash_reader<R: Read>(mut reader: R) -> io::Result<Hashes> {
+    let mut crc32 = crc32fast::Hasher::new();
+    let mut md5 = Md5::new();
+    let mut sha1 = Sha1::new();
+    let mut buffer = vec![0; 0x100000];
+    loop {
+        match reader.read(&mut buffer)? {
+            0 => break,
+            read => {
+                crc32.update(&buffer[..read]);
+                md5.update(&buffer[..read]);
+                sha1.update(&buffer[..read]);
+            }
+        }
+    }
+
+    Ok(Hashes {
+        crc32: crc32.finalize(),
+        md5: hex(&md5.finalize()),
+        sha1: hex(&sha1.finalize()),
+    })
+}
+
+/// Hash a file on disk.
+pub fn hash_file<P: AsRef<Path>>(path: P) -> io::Result<Hashes> {
+    hash_reader(std::fs::File::open(path)?)
+}
+
+/// Hash the uncompressed contents of a disc image, raw or compressed.
+pub fn hash_image<P: AsRef<Path>>(path: P) -> io::Result<Hashes> {
+    let image = formats::open(path)?;
+    hash_reader(Reader::new(&*image)?)
+}
+
+/// Read the game ID (0x00 -> 0x06) and revision (0x07) from the disc header.
+pub fn read_header<R: Read + Seek>(iso: &mut R) -> io::Result<(String, u8)> {
+    let mut header = [0; 8];
+    iso.seek(SeekFrom::Start(0))?;
+    iso.read_exact(&mut header)?;
+    Ok((
+        String::from_utf8_lossy(&header[..6]).into_owned(),
+        header[7],
+    ))
+}
+
+/// An image on disk, as of its last modification.
+type Identity = (PathBuf, u64, SystemTime);
+
+/// Images already verified, so planning a build again (or building after a
+/// dry run) doesn't hash the whole image again.
+static VERIFIED: Mutex<Vec<(Identity, Verification)>> = Mutex::new(Vec::new());
+
+/// Hash a disc image and compare it against [`KNOWN_IMAGES`].
+///
+/// Compressed images are hashed as if they were uncompressed. Results are
+/// remembered by path, size and modification time, so an unchanged image is
+/// only hashed once per process.
+pub fn verify<P: AsRef<Path>>(path: P) -> io::Result<Verification> {
+    let metadata = std::fs::metadata(&path)?;
+    let identity = (
+        std::fs::canonicalize(&path)?,
+        metadata.len(),
+        metadata.modified()?,
+    );
+    let verified = VERIFIED.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some((_, verification)) = verified.iter().find(|(known, _)| *known == identity) {
+        debug!(iso = %path.as_ref().display(), "reusing verification");
+        return Ok(verification.clone());
+    }
+    drop(verified);
+
+    let verification = verify_uncached(path)?;
+    VERIFIED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push((identity, verification.clone()));
+    Ok(verification)
+}
+
+fn verify_uncached<P: AsRef<Path>>(path: P) -> io::Result<Verification> {
+    let image = formats::open(path)?;
+    let mut iso = Reader::new(&*image)?;
+    let (game_id, revision) = read_header(&mut iso)?;
+    if formats::is_nkit(&*image)? {
+        return Ok(Verification::NKit { game_id, revision });
+    }
+    iso.seek(SeekFrom::Start(0))?;
+    let hashes = hash_reader(&mut iso)?;
+
+    if let Some(known) = KNOWN_IMAGES
+        .iter()
+        .find(|known| known.md5 == hashes.md5 && known.sha1 == hashes.sha1)
+    {
+        return Ok(Verification::Vanilla(known));
+    }
+
+    let known_revision = KNOWN_IMAGES
+        .iter()
+        .any(|known| known.game_id == game_id && known.revision == revision);
+    Ok(if known_revision {
+        Verification::Modified {
+            game_id,
+            revision,
+            hashes,
+        }
+    } else if MELEE_GAME_IDS.contains(&game_id.as_str()) {
+        Verification::UnknownRevision {
+            game_id,
+            revision,
+            hashes,
+        }
+    } else {
+        Verification::Unknown {
+            game_id,
+            revision,
+            hashes,
+        }
+    })
+}
+
+/// Verify a disc image, handling unsupported images according to `policy`.
+///
+/// Returns a warning message for unsupported images under [`Policy::Warn`].
+pub fn check<P: AsRef<Path>>(path: P, policy: Policy) -> io::Result<Option<String>> {
+    if policy == Policy::Skip {
+        return Ok(None);
+    }
+
+    let verification = verify(&path)?;
+    debug!(iso = %path.as_ref().display(), ?verification, "verified image");
+    if verification.is_supported() {
+        return Ok(None);
+    }
+
+    let message = match verification {
+        Verification::NKit { .. } => format!(
+            "{} is an NKit image: files are not at vanilla offsets, restore it to a full ISO with NKit first",
+            path.as_ref().display()
+        ),
+        Verification::UnknownRevision {
+            game_id, revision, ..
+        } => format!(
+            "{} is {game_id} revision {revision}, which can't be verified: only v1.02 NTSC GALE01 is supported",
+            path.as_ref().display()
+        ),
+        _ => format!(
+            "{} is not a vanilla v1.02 NTSC GALE01 image: {verification:?}",
+            path.as_ref().display()
+        ),
+    };
+    match policy {
+        Policy::Refuse => Err(io::Error::new(io::ErrorKind::InvalidData, message)),
+        _ => Ok(Some(message)),
+    }
+}
+
+/// Hash every file in the disc filesystem, in FST order.
+///
+/// Returns the full FST path of each file with its hashes.
+pub fn file_hashes<P: AsRef<Path>>(path: P) -> io::Result<Vec<(String, Hashes)>> {
+    let disc = Disc::open(path)?;
+    let hashes = disc.file_hashes()?;
+    Ok(hashes
+        .into_iter()
+        .map(|(file, hashes)| (file.path.clone(), hashes))
+        .collect())
+}
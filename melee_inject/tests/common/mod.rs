@@ -0,0 +1,160 @@
+//! Small synthetic disc images, for tests that don't need a real ISO.
+#![allow(dead_code)]
+
+use melee_inject::fst::{Entry, Fst, FST_LENGTH, FST_OFFSET};
+use std::path::PathBuf;
+
+/// Where the first file starts in a synthetic image.
+pub const DATA_OFFSET: u32 = 0x460000;
+
+/// Build a disc image with a GALE01 header and the given root files.
+///
+/// Files are laid out back to back from [`DATA_OFFSET`], 4 byte aligned.
+pub fn synthetic_image(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut image = vec![0; FST_OFFSET as usize];
+    image[0..6].copy_from_slice(b"GALE01");
+    image[7] = 2;
+    image[0x1c..0x20].copy_from_slice(&0xc2339f3du32.to_be_bytes());
+    image[0x424..0x428].copy_from_slice(&(FST_OFFSET as u32).to_be_bytes());
+    image[0x428..0x42c].copy_from_slice(&(FST_LENGTH as u32).to_be_bytes());
+    image[0x42c..0x430].copy_from_slice(&(FST_LENGTH as u32).to_be_bytes());
+
+    let mut entries = vec![Entry::Directory {
+        name: String::new(),
+        parent: 0,
+        next: files.len() as u32 + 1,
+    }];
+    let mut offset = DATA_OFFSET;
+    for (name, data) in files {
+        entries.push(Entry::File {
+            name: name.to_string(),
+            offset,
+            size: data.len() as u32,
+        });
+        offset += (data.len() as u32 + 3) & !3;
+    }
+
+    let mut fst = Fst { entries }.to_bytes();
+    fst.resize(FST_LENGTH as usize, 0);
+    image.extend(fst);
+
+    image.resize(DATA_OFFSET as usize, 0);
+    for (_, data) in files {
+        image.extend(*data);
+        image.resize((image.len() + 3) & !3, 0);
+    }
+
+    image
+}
+
+/// Write `data` to a fresh file in the temporary directory, returning its path.
+pub fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("melee-inject-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("failed to create temp dir");
+    let path = dir.join(name);
+    std::fs::write(&path, data).expect("failed to write temp file");
+    path
+}
+
+/// Build an HSD archive from a data block, its pointer offsets, and root nodes.
+pub fn synthetic_dat(data: &[u8], relocations: &[u32], roots: &[(u32, &str)]) -> Vec<u8> {
+    let mut strings = Vec::new();
+    let mut nodes = Vec::new();
+    for (offset, symbol) in roots {
+        nodes.extend(offset.to_be_bytes());
+        nodes.extend((strings.len() as u32).to_be_bytes());
+        strings.extend(symbol.as_bytes());
+        strings.push(0);
+    }
+
+    let mut dat = vec![0; 0x20];
+    dat.extend(data);
+    relocations
+        .iter()
+        .for_each(|offset| dat.extend(offset.to_be_bytes()));
+    dat.extend(nodes);
+    dat.extend(strings);
+
+    let size = dat.len() as u32;
+    dat[0x00..0x04].copy_from_slice(&size.to_be_bytes());
+    dat[0x04..0x08].copy_from_slice(&(data.len() as u32).to_be_bytes());
+    dat[0x08..0x0c].copy_from_slice(&(relocations.len() as u32).to_be_bytes());
+    dat[0x0c..0x10].copy_from_slice(&(roots.len() as u32).to_be_bytes());
+    dat
+}
+
+/// Write a big endian word into a data block.
+fn put(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+/// An HSD archive with a model holding two textures.
+///
+/// ```text
+/// 0x000  JObj, child 0x140, DObj 0x040
+/// 0x040  DObj, MObj 0x050
+/// 0x050  MObj, TObj 0x070
+/// 0x070  TObj, next 0x0d0, image 0x220
+/// 0x0d0  TObj, image 0x220 (the same texture again)
+/// 0x140  JObj, DObj 0x180
+/// 0x180  DObj, MObj 0x190
+/// 0x190  MObj, TObj 0x1b0
+/// 0x1b0  TObj, image 0x240, palette 0x260
+/// 0x220  ImageHeader: 0x280, 8x8 CMPR
+/// 0x240  ImageHeader: 0x2a0, 8x8 CI4
+/// 0x260  PaletteHeader: 0x2c0, RGB565, 16 colours
+/// 0x280  CMPR data: red, green, blue and white quarters
+/// 0x2a0  CI4 data: each row indices 0 to 7
+/// 0x2c0  palette: shades of red
+/// ```
+pub fn textured_dat() -> Vec<u8> {
+    let mut data = vec![0; 0x2e0];
+    let pointers = [
+        (0x008, 0x140),
+        (0x010, 0x040),
+        (0x048, 0x050),
+        (0x058, 0x070),
+        (0x074, 0x0d0),
+        (0x0bc, 0x220),
+        (0x11c, 0x220),
+        (0x150, 0x180),
+        (0x188, 0x190),
+        (0x198, 0x1b0),
+        (0x1fc, 0x240),
+        (0x200, 0x260),
+        (0x220, 0x280),
+        (0x240, 0x2a0),
+        (0x260, 0x2c0),
+    ];
+    for (offset, target) in pointers {
+        put(&mut data, offset, target);
+    }
+
+    put(&mut data, 0x224, 8 << 16 | 8);
+    put(&mut data, 0x228, 14);
+    put(&mut data, 0x244, 8 << 16 | 8);
+    put(&mut data, 0x248, 8);
+    put(&mut data, 0x264, 1);
+    put(&mut data, 0x26c, 16 << 16);
+
+    for (block, colour) in [0xf800u32, 0x07e0, 0x001f, 0xffff].into_iter().enumerate() {
+        put(&mut data, 0x280 + block * 8, colour << 16);
+    }
+    for row in 0..8 {
+        data[0x2a0 + row * 4..0x2a4 + row * 4].copy_from_slice(&[0x01, 0x23, 0x45, 0x67]);
+    }
+    for index in 0..16 {
+        data[0x2c0 + index * 2..0x2c2 + index * 2]
+            .copy_from_slice(&((index as u16) << 11).to_be_bytes());
+    }
+
+    let relocations = pointers.map(|(offset, _)| offset as u32);
+    synthetic_dat(
+        &data,
+        &relocations,
+        &[
+            (0x000, "PlyFox5K_Share_joint"),
+            (0x140, "PlyFox5K_Share_matanim_joint"),
+        ],
+    )
+}
@@ -0,0 +1,232 @@
+mod common;
+
+use common::synthetic_dat;
+use melee_inject::dat::{self, DatFile, Slot};
+use std::io;
+
+/// A data block with a root structure pointing at a second structure.
+fn data() -> Vec<u8> {
+    let mut data = vec![0; 0x20];
+    // root structure at 0x00: pointer to 0x10, then a pointer to 0x00
+    data[0x00..0x04].copy_from_slice(&0x10u32.to_be_bytes());
+    // second structure at 0x10
+    data[0x10..0x14].copy_from_slice(&0xdeadbeefu32.to_be_bytes());
+    data
+}
+
+#[test]
+fn parse_archive() {
+    let bytes = synthetic_dat(
+        &data(),
+        &[0x00, 0x04],
+        &[(0x00, "ftDataCaptain"), (0x10, "PlyCaptain5K_Share_joint")],
+    );
+    let dat = DatFile::parse(&bytes).expect("parse");
+
+    assert_eq!(dat.header.file_size as usize, bytes.len());
+    assert_eq!(dat.header.data_size, 0x20);
+    assert_eq!(dat.relocations(), [0x00, 0x04]);
+    let symbols = dat
+        .roots
+        .iter()
+        .map(|node| node.symbol.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(symbols, ["ftDataCaptain", "PlyCaptain5K_Share_joint"]);
+    assert!(dat.references.is_empty());
+
+    let root = dat.root("ftDataCaptain").expect("root");
+    let child = dat
+        .read_pointer(root.offset)
+        .expect("read")
+        .expect("pointer");
+    assert_eq!(child, 0x10);
+    assert_eq!(dat.read_u32(child).expect("read"), 0xdeadbeef);
+
+    // a pointer to the start of the data block, not a null pointer
+    assert!(dat.is_pointer(0x04));
+    assert_eq!(dat.read_pointer(0x04).expect("read"), Some(0));
+    // not relocated: plain data
+    assert_eq!(dat.read_pointer(0x10).expect("read"), None);
+}
+
+#[test]
+fn reject_truncated_archive() {
+    let bytes = synthetic_dat(&data(), &[0x00], &[(0x00, "ftDataCaptain")]);
+
+    let error = DatFile::parse(&bytes[..bytes.len() - 4]).expect_err("truncated");
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert!(DatFile::parse(&bytes[..0x10]).is_err());
+}
+
+#[test]
+fn reject_pointer_outside_data() {
+    let mut data = data();
+    data[0x04..0x08].copy_from_slice(&0x1000u32.to_be_bytes());
+    let bytes = synthetic_dat(&data, &[0x04], &[]);
+
+    let dat = DatFile::parse(&bytes).expect("parse");
+    assert!(dat.read_pointer(0x04).is_err());
+}
+
+#[test]
+fn write_unchanged_archive() {
+    let mut bytes = synthetic_dat(&data(), &[0x04, 0x00], &[(0x00, "ftDataCaptain")]);
+    // a version string in the header, and padding after the string table
+    bytes[0x14..0x18].copy_from_slice(b"001B");
+    bytes.extend([0; 3]);
+    let size = bytes.len() as u32;
+    bytes[0..4].copy_from_slice(&size.to_be_bytes());
+
+    let dat = DatFile::parse(&bytes).expect("parse");
+    assert_eq!(dat.to_bytes(), bytes);
+}
+
+#[test]
+fn write_edited_archive() {
+    let bytes = synthetic_dat(&data(), &[0x00], &[(0x00, "PlyCaptain5KGr_TopN")]);
+    let mut dat = DatFile::parse(&bytes).expect("parse");
+
+    let appended = dat.append(&0xcafef00du32.to_be_bytes(), 0x20);
+    assert_eq!(appended, 0x20);
+    dat.set_pointer(0x08, appended).expect("set pointer");
+    dat.clear_pointer(0x00).expect("clear pointer");
+    dat.roots[0].symbol = "PlyCaptain5KNr_TopN".to_string();
+    dat.references.push(melee_inject::dat::Node {
+        offset: 0x0c,
+        symbol: "ftDataCaptain".to_string(),
+        name_offset: 0,
+    });
+
+    let dat = DatFile::parse(&dat.to_bytes()).expect("reparse");
+    assert_eq!(dat.relocations(), [0x08]);
+    assert_eq!(dat.read_pointer(0x08).expect("read"), Some(0x20));
+    assert_eq!(dat.read_u32(0x20).expect("read"), 0xcafef00d);
+    assert_eq!(dat.read_u32(0x00).expect("read"), 0);
+    assert_eq!(dat.roots[0].symbol, "PlyCaptain5KNr_TopN");
+    assert_eq!(dat.references[0].symbol, "ftDataCaptain");
+    assert_eq!(dat.header.data_size, 0x24);
+
+    assert!(DatFile::parse(&bytes)
+        .expect("parse")
+        .set_pointer(0x00, 0x1000)
+        .is_err());
+}
+
+#[test]
+fn costume_slots() {
+    let slot = Slot::from_file_name("PlCaGr.dat").expect("slot");
+    assert_eq!(slot.character(), "Captain");
+    assert_eq!(slot.symbol_prefix(), "PlyCaptain5KGr");
+    assert_eq!(
+        Slot::from_file_name("PlCaNr.dat")
+            .expect("slot")
+            .symbol_prefix(),
+        "PlyCaptain5K"
+    );
+    assert_eq!(Slot::from_file_name("PlCa.dat"), None);
+    assert_eq!(Slot::from_file_name("PlKbCpFx.dat"), None);
+    assert_eq!(
+        dat::shared_symbol("PlCa.dat").as_deref(),
+        Some("ftDataCaptain")
+    );
+}
+
+#[test]
+fn validate_archives() {
+    let green = synthetic_dat(&data(), &[0x00], &[(0x00, "PlyCaptain5KGr_Share_joint")]);
+    assert!(dat::validate(&green, "PlCaGr.dat").is_empty());
+
+    // a green costume in the neutral slot
+    let problems = dat::validate(&green, "PlCaNr.dat");
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].contains("not PlCaNr.dat"), "{problems:?}");
+
+    // a costume in place of the shared file
+    assert_eq!(dat::validate(&green, "PlCa.dat").len(), 1);
+
+    let mut broken = data();
+    broken[0x00..0x04].copy_from_slice(&0x100u32.to_be_bytes());
+    let broken = synthetic_dat(
+        &broken,
+        &[0x00, 0x40],
+        &[(0x00, "PlyCaptain5KGr_Share_joint")],
+    );
+    assert_eq!(dat::validate(&broken, "PlCaGr.dat").len(), 2);
+
+    let truncated = dat::validate(&green[..green.len() - 1], "PlCaGr.dat");
+    assert!(truncated[0].starts_with("not a valid HSD archive"));
+
+    let mut padded = green.clone();
+    padded.extend([0; 0x20]);
+    assert_eq!(dat::validate(&padded, "PlCaGr.dat").len(), 1);
+}
+
+#[test]
+fn detect_and_rename_costume() {
+    assert_eq!(
+        Slot::from_symbol("PlyCaptain5KGr_TopN"),
+        Slot::from_file_name("PlCaGr.dat")
+    );
+    assert_eq!(
+        Slot::from_symbol("PlyCaptain5K_Share_joint"),
+        Slot::from_file_name("PlCaNr.dat")
+    );
+    assert_eq!(Slot::from_symbol("ftDataCaptain"), None);
+    assert_eq!(Slot::from_symbol("PlyCaptain5KGreen_TopN"), None);
+
+    let bytes = synthetic_dat(
+        &data(),
+        &[0x00],
+        &[
+            (0x00, "PlyCaptain5KGr_Share_joint"),
+            (0x10, "PlyCaptain5KGr_TopN"),
+        ],
+    );
+    let mut dat = DatFile::parse(&bytes).expect("parse");
+    let green = dat.costume_slot().expect("costume");
+    let neutral = Slot::from_file_name("PlCaNr.dat").expect("slot");
+    assert_eq!(green.file_name(), "PlCaGr.dat");
+
+    let problems = dat::validate(&bytes, "PlCaNr.dat");
+    assert!(
+        problems[0].starts_with("made for PlCaGr.dat"),
+        "{problems:?}"
+    );
+
+    assert_eq!(dat.rename_costume(&green, &neutral), 2);
+    let renamed = dat.to_bytes();
+    assert_eq!(renamed.len(), bytes.len() - 4);
+    assert!(dat::validate(&renamed, "PlCaNr.dat").is_empty());
+    assert_eq!(
+        DatFile::parse(&renamed).expect("parse").roots[1].symbol,
+        "PlyCaptain5K_TopN"
+    );
+}
+
+#[test]
+fn convert_costume_between_slots() {
+    let orange = synthetic_dat(
+        &data(),
+        &[0x00],
+        &[(0x00, "PlyFox5KOr_Share_joint"), (0x10, "PlyFox5KOr_TopN")],
+    );
+
+    let neutral = dat::convert_costume(&orange, "PlFxOr.dat", "PlFxNr").expect("convert");
+    let converted = DatFile::parse(&neutral).expect("parse");
+    let symbols = converted
+        .roots
+        .iter()
+        .map(|node| node.symbol.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(symbols, ["PlyFox5K_Share_joint", "PlyFox5K_TopN"]);
+    assert_eq!(converted.data, data());
+    assert!(dat::validate(&neutral, "PlFxNr.dat").is_empty());
+
+    // and back again
+    let back = dat::convert_costume(&neutral, "PlFxNr.dat", "PlFxOr.dat").expect("convert");
+    assert_eq!(back, orange);
+
+    assert!(dat::convert_costume(&orange, "PlFxGr.dat", "PlFxNr.dat").is_err());
+    assert!(dat::convert_costume(&orange, "PlFxOr.dat", "PlCaNr.dat").is_err());
+    assert!(dat::convert_costume(&orange, "PlFx.dat", "PlFxNr.dat").is_err());
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use common::{synthetic_image, temp_file};
+use melee_inject::diagram::{self, DiagramOptions};
+use melee_inject::replace::{rebuild_fst_with_changes, Changes};
+use melee_inject::verify::Policy;
+
+#[test]
+fn dot_shows_replaced_file_and_neighbours() {
+    let iso = temp_file(
+        "diagram.iso",
+        &synthetic_image(&[
+            ("PlCaBu.dat", &[1; 0x10]),
+            ("PlCaGr.dat", &[2; 0x10]),
+            ("PlCaGy.dat", &[3; 0x10]),
+            ("PlCaNr.dat", &[4; 0x10]),
+        ]),
+    );
+    let changes = Changes {
+        replace: vec![(
+            "PlCaGr.dat".to_string(),
+            temp_file("diagram-gr.dat", &[9; 4]),
+        )],
+        verify: Policy::Skip,
+        ..Changes::default()
+    };
+    let rebuilt = rebuild_fst_with_changes(&iso, &changes).expect("rebuild");
+
+    let dot = diagram::dot(&rebuilt, &DiagramOptions::default());
+    assert!(dot.starts_with("digraph rebuild {"));
+    assert!(dot.contains("label = \"ssbm.iso\""));
+    assert!(dot.contains("PlCaGr.dat\\n[REPLACED]|{<offset>pos|0x00460010}|{<size>len|0x00000004}"));
+    // the neighbour after the replacement moved back by 0xc
+    assert!(dot.contains("label = \"- 0x00000c\""));
+    assert!(dot.contains("PlCaBu_dat"));
+    assert!(dot.contains("PlCaGy_dat"));
+    assert!(!dot.contains("PlCaNr_dat"));
+
+    let plantuml = diagram::plantuml(&rebuilt, &DiagramOptions::default());
+    assert!(plantuml.starts_with("@startdot\ndigraph"));
+    assert!(plantuml.ends_with("}\n@enddot\n"));
+}
+
+#[test]
+fn dot_escapes_labels() {
+    let name = r#"a|b{c}<d>"e\.dat"#;
+    let iso = temp_file(
+        "diagram-escape.iso",
+        &synthetic_image(&[(name, &[1; 0x10]), ("b.dat", &[2; 0x10])]),
+    );
+    let changes = Changes {
+        replace: vec![(
+            "b.dat".to_string(),
+            temp_file("diagram-escape-b.dat", &[9; 4]),
+        )],
+        verify: Policy::Skip,
+        ..Changes::default()
+    };
+    let rebuilt = rebuild_fst_with_changes(&iso, &changes).expect("rebuild");
+
+    let options = DiagramOptions {
+        original_label: r#"melee "vanilla""#,
+        rebuilt_label: "{modded}",
+        ..DiagramOptions::default()
+    };
+    let dot = diagram::dot(&rebuilt, &options);
+    assert!(dot.contains(r#"label = "a\|b\{c\}\<d\>\"e\\.dat|{<offset>pos|"#));
+    assert!(dot.contains(r#"label = "melee \"vanilla\"";"#));
+    assert!(dot.contains(r#"label = "\{modded\}";"#));
+}
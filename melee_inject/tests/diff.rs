@@ -0,0 +1,132 @@
+mod common;
+
+use common::{synthetic_image, temp_file};
+use melee_inject::diff::{self, ChangeKind};
+use melee_inject::disc::Disc;
+use melee_inject::fst::{Entry, Fst};
+use melee_inject::progress::Silent;
+use melee_inject::replace::{build_iso_with_progress, rebuild_fst_with_changes, Changes};
+use melee_inject::verify::Policy;
+
+fn fst(files: &[(&str, u32, u32)]) -> Fst {
+    let mut entries = vec![Entry::Directory {
+        name: String::new(),
+        parent: 0,
+        next: files.len() as u32 + 1,
+    }];
+    entries.extend(files.iter().map(|(name, offset, size)| Entry::File {
+        name: name.to_string(),
+        offset: *offset,
+        size: *size,
+    }));
+    Fst { entries }
+}
+
+#[test]
+fn diff_fst_entries() {
+    let old = fst(&[
+        ("a.dat", 0x100, 0x10),
+        ("b.dat", 0x200, 0x10),
+        ("c.dat", 0x300, 0x10),
+        ("d.dat", 0x400, 0x10),
+    ]);
+    let new = fst(&[
+        ("a.dat", 0x100, 0x10),
+        ("b.dat", 0x200, 0x08),
+        ("c.dat", 0x2f8, 0x10),
+        ("e.dat", 0x400, 0x10),
+        ("f.dat", 0x500, 0x4),
+    ]);
+
+    let diffs = diff::diff_fst(&old, &new);
+    let summary = diffs
+        .iter()
+        .map(|d| {
+            (
+                d.path.as_str(),
+                d.renamed_from.as_deref(),
+                d.changes.clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        summary,
+        [
+            ("b.dat", None, vec![ChangeKind::Resized]),
+            ("c.dat", None, vec![ChangeKind::Moved]),
+            ("e.dat", Some("d.dat"), vec![ChangeKind::Renamed]),
+            ("f.dat", None, vec![ChangeKind::Added]),
+        ]
+    );
+}
+
+#[test]
+fn diff_disc_contents() {
+    let old = synthetic_image(&[("a.dat", b"abcd"), ("b.dat", b"efgh"), ("c.dat", b"ijkl")]);
+    let new = synthetic_image(&[("a.dat", b"abcd"), ("b.dat", b"EFGH"), ("z.dat", b"ijkl")]);
+    let old = Disc::from_backend(Box::new(old)).expect("open");
+    let new = Disc::from_backend(Box::new(new)).expect("open");
+
+    let diffs = diff::diff_discs(&old, &new).expect("diff");
+    assert_eq!(diffs.len(), 2);
+    assert_eq!(diffs[0].changes, [ChangeKind::ContentChanged]);
+    assert_eq!(diffs[1].renamed_from.as_deref(), Some("c.dat"));
+
+    let table = diff::to_table(&diffs);
+    assert!(table.starts_with("CHANGES"));
+    assert!(table.contains("c.dat -> z.dat"));
+
+    let json: serde_json::Value = serde_json::from_str(&diff::to_json(&diffs)).expect("json");
+    assert_eq!(json[0]["changes"][0], "content_changed");
+    assert_eq!(json[0]["old"]["size"], 4);
+}
+
+#[test]
+fn diff_fst_blobs_on_disk() {
+    let old = temp_file(
+        "diff-old-fst.bin",
+        &fst(&[("a.dat", 0x100, 0x10)]).to_bytes(),
+    );
+    let new = temp_file(
+        "diff-new-fst.bin",
+        &fst(&[("a.dat", 0x100, 0x20)]).to_bytes(),
+    );
+
+    let diffs = diff::diff_paths(&old, &new).expect("diff");
+    assert_eq!(diffs[0].changes, [ChangeKind::Resized]);
+}
+
+#[test]
+fn diff_rebuilt_image_with_added_file() {
+    let old = temp_file(
+        "diff-base.iso",
+        &synthetic_image(&[("a.dat", b"abcd"), ("b.dat", b"efgh")]),
+    );
+    // a long name grows the table past the first file, moving every file
+    let name = format!("{}.dat", "n".repeat(0xa000));
+    let changes = Changes {
+        add: vec![(name.clone(), temp_file("diff-added.dat", b"new"))],
+        verify: Policy::Skip,
+        ..Changes::default()
+    };
+    let rebuilt = rebuild_fst_with_changes(&old, &changes).expect("rebuild");
+    let new = temp_file(
+        "diff-rebuilt.iso",
+        &build_iso_with_progress(&old, &rebuilt, &mut Silent).expect("build"),
+    );
+
+    let diffs = diff::diff_paths(&old, &new).expect("diff");
+    let summary = diffs
+        .iter()
+        .map(|d| (d.path == name, d.changes.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        [
+            (false, vec![ChangeKind::Moved]),
+            (false, vec![ChangeKind::Moved]),
+            (true, vec![ChangeKind::Added]),
+        ]
+    );
+}
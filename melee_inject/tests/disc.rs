@@ -0,0 +1,151 @@
+mod common;
+
+use common::{synthetic_image, temp_file, DATA_OFFSET};
+use melee_inject::disc::{Disc, FileStatus};
+use melee_inject::fst::FST_LENGTH;
+use melee_inject::progress::Silent;
+use melee_inject::replace::{build_iso_with_progress, rebuild_fst_with_changes, Changes};
+use melee_inject::vanilla::VanillaFile;
+use melee_inject::verify::Policy;
+use std::borrow::Cow;
+
+#[test]
+fn read_files() {
+    let image = synthetic_image(&[("PlCaGr.dat", b"green falcon"), ("PlCaNr.dat", b"falcon")]);
+    let disc = Disc::from_backend(Box::new(image)).expect("failed to open disc");
+
+    let paths = disc
+        .files()
+        .iter()
+        .map(|f| f.path.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(paths, ["PlCaGr.dat", "PlCaNr.dat"]);
+
+    let neutral = disc.file("PlCaNr.dat").expect("find");
+    assert_eq!(neutral.offset, DATA_OFFSET + 12);
+    assert_eq!(disc.read_file(neutral).expect("read"), b"falcon");
+}
+
+#[test]
+fn open_from_disk() {
+    let iso = temp_file("disc-open.iso", &synthetic_image(&[("a.dat", b"abc")]));
+    let disc = Disc::open(iso).expect("failed to open disc");
+
+    let hashes = disc.file_hashes().expect("hash");
+    assert_eq!(hashes[0].1.sha1, "a9993e364706816aba3e25717850c26c9cd0d89d");
+}
+
+#[test]
+fn file_data_borrows_from_memory() {
+    let image = synthetic_image(&[("a.dat", b"abc")]);
+    let iso = temp_file("disc-file-data.iso", &image);
+
+    let disc = Disc::from_backend(Box::new(image)).expect("open");
+    let data = disc.file_data(&disc.files()[0]).expect("read");
+    assert!(matches!(data, Cow::Borrowed(b"abc")));
+
+    let disc = Disc::open(iso).expect("open");
+    let data = disc.file_data(&disc.files()[0]).expect("read");
+    assert!(matches!(data, Cow::Owned(_)));
+    assert_eq!(data, &b"abc"[..]);
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn open_mmap() {
+    let iso = temp_file("disc-mmap.iso", &synthetic_image(&[("a.dat", b"abc")]));
+    let disc = Disc::open_mmap(iso).expect("map");
+
+    let file = disc.file("a.dat").expect("find");
+    assert!(matches!(
+        disc.file_data(file).expect("read"),
+        Cow::Borrowed(b"abc")
+    ));
+    assert_eq!(disc.read_file(file).expect("read"), b"abc");
+}
+
+#[test]
+fn modified_files_against_table() {
+    let image = synthetic_image(&[
+        ("PlCaNr.dat", b"abc"),
+        ("PlCaGr.dat", b"abd"),
+        ("PlCaRe.dat", b"abcd"),
+        ("new.dat", b"new"),
+    ]);
+    let disc = Disc::from_backend(Box::new(image)).expect("open");
+
+    let vanilla = |path, data: &[u8]| VanillaFile {
+        path,
+        size: data.len() as u32,
+        crc32: crc32fast::hash(data),
+        sha1: "a9993e364706816aba3e25717850c26c9cd0d89d",
+    };
+    let table = [
+        vanilla("PlCaBu.dat", b"abc"),
+        vanilla("PlCaGr.dat", b"abc"),
+        vanilla("PlCaNr.dat", b"abc"),
+        vanilla("PlCaRe.dat", b"abc"),
+    ];
+
+    let modified = disc.modified_files_from(&table).expect("compare");
+    let statuses = modified
+        .iter()
+        .map(|file| (file.path.as_str(), file.status))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        statuses,
+        [
+            ("PlCaGr.dat", FileStatus::Modified),
+            ("PlCaRe.dat", FileStatus::Modified),
+            ("new.dat", FileStatus::Added),
+            ("PlCaBu.dat", FileStatus::Removed),
+        ]
+    );
+}
+
+#[test]
+fn file_hashes_keep_fst_order() {
+    let names = (0..200).map(|i| format!("{i:03}.dat")).collect::<Vec<_>>();
+    let contents = (0..200u32).map(|i| i.to_be_bytes()).collect::<Vec<_>>();
+    let files = names
+        .iter()
+        .zip(&contents)
+        .map(|(name, data)| (name.as_str(), &data[..]))
+        .collect::<Vec<_>>();
+    let disc = Disc::from_backend(Box::new(synthetic_image(&files))).expect("open");
+
+    let hashes = disc.file_hashes().expect("hash");
+    assert_eq!(hashes.len(), 200);
+    for ((file, hashes), data) in hashes.iter().zip(&contents) {
+        assert_eq!(hashes.crc32, crc32fast::hash(data));
+        assert_eq!(file.path, format!("{:03}.dat", u32::from_be_bytes(*data)));
+    }
+}
+
+#[test]
+fn reopen_image_with_added_file() {
+    let iso = temp_file(
+        "disc-added.iso",
+        &synthetic_image(&[("PlCaNr.dat", b"falcon")]),
+    );
+    // a long name grows the table past its vanilla size
+    let name = format!("{}.dat", "a".repeat(0x8000));
+    let changes = Changes {
+        add: vec![(name.clone(), temp_file("disc-added.dat", b"added"))],
+        verify: Policy::Skip,
+        ..Changes::default()
+    };
+    let rebuilt = rebuild_fst_with_changes(&iso, &changes).expect("rebuild");
+    assert!(rebuilt.new_fst.len() as u64 > FST_LENGTH);
+    let image = build_iso_with_progress(&iso, &rebuilt, &mut Silent).expect("build");
+
+    let disc = Disc::from_backend(Box::new(image)).expect("reopen");
+    let added = disc
+        .files()
+        .iter()
+        .find(|file| file.path == name)
+        .expect("find added file");
+    assert_eq!(disc.read_file(added).expect("read"), b"added");
+    let neutral = disc.file("PlCaNr.dat").expect("find");
+    assert_eq!(disc.read_file(neutral).expect("read"), b"falcon");
+}
@@ -0,0 +1,337 @@
+mod common;
+
+use common::{synthetic_image, temp_file};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use melee_inject::disc::Disc;
+use melee_inject::formats::{
+    self, Format, CISO_HEADER_SIZE, GCZ_MAGIC, RVZ_DISC_SIZE, RVZ_HEAD_SIZE, RVZ_MAGIC,
+};
+use melee_inject::replace::{build_iso, rebuild_fst_with_changes, Changes};
+use melee_inject::verify::{self, Policy};
+use std::io::{self, Cursor, Write};
+
+const BLOCK_SIZE: usize = 0x8000;
+
+/// A synthetic image, padded to a whole number of blocks.
+///
+/// CISO doesn't record the image size, so it is always a multiple of the block size.
+fn image() -> Vec<u8> {
+    let mut image = synthetic_image(&[("a.dat", b"aaaaaaaa"), ("b.dat", &[0x11; 0x9000])]);
+    image.resize(image.len().next_multiple_of(BLOCK_SIZE), 0);
+    image
+}
+
+/// Encode an image as CISO, leaving out blocks of zeros.
+fn ciso(image: &[u8]) -> Vec<u8> {
+    let mut header = vec![0; CISO_HEADER_SIZE as usize];
+    header[..4].copy_from_slice(b"CISO");
+    header[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+    let mut data = Vec::new();
+    for (index, block) in image.chunks(BLOCK_SIZE).enumerate() {
+        if block.iter().any(|byte| *byte != 0) {
+            header[8 + index] = 1;
+            data.extend(block);
+            data.resize(data.len().next_multiple_of(BLOCK_SIZE), 0);
+        }
+    }
+    header.extend(data);
+    header
+}
+
+/// Encode an image as GCZ, storing every other block uncompressed.
+fn gcz(image: &[u8]) -> Vec<u8> {
+    let blocks = image.chunks(BLOCK_SIZE).collect::<Vec<_>>();
+    let mut pointers = Vec::new();
+    let mut data = Vec::new();
+    for (index, block) in blocks.iter().enumerate() {
+        if index % 2 == 0 {
+            pointers.push(data.len() as u64);
+            let mut encoder = ZlibEncoder::new(&mut data, Compression::default());
+            encoder.write_all(block).expect("compress");
+            encoder.finish().expect("compress");
+        } else {
+            pointers.push(data.len() as u64 | 1 << 63);
+            data.extend(*block);
+        }
+    }
+
+    let mut gcz = Vec::new();
+    gcz.extend(GCZ_MAGIC.to_le_bytes());
+    gcz.extend(0u32.to_le_bytes());
+    gcz.extend((data.len() as u64).to_le_bytes());
+    gcz.extend((image.len() as u64).to_le_bytes());
+    gcz.extend((BLOCK_SIZE as u32).to_le_bytes());
+    gcz.extend((blocks.len() as u32).to_le_bytes());
+    pointers
+        .iter()
+        .for_each(|pointer| gcz.extend(pointer.to_le_bytes()));
+    blocks.iter().for_each(|_| gcz.extend(0u32.to_le_bytes()));
+    gcz.extend(data);
+    gcz
+}
+
+/// How a group is stored in [`rvz`].
+enum Group {
+    Zstd,
+    Stored,
+    /// Packed runs, each raw data or a junk seed with its length.
+    Packed(Vec<(u32, Vec<u8>)>),
+}
+
+/// Encode an image as RVZ (with zstd) in `BLOCK_SIZE` groups, one per block.
+fn rvz(image: &[u8], groups: impl Fn(usize, &[u8]) -> Group) -> Vec<u8> {
+    let header = (RVZ_HEAD_SIZE + RVZ_DISC_SIZE) as usize;
+    let mut table = Vec::new();
+    let mut data = Vec::new();
+    for (index, block) in image.chunks(BLOCK_SIZE).enumerate() {
+        let (stored, flag, packed) = match groups(index, block) {
+            Group::Zstd => (
+                zstd::bulk::compress(block, 5).expect("compress"),
+                1 << 31,
+                0,
+            ),
+            Group::Stored => (block.to_vec(), 0, 0),
+            Group::Packed(runs) => {
+                let packed = runs
+                    .into_iter()
+                    .flat_map(|(run, bytes)| run.to_be_bytes().into_iter().chain(bytes))
+                    .collect::<Vec<_>>();
+                (packed.clone(), 0, packed.len() as u32)
+            }
+        };
+        table.extend(((header + data.len()) as u32 / 4).to_be_bytes());
+        table.extend((stored.len() as u32 | flag).to_be_bytes());
+        table.extend(packed.to_be_bytes());
+        data.extend(stored);
+        data.resize(data.len().next_multiple_of(4), 0);
+    }
+    let groups = zstd::bulk::compress(&table, 5).expect("compress");
+
+    let mut raw_data = Vec::new();
+    raw_data.extend(0x80u64.to_be_bytes());
+    raw_data.extend((image.len() as u64 - 0x80).to_be_bytes());
+    raw_data.extend(0u32.to_be_bytes());
+    raw_data.extend((table.len() as u32 / 12).to_be_bytes());
+    let raw_data = zstd::bulk::compress(&raw_data, 5).expect("compress");
+
+    let mut rvz = Vec::new();
+    rvz.extend(RVZ_MAGIC);
+    rvz.extend(0x01000000u32.to_be_bytes());
+    rvz.extend(0x00030000u32.to_be_bytes());
+    rvz.extend((RVZ_DISC_SIZE as u32).to_be_bytes());
+    rvz.extend([0; 20]);
+    rvz.extend((image.len() as u64).to_be_bytes());
+    rvz.extend(0u64.to_be_bytes());
+    rvz.extend([0; 20]);
+
+    let raw_data_offset = (header + data.len()) as u64;
+    let group_offset = raw_data_offset + raw_data.len() as u64;
+    rvz.extend(1u32.to_be_bytes());
+    rvz.extend(5u32.to_be_bytes());
+    rvz.extend(5u32.to_be_bytes());
+    rvz.extend((BLOCK_SIZE as u32).to_be_bytes());
+    rvz.extend(&image[..0x80]);
+    rvz.extend(0u32.to_be_bytes());
+    rvz.extend(0x30u32.to_be_bytes());
+    rvz.extend(0u64.to_be_bytes());
+    rvz.extend([0; 20]);
+    rvz.extend(1u32.to_be_bytes());
+    rvz.extend(raw_data_offset.to_be_bytes());
+    rvz.extend((raw_data.len() as u32).to_be_bytes());
+    rvz.extend((table.len() as u32 / 12).to_be_bytes());
+    rvz.extend(group_offset.to_be_bytes());
+    rvz.extend((groups.len() as u32).to_be_bytes());
+    rvz.extend([0; 8]);
+    assert_eq!(rvz.len(), header);
+
+    rvz.extend(data);
+    rvz.extend(raw_data);
+    rvz.extend(groups);
+    rvz
+}
+
+/// Every kind of group: compressed, stored, packed in two runs, and zeros.
+fn rvz_groups(index: usize, block: &[u8]) -> Group {
+    match index % 3 {
+        _ if block.iter().all(|byte| *byte == 0) => Group::Stored,
+        0 => Group::Zstd,
+        1 => Group::Stored,
+        _ => Group::Packed(vec![
+            (0x100, block[..0x100].to_vec()),
+            (block.len() as u32 - 0x100, block[0x100..].to_vec()),
+        ]),
+    }
+}
+
+#[test]
+fn detect_formats() {
+    assert_eq!(Format::detect(*b"CISO"), Format::Ciso);
+    assert_eq!(Format::detect(GCZ_MAGIC.to_le_bytes()), Format::Gcz);
+    assert_eq!(Format::detect(*b"RVZ\x01"), Format::Rvz);
+    assert_eq!(Format::detect(*b"GALE"), Format::Gcm);
+
+    let error = formats::from_backend(b"WIA\x01 and more".to_vec())
+        .err()
+        .expect("unsupported");
+    assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn read_compressed_images() {
+    let image = image();
+    for (name, compressed) in [
+        ("formats.ciso", ciso(&image)),
+        ("formats.gcz", gcz(&image)),
+        ("formats.rvz", rvz(&image, rvz_groups)),
+    ] {
+        assert!(compressed.len() < image.len());
+        let path = temp_file(name, &compressed);
+
+        let disc = Disc::open(&path).expect("open");
+        assert_eq!(disc.size().expect("size") as usize, image.len());
+        let b = disc.file("b.dat").expect("find");
+        assert_eq!(disc.read_file(b).expect("read"), [0x11; 0x9000]);
+
+        let backend = formats::open(&path).expect("open");
+        let mut straddling = vec![0; 0x100];
+        backend
+            .read_at(BLOCK_SIZE as u64 - 0x80, &mut straddling)
+            .expect("read");
+        assert_eq!(straddling, image[BLOCK_SIZE - 0x80..BLOCK_SIZE + 0x80]);
+
+        let raw = verify::hash_reader(&image[..]).expect("hash");
+        assert_eq!(verify::hash_image(&path).expect("hash"), raw);
+    }
+}
+
+#[test]
+fn read_rvz_junk() {
+    let seed = (0..68u8).collect::<Vec<_>>();
+    let junk = |literal: usize| {
+        let mut image = vec![0; BLOCK_SIZE];
+        image[..literal].fill(0x11);
+        let run = 1 << 31 | (BLOCK_SIZE - literal) as u32;
+        let packed = vec![
+            (literal as u32, image[..literal].to_vec()),
+            (run, seed.clone()),
+        ];
+        let backend =
+            formats::from_backend(rvz(&image, |_, _| Group::Packed(packed.clone()))).expect("open");
+        backend.read_at(0, &mut image).expect("read");
+        image
+    };
+
+    // junk depends on where it is within its block, not where the run starts
+    let (early, late) = (junk(0x80), junk(0x100));
+    assert_eq!(early[0x100..], late[0x100..]);
+    assert!(early[0x80..0x100].iter().any(|byte| *byte != 0x11));
+    assert!(late[0x100..].iter().any(|byte| *byte != 0));
+}
+
+#[test]
+fn rvz_needs_zstd() {
+    let mut image = rvz(&image(), rvz_groups);
+    // LZMA
+    image[RVZ_HEAD_SIZE as usize + 4..][..4].copy_from_slice(&3u32.to_be_bytes());
+    let error = formats::from_backend(image).err().expect("unsupported");
+    assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    assert!(error.to_string().contains("Dolphin"));
+}
+
+#[test]
+fn build_from_compressed_image() {
+    let image = image();
+    let raw = temp_file("formats-build.iso", &image);
+    let compressed = temp_file("formats-build.gcz", &gcz(&image));
+    let replacement = temp_file("formats-build-a.dat", b"AAAAAAAAAAAA");
+
+    let changes = Changes {
+        replace: vec![("a.dat".to_string(), replacement)],
+        verify: Policy::Skip,
+        ..Changes::default()
+    };
+    let expected = build_iso(
+        &raw,
+        &rebuild_fst_with_changes(&raw, &changes).expect("rebuild"),
+    );
+    let actual = build_iso(
+        &compressed,
+        &rebuild_fst_with_changes(&compressed, &changes).expect("rebuild"),
+    );
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn write_compressed_images() {
+    let mut image = image();
+    // an image that ends part way through a block
+    image.truncate(image.len() - 0x10);
+    let write = |format| {
+        let mut out = Cursor::new(Vec::new());
+        formats::write(&image, format, &mut out).expect("write");
+        out.into_inner()
+    };
+
+    let gcz = write(Format::Gcz);
+    let backend = formats::from_backend(gcz).expect("open gcz");
+    let mut read = vec![0; image.len()];
+    backend.read_at(0, &mut read).expect("read");
+    assert_eq!(read, image);
+
+    let ciso = write(Format::Ciso);
+    // the header, the boot block, and the block holding the files
+    assert_eq!(
+        ciso.len(),
+        CISO_HEADER_SIZE as usize + 2 * formats::CISO_BLOCK_SIZE
+    );
+    let backend = formats::from_backend(ciso).expect("open ciso");
+    assert_eq!(
+        backend.size().expect("size") as usize,
+        3 * formats::CISO_BLOCK_SIZE
+    );
+    backend.read_at(0, &mut read).expect("read");
+    assert_eq!(read, image);
+
+    assert_eq!(Format::from_path("build/potemkin.GCZ"), Format::Gcz);
+    assert!(!Format::from_path("build/potemkin.wia").is_writable());
+    let error = formats::write(&image, Format::Wia, Cursor::new(Vec::new())).expect_err("wia");
+    assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn write_rvz() {
+    // compressible data, a chunk of zeros, and data that doesn't compress
+    let mut image = image();
+    let end = image.len().next_multiple_of(formats::RVZ_CHUNK_SIZE) + formats::RVZ_CHUNK_SIZE;
+    image.resize(end + formats::RVZ_CHUNK_SIZE + 0x123, 0);
+    let mut state = 1u32;
+    for byte in &mut image[end..] {
+        state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+        *byte = (state >> 24) as u8;
+    }
+
+    let path = temp_file("formats-write.rvz", &[]);
+    let source = temp_file("formats-write.iso", &image);
+    let out = std::fs::File::create(&path).expect("create");
+    let backend = formats::open(&source).expect("open");
+    formats::write(&*backend, Format::from_path(&path), io::BufWriter::new(out)).expect("write");
+
+    let rvz = std::fs::read(&path).expect("read");
+    assert_eq!(&rvz[..4], RVZ_MAGIC);
+    assert!(rvz.len() < image.len());
+    assert_eq!(
+        u64::from_be_bytes(rvz[0x2c..0x34].try_into().unwrap()),
+        rvz.len() as u64
+    );
+
+    let disc = Disc::open(&path).expect("open");
+    assert_eq!(disc.size().expect("size") as usize, image.len());
+    assert_eq!(
+        disc.read_file(disc.file("b.dat").expect("find"))
+            .expect("read"),
+        [0x11; 0x9000]
+    );
+    let raw = verify::hash_reader(&image[..]).expect("hash");
+    assert_eq!(verify::hash_image(&path).expect("hash"), raw);
+}
@@ -0,0 +1,293 @@
+mod common;
+
+use common::{synthetic_image, temp_file};
+use melee_inject::disc::Disc;
+use melee_inject::fst::{Entry, Fst, FST_LENGTH};
+use melee_inject::manifest::Manifest;
+use std::path::{Path, PathBuf};
+
+/// root
+/// ├── a.dat
+/// ├── audio/
+/// │   └── b.ssm
+/// └── c.dat
+fn small_fst() -> Fst {
+    let file = |name: &str, offset| Entry::File {
+        name: name.to_string(),
+        offset,
+        size: 0x10,
+    };
+
+    Fst {
+        entries: vec![
+            Entry::Directory {
+                name: String::new(),
+                parent: 0,
+                next: 5,
+            },
+            file("a.dat", 0x1000),
+            Entry::Directory {
+                name: "audio".to_string(),
+                parent: 0,
+                next: 4,
+            },
+            file("b.ssm", 0x2000),
+            file("c.dat", 0x3000),
+        ],
+    }
+}
+
+#[test]
+fn fst_round_trip() {
+    let fst = small_fst();
+    let bytes = fst.to_bytes();
+
+    assert_eq!(&bytes[0..0x0c], &[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5]);
+    assert_eq!(Fst::parse(&bytes).expect("failed to parse fst"), fst);
+    assert_eq!(fst.paths(), ["", "a.dat", "audio", "audio/b.ssm", "c.dat"]);
+}
+
+#[test]
+fn fst_find_by_name_or_path() {
+    let fst = small_fst();
+
+    assert_eq!(fst.find("b.ssm").expect("name"), 3);
+    assert_eq!(fst.find("/audio/b.ssm").expect("path"), 3);
+    assert!(fst.find("audio/c.dat").is_err());
+    assert!(fst.find("audio").is_err());
+}
+
+#[test]
+fn fst_add_and_remove_files() {
+    let mut fst = small_fst();
+
+    fst.add_file("audio/d.hps", 0x4000, 0x20).expect("add");
+    fst.add_file("new/e.dat", 0x5000, 0x20).expect("add");
+    assert_eq!(
+        fst.paths(),
+        [
+            "",
+            "a.dat",
+            "audio",
+            "audio/b.ssm",
+            "audio/d.hps",
+            "c.dat",
+            "new",
+            "new/e.dat"
+        ]
+    );
+    assert!(fst.add_file("a.dat", 0, 0).is_err());
+
+    fst.remove_file(fst.find("audio/b.ssm").expect("find"))
+        .expect("remove");
+    assert_eq!(
+        fst.paths(),
+        [
+            "",
+            "a.dat",
+            "audio",
+            "audio/d.hps",
+            "c.dat",
+            "new",
+            "new/e.dat"
+        ]
+    );
+
+    let reparsed = Fst::parse(&fst.to_bytes()).expect("failed to parse fst");
+    assert_eq!(reparsed, fst);
+    assert_eq!(
+        reparsed.entries[0],
+        Entry::Directory {
+            name: String::new(),
+            parent: 0,
+            next: 7,
+        }
+    );
+}
+
+#[test]
+fn manifest_changes() {
+    let manifest: Manifest = r#"
+        remove = ["MvEndCa.mth"]
+
+        [base]
+        iso = "ssbm.iso"
+
+        [output]
+        iso = "build/melee.iso"
+
+        [[replace]]
+        target = "CaptainFalcon::PlCaGr"
+        file = "falcon/POTEMKIN FALCON.dat"
+
+        [[replace]]
+        target = "audio/1padv.ssm"
+        file = "1padv.ssm"
+
+        [[add]]
+        path = "audio/potemkin.hps"
+        file = "potemkin.hps"
+
+        [[dol_patch]]
+        offset = 0x100
+        bytes = "6000 0000"
+    "#
+    .parse()
+    .expect("failed to parse manifest");
+
+    let changes = manifest.changes();
+    assert_eq!(
+        changes.replace,
+        [
+            (
+                "PlCaGr.dat".to_string(),
+                PathBuf::from("./falcon/POTEMKIN FALCON.dat")
+            ),
+            ("audio/1padv.ssm".to_string(), PathBuf::from("./1padv.ssm")),
+        ]
+    );
+    assert_eq!(
+        changes.add,
+        [(
+            "audio/potemkin.hps".to_string(),
+            PathBuf::from("./potemkin.hps")
+        )]
+    );
+    assert_eq!(changes.remove, ["MvEndCa.mth"]);
+    assert_eq!(
+        manifest.dol_patches[0].data().expect("hex"),
+        [0x60, 0x00, 0x00, 0x00]
+    );
+}
+
+#[test]
+fn manifest_dol_patch_by_address() {
+    let manifest: Manifest = r#"
+        [base]
+        iso = "ssbm.iso"
+
+        [output]
+        iso = "melee.iso"
+
+        [[dol_patch]]
+        address = 0x80003108
+        bytes = "60000000"
+    "#
+    .parse()
+    .expect("failed to parse manifest");
+
+    // one text section at file offset 0x100, loaded at 0x80003100
+    let mut dol = vec![0; 0x200];
+    dol[0x00..0x04].copy_from_slice(&0x100u32.to_be_bytes());
+    dol[0x48..0x4c].copy_from_slice(&0x80003100u32.to_be_bytes());
+    dol[0x90..0x94].copy_from_slice(&0x100u32.to_be_bytes());
+
+    assert_eq!(
+        manifest.dol_patches[0].dol_offset(&dol).expect("offset"),
+        0x108
+    );
+}
+
+#[test]
+fn manifest_rejects_short_images() {
+    let manifest: Manifest = r#"
+        [base]
+        iso = "ssbm.iso"
+
+        [output]
+        iso = "melee.iso"
+
+        [header]
+        game_id = "GALE01"
+        internal_name = "Super Smash Bros Melee"
+
+        [[dol_patch]]
+        offset = 0x100
+        bytes = "60000000"
+    "#
+    .parse()
+    .expect("failed to parse manifest");
+
+    assert!(manifest.patch_header(&mut [0; 4]).is_err());
+    assert!(manifest.patch_header(&mut [0; 0x100]).is_err());
+    assert!(manifest.patch_header(&mut [0; 0x400]).is_ok());
+
+    // the dol offset at 0x420 points past the end of the image
+    let mut image = vec![0; 0x440];
+    image[0x420..0x424].copy_from_slice(&0x1000u32.to_be_bytes());
+    assert!(manifest.patch_dol(&mut image).is_err());
+    assert!(manifest.patch_dol(&mut [0; 0x10]).is_err());
+}
+
+#[test]
+fn manifest_rejects_unknown_fields() {
+    let manifest = r#"
+        [base]
+        iso = "ssbm.iso"
+        md5 = "00"
+
+        [output]
+        iso = "melee.iso"
+    "#
+    .parse::<Manifest>();
+
+    assert!(manifest.is_err());
+}
+
+#[test]
+fn manifest_builds_on_a_built_image() {
+    let base = temp_file(
+        "manifest-base.iso",
+        &synthetic_image(&[("PlCaNr.dat", b"falcon")]),
+    );
+    let first = base.with_file_name("manifest-first.iso");
+    let second = base.with_file_name("manifest-second.iso");
+    let manifest = |base: &Path, output: &Path, edits: &str| -> Manifest {
+        format!(
+            r#"
+            {edits}
+
+            [base]
+            iso = {base:?}
+            verify = "skip"
+
+            [output]
+            iso = {output:?}
+            "#
+        )
+        .parse()
+        .expect("failed to parse manifest")
+    };
+
+    // a long name grows the table past its vanilla size
+    let long = format!("audio/{}.hps", "a".repeat(0x8000));
+    let added = temp_file("manifest-added.hps", b"added");
+    manifest(
+        &base,
+        &first,
+        &format!("[[add]]\npath = {long:?}\nfile = {added:?}"),
+    )
+    .apply()
+    .expect("first build");
+    let disc = Disc::open(&first).expect("reopen first build");
+    assert!(disc.fst.to_bytes().len() as u64 > FST_LENGTH);
+
+    let falcon = temp_file("manifest-falcon.dat", b"potemkin falcon");
+    manifest(
+        &first,
+        &second,
+        &format!("remove = [{long:?}]\n[[replace]]\ntarget = \"PlCaNr.dat\"\nfile = {falcon:?}"),
+    )
+    .apply()
+    .expect("second build");
+
+    let disc = Disc::open(&second).expect("reopen second build");
+    let paths = disc
+        .files()
+        .iter()
+        .map(|file| file.path.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(paths, ["PlCaNr.dat"]);
+    let neutral = disc.file("PlCaNr.dat").expect("find");
+    assert_eq!(disc.read_file(neutral).expect("read"), b"potemkin falcon");
+}
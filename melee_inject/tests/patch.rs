@@ -0,0 +1,233 @@
+mod common;
+
+use common::{synthetic_image, temp_file};
+use melee_inject::patch;
+use melee_inject::replace::{build_iso, rebuild_fst_with_changes, Changes};
+use melee_inject::verify::{self, Policy};
+
+/// A synthetic image with three large files, and a build replacing the middle one.
+fn build() -> (
+    std::path::PathBuf,
+    Vec<u8>,
+    melee_inject::replace::RebuiltFST,
+) {
+    let a = vec![0xaa; 0x40000];
+    let b = vec![0xbb; 0x40000];
+    let c = vec![0xcc; 0x40000];
+    let source = temp_file(
+        "patch-source.iso",
+        &synthetic_image(&[("a.dat", &a), ("b.dat", &b), ("c.dat", &c)]),
+    );
+    let replacement = temp_file("patch-b.dat", &[0x11; 0x100]);
+
+    let changes = Changes {
+        replace: vec![("b.dat".to_string(), replacement)],
+        verify: Policy::Skip,
+        ..Changes::default()
+    };
+    let rebuilt = rebuild_fst_with_changes(&source, &changes).expect("rebuild");
+    let target = build_iso(&source, &rebuilt);
+    (source, target, rebuilt)
+}
+
+#[test]
+fn create_bps_patch() {
+    let (source, target, rebuilt) = build();
+
+    let mut bps = Vec::new();
+    patch::create_bps(&source, &target, &rebuilt, &mut bps).expect("create patch");
+
+    assert_eq!(&bps[..4], patch::BPS_MAGIC);
+    // unchanged files are copied from the source rather than stored
+    assert!(bps.len() < 0x4000, "patch is {:#x} bytes", bps.len());
+
+    let footer = &bps[bps.len() - 12..];
+    let source_crc32 = verify::hash_file(&source).expect("hash").crc32;
+    let target_crc32 = verify::hash_reader(&target[..]).expect("hash").crc32;
+    let patch_crc32 = verify::hash_reader(&bps[..bps.len() - 4])
+        .expect("hash")
+        .crc32;
+    assert_eq!(footer[0..4], source_crc32.to_le_bytes());
+    assert_eq!(footer[4..8], target_crc32.to_le_bytes());
+    assert_eq!(footer[8..12], patch_crc32.to_le_bytes());
+}
+
+#[test]
+fn apply_bps_patch() {
+    let (source, target, rebuilt) = build();
+
+    let mut bps = Vec::new();
+    patch::create_bps(&source, &target, &rebuilt, &mut bps).expect("create patch");
+    let bps = temp_file("patch-apply.bps", &bps);
+
+    let output = temp_file("patch-apply.iso", &[]);
+    patch::apply_bps(&source, &bps, &output).expect("apply patch");
+    assert!(std::fs::read(&output).expect("read output") == target);
+}
+
+#[test]
+fn apply_bps_target_copy() {
+    // "abcabcabc" from an empty source: TargetRead "abc", then an overlapping TargetCopy
+    let mut bps = b"BPS1".to_vec();
+    bps.extend([0x80, 0x89, 0x80]); // source 0, target 9, metadata 0
+    bps.extend([0x80 | (2 << 2 | 1)]);
+    bps.extend(b"abc");
+    bps.extend([0x80 | (5 << 2 | 3), 0x80]); // 6 bytes from target offset 0
+    bps.extend(0u32.to_le_bytes()); // crc32 of empty source
+    bps.extend(
+        verify::hash_reader(&b"abcabcabc"[..])
+            .expect("hash")
+            .crc32
+            .to_le_bytes(),
+    );
+    let patch_crc32 = verify::hash_reader(&bps[..]).expect("hash").crc32;
+    bps.extend(patch_crc32.to_le_bytes());
+
+    let source = temp_file("patch-empty.iso", &[]);
+    let bps = temp_file("patch-target-copy.bps", &bps);
+    let output = temp_file("patch-target-copy.iso", &[]);
+    patch::apply_bps(&source, &bps, &output).expect("apply patch");
+    assert_eq!(std::fs::read(&output).expect("read output"), b"abcabcabc");
+}
+
+#[test]
+fn apply_bps_rejects_wrong_source() {
+    let (source, target, rebuilt) = build();
+
+    let mut bps = Vec::new();
+    patch::create_bps(&source, &target, &rebuilt, &mut bps).expect("create patch");
+    let bps = temp_file("patch-wrong-source.bps", &bps);
+
+    let mut other = std::fs::read(&source).expect("read source");
+    other[0x460000] ^= 0xff;
+    let other = temp_file("patch-wrong-source.iso", &other);
+
+    let output = temp_file("patch-wrong-source-output.iso", &[]);
+    let error = patch::apply_bps(&other, &bps, &output).expect_err("wrong source");
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
+
+/// Write a VCDIFF variable length number.
+fn vcdiff_number(out: &mut Vec<u8>, number: u64) {
+    let mut bytes = vec![(number & 0x7f) as u8];
+    let mut rest = number >> 7;
+    while rest != 0 {
+        bytes.push(0x80 | (rest & 0x7f) as u8);
+        rest >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+/// A VCDIFF window producing `target`, with an Adler-32 checksum.
+fn vcdiff_window(
+    indicator: u8,
+    segment: Option<(u64, u64)>,
+    target: &[u8],
+    data: &[u8],
+    instructions: &[u8],
+    addresses: &[u8],
+) -> Vec<u8> {
+    let mut delta = Vec::new();
+    vcdiff_number(&mut delta, target.len() as u64);
+    delta.push(0);
+    vcdiff_number(&mut delta, data.len() as u64);
+    vcdiff_number(&mut delta, instructions.len() as u64);
+    vcdiff_number(&mut delta, addresses.len() as u64);
+    delta.extend(adler2::adler32_slice(target).to_be_bytes());
+    delta.extend(data);
+    delta.extend(instructions);
+    delta.extend(addresses);
+
+    let mut window = vec![indicator | 0x04];
+    if let Some((offset, length)) = segment {
+        vcdiff_number(&mut window, length);
+        vcdiff_number(&mut window, offset);
+    }
+    vcdiff_number(&mut window, delta.len() as u64);
+    window.extend(delta);
+    window
+}
+
+/// A VCDIFF patch against `0123456789abcdef`, with a source and a target window.
+fn vcdiff_patch() -> Vec<u8> {
+    let mut patch = patch::VCDIFF_MAGIC.to_vec();
+    patch.push(0);
+    // from source "456789ab": COPY 4 "6789", ADD "xy", RUN 3 "z", then an
+    // overlapping COPY 6 from this window, addressed relative to here
+    patch.extend(vcdiff_window(
+        0x01,
+        Some((4, 8)),
+        b"6789xyzzzxyzzzx",
+        b"xyz",
+        &[20, 3, 0, 3, 38],
+        &[2, 5],
+    ));
+    // from the target so far "6789": COPY 4, then ADD "!" and a COPY 4 that
+    // runs off the end of the segment into this window
+    patch.extend(vcdiff_window(
+        0x02,
+        Some((0, 4)),
+        b"6789!7896",
+        b"!",
+        &[20, 163],
+        &[0, 1],
+    ));
+    patch
+}
+
+#[test]
+fn apply_vcdiff_patch() {
+    let source = temp_file("patch-vcdiff-source.bin", b"0123456789abcdef");
+    let vcdiff = temp_file("patch-apply.vcdiff", &vcdiff_patch());
+
+    let output = temp_file("patch-vcdiff.bin", &[]);
+    patch::apply(&source, &vcdiff, &output).expect("apply patch");
+    assert_eq!(
+        std::fs::read(&output).expect("read output"),
+        b"6789xyzzzxyzzzx6789!7896"
+    );
+}
+
+#[test]
+fn apply_vcdiff_rejects_bad_window() {
+    let source = temp_file("patch-vcdiff-bad-source.bin", b"0123456789abcdef");
+    let mut vcdiff = vcdiff_patch();
+    // the first window's ADD now writes "xz"
+    let index = vcdiff
+        .windows(3)
+        .position(|bytes| bytes == b"xyz")
+        .expect("data");
+    vcdiff[index + 1] = b'z';
+    let vcdiff = temp_file("patch-bad.vcdiff", &vcdiff);
+
+    let output = temp_file("patch-vcdiff-bad.bin", &[]);
+    let error = patch::apply(&source, &vcdiff, &output).expect_err("bad checksum");
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert!(!output.exists());
+
+    // a source too small for the first window's segment
+    let short = temp_file("patch-vcdiff-short.bin", b"0123");
+    let vcdiff = temp_file("patch-short.vcdiff", &vcdiff_patch());
+    let error = patch::apply(&short, &vcdiff, &output).expect_err("short source");
+    assert!(error.to_string().contains("source image"), "{error}");
+}
+
+#[test]
+fn apply_vcdiff_rejects_secondary_compression() {
+    let mut vcdiff = patch::VCDIFF_MAGIC.to_vec();
+    vcdiff.extend([0x01, 0x02]); // djw
+    let vcdiff = temp_file("patch-djw.vcdiff", &vcdiff);
+    let source = temp_file("patch-djw-source.bin", b"");
+    let output = temp_file("patch-djw.bin", &[]);
+
+    let error = patch::apply(&source, &vcdiff, &output).expect_err("secondary compression");
+    assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+    assert!(error.to_string().contains("xdelta3 -S none"), "{error}");
+
+    let unknown = temp_file("patch-unknown.ips", b"PATCH");
+    let error = patch::apply(&source, &unknown, &output).expect_err("unknown format");
+    assert!(
+        error.to_string().contains("not a BPS or VCDIFF patch"),
+        "{error}"
+    );
+}
@@ -0,0 +1,201 @@
+mod common;
+
+use common::{synthetic_dat, synthetic_image, temp_file, textured_dat, DATA_OFFSET};
+use melee_inject::dat::DatFile;
+use melee_inject::replace::{build_iso, plan, rebuild_fst_with_changes, Changes, FileAction};
+use melee_inject::texture;
+use melee_inject::verify::Policy;
+
+fn changes(replace: Vec<(&str, &[u8])>, remove: Vec<&str>) -> Changes {
+    Changes {
+        replace: replace
+            .into_iter()
+            .map(|(target, data)| {
+                (
+                    target.to_string(),
+                    temp_file(&format!("plan-{target}"), data),
+                )
+            })
+            .collect(),
+        remove: remove.into_iter().map(str::to_string).collect(),
+        verify: Policy::Skip,
+        ..Changes::default()
+    }
+}
+
+#[test]
+fn plan_shrinking_replacement() {
+    let iso = temp_file(
+        "plan-shrink.iso",
+        &synthetic_image(&[
+            ("a.dat", &[1; 0x100]),
+            ("b.dat", &[2; 0x100]),
+            ("c.dat", &[3; 0x10]),
+        ]),
+    );
+
+    let changes = changes(vec![("a.dat", &[9; 0x41])], vec![]);
+    let plan = plan(&iso, &changes).expect("plan");
+    let layout = plan
+        .files
+        .iter()
+        .map(|f| (f.path.as_str(), f.action, f.updated_offset, f.updated_size))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        layout,
+        [
+            ("a.dat", FileAction::Replace, DATA_OFFSET, 0x41),
+            ("b.dat", FileAction::Keep, DATA_OFFSET + 0x44, 0x100),
+            ("c.dat", FileAction::Keep, DATA_OFFSET + 0x144, 0x10),
+        ]
+    );
+    assert_eq!(plan.moved_bytes, 0x110);
+
+    let rebuilt = rebuild_fst_with_changes(&iso, &changes).expect("rebuild");
+    assert_eq!(rebuilt.new_fst, plan.fst.to_bytes());
+    assert_eq!(build_iso(&iso, &rebuilt).len() as u64, plan.image_size);
+}
+
+#[test]
+fn plan_growing_replacement_and_removal() {
+    let iso = temp_file(
+        "plan-grow.iso",
+        &synthetic_image(&[
+            ("a.dat", &[1; 0x10]),
+            ("b.dat", &[2; 0x10]),
+            ("c.dat", &[3; 0x10]),
+        ]),
+    );
+
+    let plan = plan(&iso, &changes(vec![("a.dat", &[9; 0x30])], vec!["b.dat"])).expect("plan");
+
+    let c = plan
+        .files
+        .iter()
+        .find(|f| f.path == "c.dat")
+        .expect("c.dat");
+    // +0x20 for the larger a.dat, -0x10 for the removed b.dat
+    assert_eq!(c.updated_offset, c.original_offset + 0x10);
+    assert_eq!(plan.fst.paths(), ["", "a.dat", "c.dat"]);
+}
+
+#[test]
+fn plan_serializes_to_json() {
+    let iso = temp_file("plan-json.iso", &synthetic_image(&[("a.dat", &[1; 0x10])]));
+
+    let plan = plan(&iso, &changes(vec![("a.dat", &[9; 0x8])], vec![])).expect("plan");
+    let json = serde_json::to_value(&plan).expect("json");
+
+    assert_eq!(json["files"][0]["action"], "replace");
+    assert_eq!(json["files"][0]["updated_size"], 8);
+    assert_eq!(json["image_size"], plan.image_size);
+    assert!(json.get("fst").is_none());
+}
+
+#[test]
+fn plan_validates_dat_replacements() {
+    let falcon = synthetic_dat(&[0; 0x10], &[], &[(0x00, "PlyCaptain5KGr_Share_joint")]);
+    let iso = temp_file(
+        "plan-validate.iso",
+        &synthetic_image(&[("PlCaGr.dat", &falcon), ("PlCaNr.dat", &falcon)]),
+    );
+
+    let mut changes = changes(vec![("PlCaNr.dat", &falcon)], vec![]);
+    let warnings = plan(&iso, &changes).expect("plan").warnings;
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].starts_with("PlCaNr.dat"), "{warnings:?}");
+
+    changes.validate = Policy::Refuse;
+    assert!(plan(&iso, &changes).is_err());
+
+    changes.validate = Policy::Skip;
+    assert!(plan(&iso, &changes).expect("plan").warnings.is_empty());
+}
+
+#[test]
+fn plan_renames_costumes() {
+    let green = synthetic_dat(&[0; 0x10], &[], &[(0x00, "PlyCaptain5KGr_Share_joint")]);
+    let neutral = synthetic_dat(&[0; 0x10], &[], &[(0x00, "PlyCaptain5K_Share_joint")]);
+    let iso = temp_file(
+        "plan-rename.iso",
+        &synthetic_image(&[("PlCaNr.dat", &neutral), ("PlCaGr.dat", &green)]),
+    );
+
+    let mut changes = changes(vec![("PlCaNr.dat", &green)], vec![]);
+    changes.rename_costumes = true;
+    let planned = plan(&iso, &changes).expect("plan");
+    assert!(planned.warnings.is_empty(), "{:?}", planned.warnings);
+    let file = &planned.files[0];
+    assert_eq!(
+        file.renamed_from
+            .as_ref()
+            .map(|slot| slot.file_name())
+            .as_deref(),
+        Some("PlCaGr.dat")
+    );
+    assert_eq!(file.updated_size as usize, neutral.len());
+
+    let rebuilt = rebuild_fst_with_changes(&iso, &changes).expect("rebuild");
+    let image = build_iso(&iso, &rebuilt);
+    let disc = melee_inject::disc::Disc::from_backend(Box::new(image)).expect("open");
+    let data = disc
+        .read_file(disc.file("PlCaNr.dat").expect("find"))
+        .expect("read");
+    assert_eq!(data, neutral);
+}
+
+#[test]
+fn plan_injects_texture_folders() {
+    let original = textured_dat();
+    let iso = temp_file(
+        "plan-textures.iso",
+        &synthetic_image(&[("PlFxNr.dat", &original), ("audio.ssm", &[0; 0x10])]),
+    );
+    let dir =
+        std::env::temp_dir().join(format!("melee-inject-plan-textures-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("create dir");
+    let yellow = texture::Image {
+        width: 8,
+        height: 8,
+        rgba: [0xff, 0xff, 0, 0xff].repeat(64),
+    };
+    std::fs::write(
+        dir.join("0x280_CMPR_8x8.png"),
+        yellow.to_png().expect("png"),
+    )
+    .expect("write");
+
+    let changes = Changes {
+        replace: vec![("PlFxNr.dat".to_string(), dir.clone())],
+        verify: Policy::Skip,
+        ..Changes::default()
+    };
+    let planned = plan(&iso, &changes).expect("plan");
+    assert_eq!(planned.files[0].action, FileAction::Replace);
+    assert_eq!(planned.files[0].updated_size as usize, original.len());
+
+    let rebuilt = rebuild_fst_with_changes(&iso, &changes).expect("rebuild");
+    let image = build_iso(&iso, &rebuilt);
+    let disc = melee_inject::disc::Disc::from_backend(Box::new(image)).expect("open");
+    let data = disc
+        .read_file(disc.file("PlFxNr.dat").expect("find"))
+        .expect("read");
+    let dat = DatFile::parse(&data).expect("parse");
+    let textures = texture::find(&dat).expect("find textures");
+    let decoded = textures[0]
+        .read(&dat)
+        .expect("read")
+        .decode()
+        .expect("decode");
+    assert_eq!(decoded, yellow);
+
+    // only .dat files have textures
+    let changes = Changes {
+        replace: vec![("audio.ssm".to_string(), dir.clone())],
+        verify: Policy::Skip,
+        ..Changes::default()
+    };
+    assert!(plan(&iso, &changes).is_err());
+    std::fs::remove_dir_all(dir).expect("remove dir");
+}
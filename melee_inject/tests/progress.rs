@@ -0,0 +1,102 @@
+mod common;
+
+use common::{synthetic_image, temp_file};
+use melee_inject::disc::Disc;
+use melee_inject::patch;
+use melee_inject::progress::{self, Control, Phase, Progress};
+use melee_inject::replace::{
+    build_iso, build_iso_with_progress, rebuild_fst_with_changes, Changes,
+};
+use melee_inject::verify::Policy;
+
+fn rebuilt(name: &str) -> (std::path::PathBuf, melee_inject::replace::RebuiltFST) {
+    let source = temp_file(
+        &format!("{name}.iso"),
+        &synthetic_image(&[("a.dat", b"aaaa"), ("b.dat", b"bbbb"), ("c.dat", b"cccc")]),
+    );
+    let replacement = temp_file(&format!("{name}-b.dat"), b"BBBBBBBB");
+    let changes = Changes {
+        replace: vec![("b.dat".to_string(), replacement)],
+        verify: Policy::Skip,
+        ..Changes::default()
+    };
+    let rebuilt = rebuild_fst_with_changes(&source, &changes).expect("rebuild");
+    (source, rebuilt)
+}
+
+#[test]
+fn build_reports_every_file() {
+    let (source, rebuilt) = rebuilt("progress-build");
+
+    let mut updates = Vec::new();
+    let image = build_iso_with_progress(&source, &rebuilt, &mut |progress: Progress| {
+        updates.push((
+            progress.bytes,
+            progress.total,
+            progress.file.map(str::to_string),
+        ));
+        assert_eq!(progress.phase, Phase::Build);
+        Control::Continue
+    })
+    .expect("build");
+
+    assert_eq!(image, build_iso(&source, &rebuilt));
+    assert_eq!(updates.len(), 4);
+    assert!(updates.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    let (bytes, total, file) = updates.last().expect("final update");
+    assert_eq!(bytes, total);
+    assert_eq!(file.as_deref(), Some("c.dat"));
+}
+
+#[test]
+fn build_can_be_cancelled() {
+    let (source, rebuilt) = rebuilt("progress-cancel");
+
+    let mut calls = 0;
+    let error = build_iso_with_progress(&source, &rebuilt, &mut |_: Progress| {
+        calls += 1;
+        match calls {
+            2 => Control::Cancel,
+            _ => Control::Continue,
+        }
+    })
+    .expect_err("cancelled");
+
+    assert!(progress::is_cancelled(&error));
+    assert_eq!(calls, 2);
+}
+
+#[test]
+fn extract_files() {
+    let image = synthetic_image(&[("a.dat", b"aaaa"), ("b.dat", b"bbbbbb")]);
+    let disc = Disc::from_backend(Box::new(image)).expect("open");
+    let dir = temp_file("progress-extract", b"").with_extension("d");
+
+    let mut files = Vec::new();
+    disc.extract(&dir, &mut |progress: Progress| {
+        files.extend(progress.file.map(str::to_string));
+        assert_eq!(progress.total, 10);
+        Control::Continue
+    })
+    .expect("extract");
+
+    assert_eq!(files, ["a.dat", "b.dat"]);
+    assert_eq!(std::fs::read(dir.join("b.dat")).expect("read"), b"bbbbbb");
+}
+
+#[test]
+fn cancelled_patch_removes_target() {
+    let (source, rebuilt) = rebuilt("progress-patch");
+    let image = build_iso(&source, &rebuilt);
+    let mut bps = Vec::new();
+    patch::create_bps(&source, &image, &rebuilt, &mut bps).expect("create patch");
+    let bps = temp_file("progress-patch.bps", &bps);
+    let target = source.with_extension("patched.iso");
+
+    let error =
+        patch::apply_bps_with_progress(&source, &bps, &target, &mut |_: Progress| Control::Cancel)
+            .expect_err("cancelled");
+
+    assert!(progress::is_cancelled(&error));
+    assert!(!target.exists());
+}
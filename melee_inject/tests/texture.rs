@@ -0,0 +1,671 @@
+mod common;
+
+use common::textured_dat;
+use melee_inject::dat::DatFile;
+use melee_inject::texture::{self, Format, Palette, PaletteFormat};
+
+/// Big endian halfwords.
+fn halfwords(values: &[u16]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect()
+}
+
+/// The pixel at `x`, `y` of a decoded image.
+fn pixel(image: &texture::Image, x: usize, y: usize) -> [u8; 4] {
+    let at = (y * image.width + x) * 4;
+    image.rgba[at..at + 4].try_into().unwrap()
+}
+
+#[test]
+fn format_sizes_and_names() {
+    assert_eq!(Format::CMPR.data_size(128, 128), 0x2000);
+    assert_eq!(Format::RGBA8.data_size(4, 4), 64);
+    assert_eq!(Format::I4.data_size(9, 1), 64);
+    assert_eq!(Format::CI8.data_size(8, 5), 64);
+    for format in Format::ALL {
+        assert_eq!(Format::from_id(format.id()), Some(format));
+        assert_eq!(format.name().parse::<Format>().unwrap(), format);
+    }
+    assert_eq!(Format::from_id(7), None);
+    assert!("DXT5".parse::<Format>().is_err());
+}
+
+#[test]
+fn i4_block_order() {
+    // nibbles count 0..16 through the 8x8 block, high nibble first
+    let data = (0..32)
+        .map(|byte| (((byte * 2 % 16) << 4) | ((byte * 2 + 1) % 16)) as u8)
+        .collect::<Vec<_>>();
+    let image = texture::decode(&data, 8, 8, Format::I4, None).unwrap();
+    for index in 0..64 {
+        let intensity = (index % 16) as u8 * 0x11;
+        assert_eq!(
+            pixel(&image, index % 8, index / 8),
+            [intensity, intensity, intensity, 0xff]
+        );
+    }
+}
+
+#[test]
+fn blocks_are_tiled_and_cropped() {
+    // an 8x4 RGB565 texture is two 4x4 blocks side by side: red, then blue
+    let mut data = halfwords(&[0xf800; 16]);
+    data.extend(halfwords(&[0x001f; 16]));
+    let image = texture::decode(&data, 8, 4, Format::RGB565, None).unwrap();
+    assert_eq!(pixel(&image, 3, 3), [0xff, 0, 0, 0xff]);
+    assert_eq!(pixel(&image, 4, 0), [0, 0, 0xff, 0xff]);
+
+    // a 6x3 texture is still two whole blocks, cropped to its size
+    let image = texture::decode(&data, 6, 3, Format::RGB565, None).unwrap();
+    assert_eq!(image.rgba.len(), 6 * 3 * 4);
+    assert_eq!(pixel(&image, 5, 2), [0, 0, 0xff, 0xff]);
+}
+
+#[test]
+fn intensity_formats() {
+    let image = texture::decode(&[0x40; 32], 8, 4, Format::I8, None).unwrap();
+    assert_eq!(pixel(&image, 7, 3), [0x40, 0x40, 0x40, 0xff]);
+
+    let image = texture::decode(&[0x5a; 32], 8, 4, Format::IA4, None).unwrap();
+    assert_eq!(pixel(&image, 0, 0), [0xaa, 0xaa, 0xaa, 0x55]);
+
+    let image = texture::decode(&halfwords(&[0x80ff; 16]), 4, 4, Format::IA8, None).unwrap();
+    assert_eq!(pixel(&image, 1, 2), [0xff, 0xff, 0xff, 0x80]);
+}
+
+#[test]
+fn colour_formats() {
+    let mut colours = vec![0x07e0, 0x8000 | 0x7c00, 0x3a5c];
+    colours.resize(16, 0);
+    let image = texture::decode(&halfwords(&colours), 4, 4, Format::RGB565, None).unwrap();
+    assert_eq!(pixel(&image, 0, 0), [0, 0xff, 0, 0xff]);
+
+    let image = texture::decode(&halfwords(&colours), 4, 4, Format::RGB5A3, None).unwrap();
+    // opaque RGB555
+    assert_eq!(pixel(&image, 1, 0), [0xff, 0, 0, 0xff]);
+    // 3 bit alpha, RGB444
+    assert_eq!(pixel(&image, 2, 0), [0xaa, 0x55, 0xcc, 0x6d]);
+
+    // RGBA8: alpha and red, then green and blue
+    let mut data = (0..16).flat_map(|i| [i, 0x10 + i]).collect::<Vec<u8>>();
+    data.extend((0..16).flat_map(|i| [0x20 + i, 0x30 + i]));
+    let image = texture::decode(&data, 4, 4, Format::RGBA8, None).unwrap();
+    assert_eq!(pixel(&image, 0, 0), [0x10, 0x20, 0x30, 0x00]);
+    assert_eq!(pixel(&image, 3, 2), [0x1b, 0x2b, 0x3b, 0x0b]);
+}
+
+#[test]
+fn paletted_formats() {
+    let rgb565 = halfwords(&[0xf800, 0x07e0, 0x001f]);
+    let palette = Palette {
+        format: PaletteFormat::RGB565,
+        data: &rgb565,
+    };
+    let mut data = vec![0x01; 32];
+    data[0] = 0x20;
+    let image = texture::decode(&data, 8, 8, Format::CI4, Some(palette)).unwrap();
+    assert_eq!(pixel(&image, 0, 0), [0, 0, 0xff, 0xff]);
+    assert_eq!(pixel(&image, 1, 0), [0xff, 0, 0, 0xff]);
+    assert_eq!(pixel(&image, 3, 0), [0, 0xff, 0, 0xff]);
+
+    let rgb5a3 = halfwords(&[0xffff, 0x0f00]);
+    let palette = Palette {
+        format: PaletteFormat::RGB5A3,
+        data: &rgb5a3,
+    };
+    let mut data = vec![0; 32];
+    data[9] = 1;
+    let image = texture::decode(&data, 8, 4, Format::CI8, Some(palette)).unwrap();
+    assert_eq!(pixel(&image, 0, 0), [0xff, 0xff, 0xff, 0xff]);
+    assert_eq!(pixel(&image, 1, 1), [0xff, 0, 0, 0]);
+
+    // CI14x2 ignores the top two bits of each index
+    let ia8 = halfwords(&[0xff00, 0x80ff]);
+    let palette = Palette {
+        format: PaletteFormat::IA8,
+        data: &ia8,
+    };
+    let image = texture::decode(
+        &halfwords(&[0xc001; 16]),
+        4,
+        4,
+        Format::CI14x2,
+        Some(palette),
+    )
+    .unwrap();
+    assert_eq!(pixel(&image, 2, 2), [0xff, 0xff, 0xff, 0x80]);
+}
+
+#[test]
+fn cmpr_blocks() {
+    let mut data = Vec::new();
+    // top left: white and black, four colours, each row 0 1 2 3
+    data.extend(halfwords(&[0xffff, 0x0000]));
+    data.extend([0b00_01_10_11; 4]);
+    // top right: black then red, so the average and transparent
+    data.extend(halfwords(&[0x0000, 0xf800]));
+    data.extend([0b10_11_00_01; 4]);
+    // bottom: solid green, solid blue
+    data.extend(halfwords(&[0x07e0, 0x07e0]));
+    data.extend([0; 4]);
+    data.extend(halfwords(&[0x001f, 0x0000]));
+    data.extend([0; 4]);
+
+    let image = texture::decode(&data, 8, 8, Format::CMPR, None).unwrap();
+    assert_eq!(pixel(&image, 0, 0), [0xff, 0xff, 0xff, 0xff]);
+    assert_eq!(pixel(&image, 1, 1), [0, 0, 0, 0xff]);
+    assert_eq!(pixel(&image, 2, 2), [0xaa, 0xaa, 0xaa, 0xff]);
+    assert_eq!(pixel(&image, 3, 3), [0x55, 0x55, 0x55, 0xff]);
+    assert_eq!(pixel(&image, 4, 0), [0x7f, 0, 0, 0xff]);
+    assert_eq!(pixel(&image, 5, 3), [0, 0, 0, 0]);
+    assert_eq!(pixel(&image, 7, 1), [0xff, 0, 0, 0xff]);
+    assert_eq!(pixel(&image, 0, 4), [0, 0xff, 0, 0xff]);
+    assert_eq!(pixel(&image, 7, 7), [0, 0, 0xff, 0xff]);
+}
+
+#[test]
+fn invalid_textures() {
+    // too little data
+    assert!(texture::decode(&[0; 31], 4, 4, Format::RGB565, None).is_err());
+    // no palette
+    assert!(texture::decode(&[0; 32], 8, 8, Format::CI4, None).is_err());
+    // index past the end of the palette
+    let palette = Palette {
+        format: PaletteFormat::RGB565,
+        data: &[0, 0],
+    };
+    assert!(texture::decode(&[0x11; 32], 8, 8, Format::CI4, Some(palette)).is_err());
+}
+
+/// Deterministic pseudo-random bytes.
+fn noise(length: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..length)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect()
+}
+
+/// Average squared error per channel between two images.
+fn mean_error(a: &texture::Image, b: &texture::Image) -> f64 {
+    let total = a
+        .rgba
+        .iter()
+        .zip(&b.rgba)
+        .map(|(a, b)| (f64::from(*a) - f64::from(*b)).powi(2))
+        .sum::<f64>();
+    total / a.rgba.len() as f64
+}
+
+#[test]
+fn encoding_representable_images_is_lossless() {
+    for format in Format::ALL {
+        if format == Format::CMPR {
+            continue;
+        }
+        let (width, height) = (12, 10);
+        let mut data = noise(format.data_size(width, height), format.id());
+        if format == Format::CI14x2 {
+            // keep indices within the palette
+            data.iter_mut().step_by(2).for_each(|byte| *byte = 0);
+        }
+        let palette_data = noise(256 * 2, 7);
+        let palette = format.is_paletted().then_some(Palette {
+            format: PaletteFormat::RGB5A3,
+            data: &palette_data,
+        });
+        let image = texture::decode(&data, width, height, format, palette).unwrap();
+
+        let target = texture::Target::new(format, width, height);
+        let encoded = texture::encode(&image, &target, &Default::default()).unwrap();
+        assert_eq!(encoded.data.len(), format.data_size(width, height));
+        assert_eq!(encoded.decode().unwrap(), image, "{format}");
+    }
+}
+
+#[test]
+fn cmpr_encoding_quality() {
+    // a smooth gradient, which CMPR handles well
+    let (width, height) = (16, 16);
+    let rgba = (0..width * height)
+        .flat_map(|index| {
+            let x = index % width;
+            [(x * 16) as u8, (x * 8) as u8, 0x80, 0xff]
+        })
+        .collect();
+    let image = texture::Image {
+        width,
+        height,
+        rgba,
+    };
+    let target = texture::Target::new(Format::CMPR, width, height);
+    let encoded = texture::encode(&image, &target, &Default::default()).unwrap();
+    assert_eq!(encoded.data.len(), 0x80);
+    let decoded = encoded.decode().unwrap();
+    // mostly the blue channel's rounding to 5 bits
+    assert!(
+        mean_error(&image, &decoded) < 8.0,
+        "{}",
+        mean_error(&image, &decoded)
+    );
+
+    // transparent pixels stay transparent, and solid colours are exact
+    let mut rgba = [0x08, 0x20, 0xf7, 0xff].repeat(64);
+    rgba[..4].copy_from_slice(&[0; 4]);
+    let image = texture::Image {
+        width: 8,
+        height: 8,
+        rgba,
+    };
+    let target = texture::Target::new(Format::CMPR, 8, 8);
+    let decoded = texture::encode(&image, &target, &Default::default())
+        .unwrap()
+        .decode()
+        .unwrap();
+    assert_eq!(decoded, image);
+}
+
+#[test]
+fn palette_quantization() {
+    // 64 colours in 16 clusters into a 16 colour palette
+    let (width, height) = (8, 8);
+    let rgba = (0..64u8)
+        .flat_map(|index| {
+            let cluster = index / 4;
+            [
+                cluster * 16 + index % 4,
+                0xff - cluster * 16,
+                cluster % 4 * 64,
+                0xff,
+            ]
+        })
+        .collect();
+    let image = texture::Image {
+        width,
+        height,
+        rgba,
+    };
+    let target = texture::Target::new(Format::CI4, width, height);
+    let encoded = texture::encode(&image, &target, &Default::default()).unwrap();
+    assert!(encoded.palette.len() <= 16 * 2);
+    // each cluster gets a colour, off only by rounding to 5 bits
+    assert!(mean_error(&image, &encoded.decode().unwrap()) < 16.0);
+
+    // matching a texture keeps to its palette's size and format
+    let original = texture::Texture {
+        palette_format: PaletteFormat::RGB565,
+        palette: vec![0; 5 * 2],
+        ..encoded
+    };
+    let target = texture::Target::matching(&original);
+    assert_eq!(target.max_colours, 5);
+    let encoded = texture::encode(&image, &target, &Default::default()).unwrap();
+    assert!(encoded.palette.len() <= 5 * 2);
+    assert_eq!(encoded.palette_format, PaletteFormat::RGB565);
+}
+
+#[test]
+fn dithering() {
+    // 0x80 falls between two I4 levels, 0x77 and 0x88
+    let image = texture::Image {
+        width: 8,
+        height: 8,
+        rgba: [0x80, 0x80, 0x80, 0xff].repeat(64),
+    };
+    let target = texture::Target::new(Format::I4, 8, 8);
+    let flat = texture::encode(&image, &target, &Default::default()).unwrap();
+    assert!(flat.data.iter().all(|&byte| byte == flat.data[0]));
+
+    let options = texture::Options {
+        dither: true,
+        ..Default::default()
+    };
+    let dithered = texture::encode(&image, &target, &options)
+        .unwrap()
+        .decode()
+        .unwrap();
+    let average = dithered
+        .rgba
+        .chunks(4)
+        .map(|pixel| f64::from(pixel[0]))
+        .sum::<f64>()
+        / 64.0;
+    assert!((average - 128.0).abs() < 2.0, "{average}");
+}
+
+#[test]
+fn resizing_to_the_target() {
+    let image = texture::Image {
+        width: 2,
+        height: 2,
+        rgba: [
+            [0x40, 0x80, 0xc0, 0xff],
+            [0xff, 0, 0, 0],
+            [0x40, 0x80, 0xc0, 0xff],
+            [0xff, 0, 0, 0],
+        ]
+        .concat(),
+    };
+    let target = texture::Target::new(Format::RGBA8, 4, 4);
+    assert!(texture::encode(&image, &target, &Default::default()).is_err());
+
+    let options = texture::Options {
+        resize: true,
+        ..Default::default()
+    };
+    let resized = texture::encode(&image, &target, &options)
+        .unwrap()
+        .decode()
+        .unwrap();
+    assert_eq!((resized.width, resized.height), (4, 4));
+    // the transparent red doesn't tint the opaque pixels next to it
+    for row in resized.rgba.chunks(16) {
+        assert_eq!(&row[..4], [0x40, 0x80, 0xc0, 0xff]);
+        assert_eq!(&row[4..7], [0x40, 0x80, 0xc0]);
+        assert_eq!(row[15], 0);
+    }
+
+    let shrunk = image.resize(1, 1);
+    assert_eq!(shrunk.rgba, [0x40, 0x80, 0xc0, 0x80]);
+}
+
+#[test]
+fn reading_pngs() {
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, 2, 1);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder
+        .write_header()
+        .unwrap()
+        .write_image_data(&[0xff, 0, 0, 0, 0, 0xff])
+        .unwrap();
+    let image = texture::Image::from_png(&png).unwrap();
+    assert_eq!((image.width, image.height), (2, 1));
+    assert_eq!(image.rgba, [0xff, 0, 0, 0xff, 0, 0, 0xff, 0xff]);
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, 1, 1);
+    encoder.set_color(png::ColorType::GrayscaleAlpha);
+    encoder
+        .write_header()
+        .unwrap()
+        .write_image_data(&[0x40, 0x80])
+        .unwrap();
+    let image = texture::Image::from_png(&png).unwrap();
+    assert_eq!(image.rgba, [0x40, 0x40, 0x40, 0x80]);
+
+    assert!(texture::Image::from_png(b"not a png").is_err());
+}
+
+#[test]
+fn find_textures_in_a_dat() {
+    let dat = DatFile::parse(&textured_dat()).unwrap();
+    let textures = texture::find(&dat).unwrap();
+
+    // the texture used twice is only listed once
+    assert_eq!(textures.len(), 2);
+    let names = textures
+        .iter()
+        .map(|texture| texture.file_name())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["0x280_CMPR_8x8.png", "0x2a0_CI4_8x8.png"]);
+
+    let cmpr = textures[0];
+    assert_eq!(cmpr.image.offset, 0x220);
+    assert!(cmpr.palette.is_none());
+    let image = cmpr.read(&dat).unwrap().decode().unwrap();
+    assert_eq!(pixel(&image, 0, 0), [0xff, 0, 0, 0xff]);
+    assert_eq!(pixel(&image, 7, 0), [0, 0xff, 0, 0xff]);
+    assert_eq!(pixel(&image, 0, 7), [0, 0, 0xff, 0xff]);
+    assert_eq!(pixel(&image, 7, 7), [0xff, 0xff, 0xff, 0xff]);
+
+    let paletted = textures[1];
+    let palette = paletted.palette.expect("palette");
+    assert_eq!(
+        (
+            palette.offset,
+            palette.data,
+            palette.format,
+            palette.colours
+        ),
+        (0x260, 0x2c0, PaletteFormat::RGB565, 16)
+    );
+    let image = paletted.read(&dat).unwrap().decode().unwrap();
+    assert_eq!(pixel(&image, 3, 5), [0x18, 0, 0, 0xff]);
+}
+
+#[test]
+fn export_textures_to_pngs() {
+    let dat = DatFile::parse(&textured_dat()).unwrap();
+    let dir = std::env::temp_dir().join(format!("melee-inject-textures-{}", std::process::id()));
+    let paths = texture::export(&dat, &dir).unwrap();
+    assert_eq!(paths.len(), 2);
+
+    for (path, found) in paths.iter().zip(texture::find(&dat).unwrap()) {
+        assert_eq!(path, &dir.join(found.file_name()));
+        let png = texture::Image::from_png(&std::fs::read(path).unwrap()).unwrap();
+        assert_eq!(png, found.read(&dat).unwrap().decode().unwrap());
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// A solid `width` by `height` image.
+fn solid(width: usize, height: usize, rgba: [u8; 4]) -> texture::Image {
+    texture::Image {
+        width,
+        height,
+        rgba: rgba.repeat(width * height),
+    }
+}
+
+#[test]
+fn texture_offsets_from_file_names() {
+    assert_eq!(
+        texture::offset_from_file_name("0x1c3a0_CMPR_128x128.png"),
+        Some(0x1c3a0)
+    );
+    assert_eq!(texture::offset_from_file_name("0x280.png"), Some(0x280));
+    assert_eq!(texture::offset_from_file_name("0x280_CMPR_8x8.jpg"), None);
+    assert_eq!(texture::offset_from_file_name("notes.png"), None);
+}
+
+#[test]
+fn inject_textures_in_place() {
+    let original = textured_dat();
+    let mut dat = DatFile::parse(&original).unwrap();
+    let options = texture::Options::default();
+
+    let yellow = solid(8, 8, [0xff, 0xff, 0, 0xff]);
+    texture::inject(&mut dat, 0x280, &yellow, &options).unwrap();
+    let mut stripes = solid(8, 8, [0, 0, 0xff, 0xff]);
+    stripes.rgba[..32].copy_from_slice(&[0xff, 0, 0, 0xff].repeat(8));
+    texture::inject(&mut dat, 0x2a0, &stripes, &options).unwrap();
+
+    let textures = texture::find(&dat).unwrap();
+    assert_eq!(textures[0].read(&dat).unwrap().decode().unwrap(), yellow);
+    let paletted = textures[1].read(&dat).unwrap();
+    assert_eq!(paletted.decode().unwrap(), stripes);
+    // two colours, the rest of the palette cleared
+    assert_eq!(paletted.palette.len(), 16 * 2);
+    assert!(paletted.palette[4..].iter().all(|&byte| byte == 0));
+
+    // in place: nothing else moves
+    let injected = dat.to_bytes();
+    assert_eq!(injected.len(), original.len());
+    assert_eq!(injected[..0x20 + 0x280], original[..0x20 + 0x280]);
+    assert_eq!(injected[0x20 + 0x2e0..], original[0x20 + 0x2e0..]);
+}
+
+#[test]
+fn inject_refuses_mismatched_images() {
+    let mut dat = DatFile::parse(&textured_dat()).unwrap();
+    let big = solid(16, 16, [0xff, 0xff, 0xff, 0xff]);
+    let error = texture::inject(&mut dat, 0x280, &big, &Default::default()).unwrap_err();
+    assert!(error.to_string().contains("0x280"), "{error}");
+    assert!(texture::inject(&mut dat, 0x290, &solid(8, 8, [0; 4]), &Default::default()).is_err());
+
+    let options = texture::Options {
+        resize: true,
+        ..Default::default()
+    };
+    texture::inject(&mut dat, 0x280, &big, &options).unwrap();
+    let image = texture::find(&dat).unwrap()[0]
+        .read(&dat)
+        .unwrap()
+        .decode()
+        .unwrap();
+    assert_eq!(image, solid(8, 8, [0xff, 0xff, 0xff, 0xff]));
+}
+
+#[test]
+fn inject_a_folder_of_pngs() {
+    let mut dat = DatFile::parse(&textured_dat()).unwrap();
+    let dir = std::env::temp_dir().join(format!("melee-inject-inject-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let yellow = solid(8, 8, [0xff, 0xff, 0, 0xff]);
+    std::fs::write(dir.join("0x280_CMPR_8x8.png"), yellow.to_png().unwrap()).unwrap();
+    std::fs::write(dir.join("notes.txt"), "not a texture").unwrap();
+
+    let injected = texture::inject_dir(&mut dat, &dir, &Default::default()).unwrap();
+    assert_eq!(injected, [0x280]);
+    let image = texture::find(&dat).unwrap()[0]
+        .read(&dat)
+        .unwrap()
+        .decode()
+        .unwrap();
+    assert_eq!(image, yellow);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn mipmap_levels() {
+    let header = texture::ImageHeader {
+        offset: 0,
+        data: 0,
+        width: 32,
+        height: 16,
+        format: Format::CMPR,
+        mipmaps: true,
+        max_lod: 2.0,
+    };
+    assert_eq!(header.levels(), 3);
+    assert_eq!(header.total_size(), 0x100 + 0x40 + 0x20);
+
+    let header = texture::ImageHeader {
+        mipmaps: false,
+        ..header
+    };
+    assert_eq!(header.levels(), 1);
+    assert_eq!(header.total_size(), header.data_size());
+}
+
+#[test]
+fn texture_file_names() {
+    let name = texture::FileName::parse("0x1c3a0_CMPR_128x128.png").unwrap();
+    assert_eq!(
+        (name.offset, name.format, name.size),
+        (0x1c3a0, Some(Format::CMPR), Some((128, 128)))
+    );
+    let name = texture::FileName::parse("0x280.png").unwrap();
+    assert_eq!((name.format, name.size), (None, None));
+}
+
+#[test]
+fn relocate_a_larger_texture() {
+    let original = textured_dat();
+    let mut dat = DatFile::parse(&original).unwrap();
+    let big = solid(16, 16, [0xff, 0xff, 0, 0xff]);
+    let target = texture::Target::new(Format::CMPR, 16, 16);
+
+    let offset = texture::relocate(&mut dat, 0x280, &big, &target, &Default::default()).unwrap();
+    assert_eq!(offset, 0x2e0);
+    assert_eq!(dat.data.len(), 0x2e0 + 0x80);
+
+    // written out and read back, both TObjs' shared header points at the new data
+    let dat = DatFile::parse(&dat.to_bytes()).unwrap();
+    let textures = texture::find(&dat).unwrap();
+    let names = textures
+        .iter()
+        .map(|texture| texture.file_name())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["0x2a0_CI4_8x8.png", "0x2e0_CMPR_16x16.png"]);
+    assert_eq!(textures[1].image.offset, 0x220);
+    assert_eq!(textures[1].read(&dat).unwrap().decode().unwrap(), big);
+    assert_eq!(dat.relocations().len(), 15);
+    // the old data is left where it was
+    assert_eq!(
+        dat.data[0x280..0x2e0],
+        DatFile::parse(&original).unwrap().data[0x280..0x2e0]
+    );
+}
+
+#[test]
+fn relocate_to_another_format() {
+    let mut dat = DatFile::parse(&textured_dat()).unwrap();
+    let mut stripes = solid(8, 8, [0, 0, 0xff, 0xff]);
+    stripes.rgba[..32].copy_from_slice(&[0xff, 0, 0, 0xff].repeat(8));
+
+    // CMPR to CI8: the TObjs get a palette header
+    let target = texture::Target::new(Format::CI8, 8, 8);
+    let offset =
+        texture::relocate(&mut dat, 0x280, &stripes, &target, &Default::default()).unwrap();
+    let found = texture::find(&dat).unwrap();
+    let paletted = found
+        .iter()
+        .find(|texture| texture.image.data == offset)
+        .unwrap();
+    let palette = paletted.palette.expect("palette");
+    assert_eq!(
+        (palette.format, palette.colours),
+        (PaletteFormat::RGB5A3, 2)
+    );
+    assert_eq!(paletted.read(&dat).unwrap().decode().unwrap(), stripes);
+
+    // CI4 to RGB5A3: the palette is dropped
+    let target = texture::Target::new(Format::RGB5A3, 8, 8);
+    let offset =
+        texture::relocate(&mut dat, 0x2a0, &stripes, &target, &Default::default()).unwrap();
+    let found = texture::find(&dat).unwrap();
+    let direct = found
+        .iter()
+        .find(|texture| texture.image.data == offset)
+        .unwrap();
+    assert!(direct.palette.is_none());
+    assert_eq!(direct.read(&dat).unwrap().decode().unwrap(), stripes);
+
+    assert!(texture::relocate(&mut dat, 0x1000, &stripes, &target, &Default::default()).is_err());
+    let huge = texture::Target::new(Format::CMPR, 2048, 8);
+    assert!(texture::relocate(&mut dat, offset, &stripes, &huge, &Default::default()).is_err());
+}
+
+#[test]
+fn renamed_pngs_are_relocated() {
+    let dir = std::env::temp_dir().join(format!("melee-inject-relocate-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let big = solid(16, 16, [0xff, 0xff, 0, 0xff]);
+
+    // named for the original size: refused
+    std::fs::write(dir.join("0x280_CMPR_8x8.png"), big.to_png().unwrap()).unwrap();
+    let mut dat = DatFile::parse(&textured_dat()).unwrap();
+    assert!(texture::inject_dir(&mut dat, &dir, &Default::default()).is_err());
+
+    // renamed to the new size: moved
+    std::fs::rename(
+        dir.join("0x280_CMPR_8x8.png"),
+        dir.join("0x280_CMPR_16x16.png"),
+    )
+    .unwrap();
+    let mut dat = DatFile::parse(&textured_dat()).unwrap();
+    assert_eq!(
+        texture::inject_dir(&mut dat, &dir, &Default::default()).unwrap(),
+        [0x280]
+    );
+    let textures = texture::find(&dat).unwrap();
+    assert_eq!(textures[1].file_name(), "0x2e0_CMPR_16x16.png");
+    assert_eq!(textures[1].read(&dat).unwrap().decode().unwrap(), big);
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use common::{synthetic_image, temp_file};
+use melee_inject::replace::{build_iso, rebuild_fst_with_changes, Changes};
+use melee_inject::verify::{self, Policy, Verification};
+
+#[test]
+fn hash_known_vectors() {
+    let hashes = verify::hash_reader(&b"abc"[..]).expect("hash");
+    assert_eq!(hashes.crc32, 0x352441c2);
+    assert_eq!(hashes.md5, "900150983cd24fb0d6963f7d28e17f72");
+    assert_eq!(hashes.sha1, "a9993e364706816aba3e25717850c26c9cd0d89d");
+}
+
+#[test]
+fn modified_melee_image() {
+    let iso = temp_file(
+        "verify-modified.iso",
+        &synthetic_image(&[("PlCaGr.dat", b"falcon")]),
+    );
+
+    match verify::verify(&iso).expect("verify") {
+        Verification::Modified {
+            game_id, revision, ..
+        } => assert_eq!((game_id.as_str(), revision), ("GALE01", 2)),
+        other => panic!("unexpected verification: {other:?}"),
+    }
+
+    assert!(verify::check(&iso, Policy::Refuse).is_err());
+    assert!(verify::check(&iso, Policy::Warn).is_ok());
+    assert!(verify::check(&iso, Policy::Skip).is_ok());
+}
+
+#[test]
+fn unknown_melee_revision() {
+    for (game_id, revision) in [(b"GALE01", 0), (b"GALE01", 1), (b"GALP01", 0)] {
+        let mut image = synthetic_image(&[("PlCaGr.dat", b"falcon")]);
+        image[0..6].copy_from_slice(game_id);
+        image[7] = revision;
+        let name = format!("verify-{}-{revision}.iso", String::from_utf8_lossy(game_id));
+        let iso = temp_file(&name, &image);
+
+        assert!(matches!(
+            verify::verify(&iso).expect("verify"),
+            Verification::UnknownRevision { revision: r, .. } if r == revision
+        ));
+        let warning = verify::check(&iso, Policy::Warn).expect("warned");
+        assert!(warning.expect("warning").contains("can't be verified"));
+    }
+}
+
+#[test]
+fn verify_again_after_change() {
+    let mut image = synthetic_image(&[("PlCaGr.dat", b"falcon")]);
+    let iso = temp_file("verify-again.iso", &image);
+    let modified = verify::verify(&iso).expect("verify");
+    assert!(matches!(modified, Verification::Modified { .. }));
+    assert_eq!(verify::verify(&iso).expect("verify again"), modified);
+
+    // same size, with a later modification time
+    image[0..6].copy_from_slice(b"GZLE01");
+    std::fs::write(&iso, &image).expect("rewrite");
+    let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+    std::fs::File::options()
+        .write(true)
+        .open(&iso)
+        .and_then(|file| file.set_modified(later))
+        .expect("touch");
+    assert!(matches!(
+        verify::verify(&iso).expect("verify changed"),
+        Verification::Unknown { .. }
+    ));
+}
+
+#[test]
+fn unknown_image() {
+    let mut image = synthetic_image(&[]);
+    image[0..6].copy_from_slice(b"GZLE01");
+    let iso = temp_file("verify-unknown.iso", &image);
+
+    assert!(matches!(
+        verify::verify(&iso).expect("verify"),
+        Verification::Unknown { .. }
+    ));
+}
+
+#[test]
+fn hash_individual_files() {
+    let iso = temp_file(
+        "verify-files.iso",
+        &synthetic_image(&[("a.dat", b"abc"), ("b.dat", b"")]),
+    );
+
+    let hashes = verify::file_hashes(&iso).expect("file hashes");
+    assert_eq!(hashes.len(), 2);
+    assert_eq!(hashes[0].0, "a.dat");
+    assert_eq!(hashes[0].1.sha1, "a9993e364706816aba3e25717850c26c9cd0d89d");
+    assert_eq!(hashes[1].1.md5, "d41d8cd98f00b204e9800998ecf8427e");
+}
+
+#[test]
+fn nkit_image() {
+    let mut image = synthetic_image(&[("PlCaGr.dat", b"falcon")]);
+    image[0x200..0x204].copy_from_slice(b"NKIT");
+    let iso = temp_file("verify-nkit.iso", &image);
+
+    assert!(matches!(
+        verify::verify(&iso).expect("verify"),
+        Verification::NKit { revision: 2, .. }
+    ));
+
+    let error = verify::check(&iso, Policy::Refuse).expect_err("refused");
+    assert!(error.to_string().contains("NKit"), "{error}");
+    let warning = verify::check(&iso, Policy::Warn).expect("warned");
+    assert!(warning
+        .expect("warning")
+        .contains("restore it to a full ISO"));
+
+    // files are read from wherever the FST says they are
+    let rebuilt = rebuild_fst_with_changes(
+        &iso,
+        &Changes {
+            verify: Policy::Skip,
+            ..Changes::default()
+        },
+    )
+    .expect("rebuild");
+    let built = build_iso(&iso, &rebuilt);
+    assert_eq!(built[0x200..0x204], [0; 4]);
+}
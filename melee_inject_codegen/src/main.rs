@@ -1,7 +1,7 @@
-#![feature(slice_group_by)]
-
 use codegen::Scope;
 use gc_gcm::{FsNode, GcmFile};
+use melee_inject::disc::Disc;
+use melee_inject::verify;
 use std::collections::HashMap;
 
 const SSBM_ISO: &str = "ssbm.iso";
@@ -103,7 +103,7 @@ impl FromStr for CharacterFile {
             .expect("failed to strip .dat suffix");
 
         // return on animations and common files
-        if remaining.len() == 0 || remaining == "AJ" || remaining == "DViWaitAJ" {
+        if remaining.is_empty() || remaining == "AJ" || remaining == "DViWaitAJ" {
             return Ok(CharacterFile {
                 filename: filename.to_string(),
                 name: name.to_string(),
@@ -123,7 +123,7 @@ impl FromStr for CharacterFile {
             return match remaining.len() {
                 // <COLOR>
                 2 => {
-                    let color = COLORS.get(&remaining).expect("failed to match color");
+                    let color = COLORS.get(remaining).expect("failed to match color");
 
                     Ok(CharacterFile {
                         filename: filename.to_string(),
@@ -139,7 +139,7 @@ impl FromStr for CharacterFile {
                         .expect("failed on char-specific kirby copy power file");
 
                     let copied_char = CHARACTER_PREFIXES
-                        .get(&copied_char_code)
+                        .get(copied_char_code)
                         .expect("failed to find kirby copied char");
 
                     Ok(CharacterFile {
@@ -161,7 +161,7 @@ impl FromStr for CharacterFile {
                         .expect("failed to find kirby character code");
 
                     let copied_char = CHARACTER_PREFIXES
-                        .get(&copied_char_code)
+                        .get(copied_char_code)
                         .expect("failed to find kirby copied char");
 
                     Ok(CharacterFile {
@@ -175,7 +175,7 @@ impl FromStr for CharacterFile {
             };
         }
 
-        let color = COLORS.get(&remaining).expect("failed to match color");
+        let color = COLORS.get(remaining).expect("failed to match color");
 
         Ok(CharacterFile {
             filename: filename.to_string(),
@@ -186,14 +186,57 @@ impl FromStr for CharacterFile {
     }
 }
 
-#[derive(Debug)]
-struct CharacterMatch {}
+/// The Rust type name for a character, e.g. `CaptainFalcon` for "Captain Falcon".
+fn type_name(character: &str) -> String {
+    match character {
+        "Game 'n Watch" => "GameNWatch".to_string(),
+        "[Nana] Ice Climbers" => "IceClimbersNana".to_string(),
+        "[Popo] Ice Climbers" => "IceClimbersPopo".to_string(),
+        "[Popo/Nana] Ice Climbers" => "IceClimbers".to_string(),
+        _ => character.chars().filter(|c| c.is_alphanumeric()).collect(),
+    }
+}
+
+/// Generate a vanilla file table from a verified image, for
+/// `Disc::modified_files_from`.
+fn vanilla_hashes(iso: &str) {
+    match verify::verify(iso).expect("failed to verify ISO") {
+        verify::Verification::Vanilla(_) => {}
+        other => panic!("refusing to generate hashes from non-vanilla ISO: {other:?}"),
+    }
+
+    let disc = Disc::open(iso).expect("could not open ISO");
+    let mut hashes = disc.file_hashes().expect("failed to hash files");
+    hashes.sort_by(|(a, _), (b, _)| a.path.cmp(&b.path));
+
+    println!("pub const FILES: &[VanillaFile] = &[");
+    for (file, hashes) in hashes {
+        println!("    VanillaFile {{");
+        println!("        path: {:?},", file.path);
+        println!("        size: {:#x},", file.size);
+        println!("        crc32: {:#010x},", hashes.crc32);
+        println!("        sha1: {:?},", hashes.sha1);
+        println!("    }},");
+    }
+    println!("];");
+}
 
 fn main() {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        // cargo run -- vanilla-hashes <path-to-ssbm.iso>
+        Some("vanilla-hashes") => vanilla_hashes(&args.next().unwrap_or(SSBM_ISO.to_string())),
+        _ => characters(),
+    }
+}
+
+/// Generate the `melee_inject::characters` module.
+fn characters() {
     let iso = GcmFile::open(SSBM_ISO).expect("could not open ISO");
 
     let mut scope = Scope::new();
     let mut characters: HashMap<String, Vec<CharacterFile>> = HashMap::new();
+    let mut typed_names: Vec<(String, String)> = Vec::new();
 
     for node in iso.filesystem.files {
         // find all file entries (skip directories)
@@ -224,15 +267,17 @@ fn main() {
     println!("{characters:#?}");
 
     for (character, files) in &characters {
+        let type_name = type_name(character);
+
         scope
-            .new_struct(character)
+            .new_struct(&type_name)
             .derive("Debug")
             .derive("Clone")
             .vis("pub")
             .doc(format!("Supported files for {character}.").as_str());
 
         // start impl block
-        scope.raw(format!("impl {character} {{").as_str());
+        scope.raw(format!("impl {type_name} {{").as_str());
 
         for file in files {
             if file.filename.ends_with("AJ.dat") {
@@ -241,6 +286,7 @@ fn main() {
 
             let name = file.filename.strip_suffix(".dat").expect("failed to strip");
             let filename = &file.filename;
+            typed_names.push((type_name.clone(), name.to_string()));
 
             // special handling for kirby
             if character == "Kirby" {
@@ -260,7 +306,7 @@ fn main() {
                     if let Some(color) = &file.color {
                         scope.raw(format!("    /// {color} costume. ").as_str());
                     } else {
-                        scope.raw(format!("    /// Shared textures. ").as_str());
+                        scope.raw("    /// Shared textures. ");
                     }
                 }
 
@@ -271,7 +317,7 @@ fn main() {
             if let Some(color) = &file.color {
                 scope.raw(format!("    /// {color} costume. ").as_str());
             } else {
-                scope.raw(format!("    /// Shared textures. ").as_str());
+                scope.raw("    /// Shared textures. ");
             }
 
             scope.raw(format!("    pub const {name}: &'static str = \"{filename}\";").as_str());
@@ -281,6 +327,22 @@ fn main() {
         scope.raw("}");
     }
 
+    // lookup by typed name, for manifests
+    let mut lookup = String::from(
+        "/// Look up a supported file by its typed name, e.g. `\"CaptainFalcon::PlCaGr\"`.\n\
+         ///\n\
+         /// Returns the file name within the disc filesystem.\n\
+         pub fn lookup(typed_name: &str) -> Option<&'static str> {\n    \
+         Some(match typed_name {\n",
+    );
+    for (type_name, name) in &typed_names {
+        lookup.push_str(&format!(
+            "        \"{type_name}::{name}\" => {type_name}::{name},\n"
+        ));
+    }
+    lookup.push_str("        _ => return None,\n    })\n}");
+    scope.raw(&lookup);
+
     let output = scope.to_string();
     println!("{output}")
 }